@@ -1,14 +1,22 @@
 use anyhow::Result;
 use crossbeam_channel::{bounded, Receiver, Sender};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
     thread,
+    time::{Duration, Instant},
 };
 
-use crate::models::{Model, ModelConfig, ModelId, ModelParams, ModelsCache};
+use crate::models::{
+    CancelToken, ExpertStats, LoadOptions, LoraAdapter, Model, ModelConfig, ModelId, ModelInfo,
+    ModelOptions, ModelParams, ModelsCache, PerfConfig, QuantVariant, Tokenization, TokensStream,
+};
+
+/// Minimum interval between model loading progress messages.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(50);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub struct PromptId(u32);
@@ -21,14 +29,28 @@ impl PromptId {
 
 /// Command for the controller.
 enum Command {
-    /// Load the given model.
-    LoadModel(ModelId),
+    /// Load the given model variant with the given options.
+    LoadModel(ModelId, QuantVariant, ModelOptions),
     /// Process the given prompt.
     Prompt(PromptId, String),
+    /// Process the given prompt with several models or presets.
+    Compare(String, Vec<(PromptId, CompareTarget)>),
     /// Update the model configuration.
     Config(ModelConfig),
-    /// Refresh weights for the given model.
-    ReloadWeights(ModelId),
+    /// Update the options used to load models.
+    LoadOptions(LoadOptions),
+    /// Update the compute threads settings.
+    PerfConfig(PerfConfig),
+    /// Refresh weights for the given model variant.
+    ReloadWeights(ModelId, QuantVariant, ModelOptions),
+    /// Tokenize the given text with the loaded model tokenizer.
+    Tokenize(String),
+    /// Count the tokens used by the given prompt.
+    CountTokens(String),
+    /// Get the details of the loaded model.
+    ModelInfo,
+    /// Set the LoRA adapters paths and scales.
+    SetAdapters(Vec<(PathBuf, f32)>),
     /// Stops token generation.
     Stop,
     /// Shutdown controller thread.
@@ -37,8 +59,8 @@ enum Command {
 
 /// A message sent by the controller task
 pub enum Message {
-    /// A generated token.
-    Token(PromptId, String),
+    /// A generated token with the model that generated it.
+    Token(PromptId, ModelId, String),
     /// An error message.
     Error(String),
     /// Weights download has started for a model.
@@ -49,6 +71,161 @@ pub enum Message {
     DownloadProgress(f32),
     /// Weights download has completed.
     DownloadComplete,
+    /// A model has been loaded, with the time it took to create it from the
+    /// cached weights.
+    ModelLoaded(LoadTime),
+    /// Model download or loading has been cancelled by a new load, a stop or a
+    /// shutdown.
+    LoadCancelled,
+    /// Tokenization result for a `Controller::tokenize` request.
+    Tokenized(Tokenization),
+    /// Prompt tokens count for a `Controller::count_tokens` request.
+    TokenCount(TokenCount),
+    /// The loaded model details for a `Controller::model_info` request.
+    ModelInfo(ModelInfo),
+    /// The models kept in memory, the active model first.
+    ResidentModels(Vec<ResidentModel>),
+    /// Reply generation has started for a prompt.
+    Started(PromptId),
+    /// Reply generation has completed, the statistics include the finish reason.
+    Finished(PromptId, GenerationStats),
+    /// Reply generation has been stopped by the user or cancelled by another
+    /// command, the statistics include the finish reason.
+    Cancelled(PromptId, GenerationStats),
+    /// The experts selected while processing the last prompt and generating its reply,
+    /// sent after the reply by mixture of experts models.
+    ExpertStats(ExpertStats),
+}
+
+/// Why reply generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinishReason {
+    /// The model generated the end of text token.
+    EndOfText,
+    /// The context length of the model has been reached.
+    Length,
+    /// Generation was stopped by the user.
+    Stopped,
+    /// Generation was cancelled by a new command.
+    Cancelled,
+    /// Generation failed with an error.
+    Error,
+}
+
+impl FinishReason {
+    /// Gets the value description.
+    pub fn description(&self) -> &'static str {
+        match self {
+            FinishReason::EndOfText => "end of text",
+            FinishReason::Length => "context length",
+            FinishReason::Stopped => "stopped",
+            FinishReason::Cancelled => "cancelled",
+            FinishReason::Error => "error",
+        }
+    }
+
+    /// Gets the reason for stopping generation because of the given command.
+    fn from_command(cmd: &Command) -> Self {
+        match cmd {
+            Command::Stop => FinishReason::Stopped,
+            _ => FinishReason::Cancelled,
+        }
+    }
+}
+
+/// Timing statistics for a generated reply.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GenerationStats {
+    /// Number of tokens in the templated prompt.
+    pub prompt_tokens: usize,
+    /// Time spent processing the prompt.
+    pub prompt_time: Duration,
+    /// Number of generated tokens.
+    pub generated_tokens: usize,
+    /// Time spent generating tokens after the prompt was processed.
+    pub generation_time: Duration,
+    /// Why generation stopped.
+    pub finish_reason: FinishReason,
+}
+
+impl GenerationStats {
+    /// Statistics for a prompt that didn't generate a reply.
+    fn without_reply(finish_reason: FinishReason) -> Self {
+        Self {
+            prompt_tokens: 0,
+            prompt_time: Duration::ZERO,
+            generated_tokens: 0,
+            generation_time: Duration::ZERO,
+            finish_reason,
+        }
+    }
+
+    /// The generated tokens per second.
+    pub fn tokens_per_second(&self) -> f32 {
+        self.generated_tokens as f32 / self.generation_time.as_secs_f32().max(f32::EPSILON)
+    }
+
+    /// The total time to process the prompt and generate the reply.
+    pub fn total_time(&self) -> Duration {
+        self.prompt_time + self.generation_time
+    }
+
+    /// Gets a short summary used in reply footers.
+    pub fn summary(&self) -> String {
+        if self.prompt_tokens == 0 {
+            return self.finish_reason.description().to_string();
+        }
+
+        format!(
+            "prompt {} tokens {:.2}s - reply {} tokens {:.1} tok/s - total {:.2}s - {}",
+            self.prompt_tokens,
+            self.prompt_time.as_secs_f32(),
+            self.generated_tokens,
+            self.tokens_per_second(),
+            self.total_time().as_secs_f32(),
+            self.finish_reason.description(),
+        )
+    }
+}
+
+/// A model and generation preset that receives a comparison prompt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompareTarget {
+    /// The model identifier.
+    pub model_id: ModelId,
+    /// The model quantization variant, the model must be loaded.
+    pub variant: QuantVariant,
+    /// The generation preset.
+    pub config: ModelConfig,
+}
+
+/// A model kept in memory by the controller.
+#[derive(Debug, Clone, Copy)]
+pub struct ResidentModel {
+    /// The model identifier.
+    pub model_id: ModelId,
+    /// The loaded quantization variant.
+    pub variant: QuantVariant,
+    /// Approximate memory used by the model and its kv cache in bytes.
+    pub memory: usize,
+}
+
+/// The time it took to create a model from the cached weights.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadTime {
+    /// The loaded model.
+    pub model_id: ModelId,
+    /// The loading time.
+    pub duration: Duration,
+}
+
+/// The number of tokens used by a prompt.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenCount {
+    /// Number of tokens in the templated prompt.
+    pub tokens: usize,
+    /// The model context length.
+    pub context_length: usize,
 }
 
 /// Models controller.
@@ -62,16 +239,36 @@ pub struct Controller {
     task: Option<thread::JoinHandle<()>>,
     last_prompt_id: PromptId,
     model_config: ModelConfig,
+    cancel: CancelToken,
+    load_cancel: CancelToken,
 }
 
 impl Controller {
-    /// Creates a new controller with the given configuration.
-    pub fn new(model_config: ModelConfig) -> Self {
+    /// Creates a new controller with the given configuration and options.
+    pub fn new(
+        model_config: ModelConfig,
+        load_options: LoadOptions,
+        perf_config: PerfConfig,
+    ) -> Self {
         let (command_tx, command_rx) = bounded(1024);
         let (message_tx, message_rx) = bounded(1024);
+        let cancel = CancelToken::default();
+        let load_cancel = CancelToken::default();
 
-        let task = thread::spawn(move || {
-            message_loop(model_config, command_rx, message_tx);
+        let task = thread::spawn({
+            let cancel = cancel.clone();
+            let load_cancel = load_cancel.clone();
+            move || {
+                message_loop(
+                    model_config,
+                    load_options,
+                    perf_config,
+                    cancel,
+                    load_cancel,
+                    command_rx,
+                    message_tx,
+                );
+            }
         });
 
         Self {
@@ -80,6 +277,8 @@ impl Controller {
             task: Some(task),
             last_prompt_id: PromptId::default(),
             model_config,
+            cancel,
+            load_cancel,
         }
     }
 
@@ -94,14 +293,47 @@ impl Controller {
         self.last_prompt_id
     }
 
-    /// Reloads weights.
-    pub fn reload_weights(&self, model_id: ModelId) {
-        let _ = self.command_tx.send(Command::ReloadWeights(model_id));
+    /// Sends a prompt to several loaded models or presets, returns the prompt
+    /// identifiers for each target.
+    ///
+    /// Replies from different models are generated a token at a time in turn, replies
+    /// from the same model are generated one after the other.
+    pub fn send_compare_prompt(
+        &mut self,
+        prompt: &str,
+        targets: &[CompareTarget],
+    ) -> Vec<PromptId> {
+        let targets = targets
+            .iter()
+            .map(|target| {
+                self.last_prompt_id = self.last_prompt_id.inc();
+                (self.last_prompt_id, *target)
+            })
+            .collect::<Vec<_>>();
+        let prompt_ids = targets.iter().map(|(prompt_id, _)| *prompt_id).collect();
+
+        let _ = self
+            .command_tx
+            .send(Command::Compare(prompt.to_string(), targets));
+
+        prompt_ids
     }
 
-    /// Loads the a model.
-    pub fn load_model(&self, model_id: ModelId) {
-        let _ = self.command_tx.send(Command::LoadModel(model_id));
+    /// Reloads weights, cancelling a model that is being loaded.
+    pub fn reload_weights(&self, model_id: ModelId, variant: QuantVariant, options: ModelOptions) {
+        self.load_cancel.cancel();
+        let _ = self
+            .command_tx
+            .send(Command::ReloadWeights(model_id, variant, options));
+    }
+
+    /// Loads the a model with the given quantization variant and options, cancelling
+    /// a model that is being loaded.
+    pub fn load_model(&self, model_id: ModelId, variant: QuantVariant, options: ModelOptions) {
+        self.load_cancel.cancel();
+        let _ = self
+            .command_tx
+            .send(Command::LoadModel(model_id, variant, options));
     }
 
     /// Returns the current config.
@@ -115,21 +347,74 @@ impl Controller {
         let _ = self.command_tx.send(Command::Config(config));
     }
 
+    /// Sets the options used for the next model loads.
+    pub fn set_load_options(&self, options: LoadOptions) {
+        let _ = self.command_tx.send(Command::LoadOptions(options));
+    }
+
+    /// Sets the number of compute threads and their priority.
+    ///
+    /// The new settings are used from the next prompt.
+    pub fn set_perf_config(&self, config: PerfConfig) {
+        let _ = self.command_tx.send(Command::PerfConfig(config));
+    }
+
     /// Get the next available controller message.
     pub fn next_message(&self) -> Option<Message> {
         self.message_rx.try_recv().ok()
     }
 
+    /// Tokenizes text with the loaded model tokenizer.
+    ///
+    /// The result is sent back as a `Message::Tokenized` message, this doesn't
+    /// interrupt tokens generation.
+    pub fn tokenize(&self, text: &str) {
+        let _ = self.command_tx.send(Command::Tokenize(text.to_string()));
+    }
+
+    /// Counts the tokens used by a prompt.
+    ///
+    /// The result is sent back as a `Message::TokenCount` message, this doesn't
+    /// interrupt tokens generation.
+    pub fn count_tokens(&self, prompt: &str) {
+        let _ = self
+            .command_tx
+            .send(Command::CountTokens(prompt.to_string()));
+    }
+
+    /// Gets the details of the loaded model.
+    ///
+    /// The result is sent back as a `Message::ModelInfo` message, this doesn't
+    /// interrupt tokens generation.
+    pub fn model_info(&self) {
+        let _ = self.command_tx.send(Command::ModelInfo);
+    }
+
+    /// Sets the LoRA adapters to apply to the loaded model.
+    ///
+    /// The adapters are given as folder path and scale pairs, they are also applied
+    /// to models loaded later.
+    pub fn set_adapters(&self, adapters: Vec<(PathBuf, f32)>) {
+        let _ = self.command_tx.send(Command::SetAdapters(adapters));
+    }
+
     /// Stops tokens generation.
     ///
     /// This may be useful when the model is in deranged mode and it keeps generating
-    /// text we are not interested in.
+    /// text we are not interested in. The cancel token is set before sending the
+    /// command so that a long prompt evaluation stops at the next layer, it also
+    /// cancels a model that is being loaded.
     pub fn stop(&self) {
+        self.cancel.cancel();
+        self.load_cancel.cancel();
         let _ = self.command_tx.send(Command::Stop);
     }
 
-    /// Shutdown controller task.
+    /// Shutdown controller task, interrupting the prefill or generation and the model
+    /// load in progress so that the task can be joined.
     pub fn shutdown(&mut self) {
+        self.cancel.cancel();
+        self.load_cancel.cancel();
         let _ = self.command_tx.send(Command::Shutdown);
         self.task.take().map(|h| h.join());
     }
@@ -137,154 +422,842 @@ impl Controller {
 
 fn message_loop(
     model_config: ModelConfig,
+    mut load_options: LoadOptions,
+    mut perf_config: PerfConfig,
+    cancel: CancelToken,
+    load_cancel: CancelToken,
     command_rx: Receiver<Command>,
     message_tx: Sender<Message>,
 ) {
-    let mut model: Option<Box<dyn Model>> = None;
-    let mut model_params = model_config.params();
+    // Inference runs on a dedicated pool so that candle doesn't use all cores.
+    let mut pool = match build_pool(&perf_config) {
+        Ok(pool) => pool,
+        Err(e) => {
+            let _ = message_tx.send(Message::Error(e.to_string()));
+            return;
+        }
+    };
+    let mut models = ResidentModels::default();
+    let mut model_params = model_config
+        .params()
+        .with_prefill_chunk_size(perf_config.prefill_chunk_size);
+    // A command received while generating tokens that must be processed next.
+    let mut pending_cmd = None;
+    let mut adapters = Adapters::default();
+
+    loop {
+        let cmd = match pending_cmd.take() {
+            Some(cmd) => cmd,
+            None => match command_rx.recv() {
+                Ok(cmd) => cmd,
+                Err(_) => break,
+            },
+        };
 
-    while let Ok(cmd) = command_rx.recv() {
         match cmd {
-            Command::LoadModel(model_id) => {
+            Command::LoadModel(model_id, variant, options) => {
+                if let Some((_, model)) = models.activate(model_id, variant, &options) {
+                    // The model is already in memory.
+                    adapters.apply(model.as_mut(), &message_tx);
+                    set_options(model.as_mut(), &options, &message_tx);
+                    let _ = message_tx.send(Message::DownloadProgress(1.0));
+                    let _ = message_tx.send(Message::DownloadComplete);
+                    models.send_info(&message_tx);
+                    continue;
+                }
+
+                let size = expected_memory(model_id, variant, &options);
+                models.make_room(size, load_options.memory_budget, &message_tx);
+                // The token was set when this command was sent, the commands sent
+                // after it cancel the load.
+                load_cancel.reset();
                 match load_model(
                     model_id,
+                    variant,
                     model_config.params(),
-                    &command_rx,
+                    &options,
+                    &load_cancel,
                     &message_tx,
                     false,
                 ) {
-                    Ok(m) => model = Some(m),
+                    Ok(Some(mut m)) => {
+                        adapters.apply(m.as_mut(), &message_tx);
+                        set_options(m.as_mut(), &options, &message_tx);
+                        m.set_cancel_token(cancel.clone());
+                        models.insert(model_id, variant, &options, m);
+                        models.send_info(&message_tx);
+                    }
+                    Ok(None) => {
+                        let _ = message_tx.send(Message::LoadCancelled);
+                    }
                     Err(e) => {
                         let _ = message_tx.send(Message::Error(e.to_string()));
                     }
                 };
             }
             Command::Prompt(prompt_id, prompt) => {
-                if let Some(model) = model.as_mut() {
-                    let mut token_stream = match model.prompt(&prompt, &model_params) {
-                        Ok(ts) => ts,
-                        Err(e) => {
-                            let _ = message_tx.send(Message::Error(e.to_string()));
-                            continue;
-                        }
-                    };
-
-                    loop {
-                        match token_stream.next(model.as_mut()) {
-                            Ok(Some(token_str)) => {
-                                let _ = message_tx.send(Message::Token(prompt_id, token_str));
-                            }
-                            Ok(None) => break,
-                            Err(e) => {
-                                let _ = message_tx.send(Message::Error(e.to_string()));
-                                break;
-                            }
-                        }
-
-                        // Skip remainining tokens if there is a new command.
-                        if !command_rx.is_empty() {
-                            break;
-                        }
+                if let Some((model_id, model)) = models.active_mut() {
+                    pending_cmd = pool.install(|| {
+                        generate(
+                            model.as_mut(),
+                            model_id,
+                            prompt_id,
+                            &prompt,
+                            &model_params,
+                            &cancel,
+                            &command_rx,
+                            &message_tx,
+                        )
+                    });
+                } else {
+                    // The prompt is finished so that its reply stops showing progress.
+                    let _ = message_tx.send(Message::Error("No model is loaded".to_string()));
+                    let stats = GenerationStats::without_reply(FinishReason::Error);
+                    let _ = message_tx.send(Message::Finished(prompt_id, stats));
+                }
+            }
+            Command::Compare(prompt, targets) => {
+                pending_cmd = pool.install(|| {
+                    compare(
+                        &mut models,
+                        &prompt,
+                        targets,
+                        perf_config.prefill_chunk_size,
+                        &cancel,
+                        &command_rx,
+                        &message_tx,
+                    )
+                });
+            }
+            Command::Config(config) => {
+                model_params = config
+                    .params()
+                    .with_prefill_chunk_size(perf_config.prefill_chunk_size);
+            }
+            Command::LoadOptions(options) => {
+                load_options = options;
+                models.make_room(0, load_options.memory_budget, &message_tx);
+            }
+            Command::PerfConfig(config) => {
+                perf_config = config;
+                model_params = model_params.with_prefill_chunk_size(config.prefill_chunk_size);
+                match build_pool(&config) {
+                    Ok(p) => pool = p,
+                    Err(e) => {
+                        let _ = message_tx.send(Message::Error(e.to_string()));
                     }
                 }
             }
-            Command::Config(config) => model_params = config.params(),
-            Command::Stop => {}
-            Command::ReloadWeights(model_id) => {
+            Command::Tokenize(text) => {
+                if let Some((_, model)) = models.active_mut() {
+                    tokenize(model.as_ref(), &text, &message_tx);
+                }
+            }
+            Command::CountTokens(text) => {
+                if let Some((_, model)) = models.active_mut() {
+                    count_tokens(model.as_ref(), &text, &message_tx);
+                }
+            }
+            Command::ModelInfo => {
+                if let Some((_, model)) = models.active_mut() {
+                    model_info(model.as_ref(), &message_tx);
+                }
+            }
+            Command::SetAdapters(settings) => {
+                adapters.settings = settings;
+                if let Some((_, model)) = models.active_mut() {
+                    adapters.apply(model.as_mut(), &message_tx);
+                }
+            }
+            // Generation and loading have already stopped, clear the tokens for the
+            // next prompt and load.
+            Command::Stop => {
+                cancel.reset();
+                load_cancel.reset();
+            }
+            Command::ReloadWeights(model_id, variant, options) => {
+                models.remove(model_id, variant);
+                let size = expected_memory(model_id, variant, &options);
+                models.make_room(size, load_options.memory_budget, &message_tx);
+                load_cancel.reset();
                 match load_model(
                     model_id,
+                    variant,
                     model_config.params(),
-                    &command_rx,
+                    &options,
+                    &load_cancel,
                     &message_tx,
                     true,
                 ) {
-                    Ok(m) => model = Some(m),
+                    Ok(Some(mut m)) => {
+                        adapters.apply(m.as_mut(), &message_tx);
+                        set_options(m.as_mut(), &options, &message_tx);
+                        m.set_cancel_token(cancel.clone());
+                        models.insert(model_id, variant, &options, m);
+                    }
+                    Ok(None) => {
+                        let _ = message_tx.send(Message::LoadCancelled);
+                    }
                     Err(e) => {
                         let _ = message_tx.send(Message::Error(e.to_string()));
                     }
                 };
+                models.send_info(&message_tx);
             }
             Command::Shutdown => break,
         }
     }
 }
 
+/// Sets the per model options, reporting the options the model doesn't support.
+fn set_options(model: &mut dyn Model, options: &ModelOptions, message_tx: &Sender<Message>) {
+    let results = [
+        model.set_kv_cache_type(options.kv_cache),
+        model.set_rope_scaling(options.rope_scaling),
+    ];
+    for result in results {
+        if let Err(e) = result {
+            let _ = message_tx.send(Message::Error(e.to_string()));
+        }
+    }
+}
+
+/// Generates tokens for a prompt and returns the command that stopped generation.
+#[allow(clippy::too_many_arguments)]
+fn generate(
+    model: &mut dyn Model,
+    model_id: ModelId,
+    prompt_id: PromptId,
+    prompt: &str,
+    params: &ModelParams,
+    cancel: &CancelToken,
+    command_rx: &Receiver<Command>,
+    message_tx: &Sender<Message>,
+) -> Option<Command> {
+    let _ = message_tx.send(Message::Started(prompt_id));
+    let mut generation = match Generation::start(model, prompt_id, prompt, params) {
+        Ok(generation) => generation,
+        Err(e) => {
+            start_failed(prompt_id, e, cancel, message_tx);
+            return None;
+        }
+    };
+
+    loop {
+        if let Some(reason) = generation.next(model, model_id, cancel, message_tx) {
+            generation.finish(reason, message_tx);
+            send_expert_stats(model, message_tx);
+            return None;
+        }
+
+        // Skip remainining tokens if there is a new command, tokenize
+        // requests are served without interrupting generation.
+        match command_rx.try_recv() {
+            Ok(Command::Tokenize(text)) => tokenize(model, &text, message_tx),
+            Ok(Command::CountTokens(text)) => count_tokens(model, &text, message_tx),
+            Ok(Command::ModelInfo) => model_info(model, message_tx),
+            Ok(cmd) => {
+                generation.finish(FinishReason::from_command(&cmd), message_tx);
+                send_expert_stats(model, message_tx);
+                return Some(cmd);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Sends the experts selection counts of mixture of experts models.
+fn send_expert_stats(model: &dyn Model, message_tx: &Sender<Message>) {
+    if let Some(stats) = model.expert_stats() {
+        let _ = message_tx.send(Message::ExpertStats(stats));
+    }
+}
+
+/// A reply being generated with its timing.
+struct Generation {
+    prompt_id: PromptId,
+    token_stream: TokensStream,
+    prompt_time: Duration,
+    start: Instant,
+}
+
+impl Generation {
+    /// Processes the prompt and starts the generation timer.
+    fn start(
+        model: &mut dyn Model,
+        prompt_id: PromptId,
+        prompt: &str,
+        params: &ModelParams,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        let token_stream = model.prompt(prompt, params)?;
+        Ok(Self {
+            prompt_id,
+            token_stream,
+            prompt_time: start.elapsed(),
+            start: Instant::now(),
+        })
+    }
+
+    /// Generates the next token, returns the finish reason when generation stops.
+    fn next(
+        &mut self,
+        model: &mut dyn Model,
+        model_id: ModelId,
+        cancel: &CancelToken,
+        message_tx: &Sender<Message>,
+    ) -> Option<FinishReason> {
+        if cancel.is_cancelled() {
+            return Some(FinishReason::Stopped);
+        }
+
+        match self.token_stream.next(model) {
+            Ok(Some(token_str)) => {
+                let _ = message_tx.send(Message::Token(self.prompt_id, model_id, token_str));
+                None
+            }
+            Ok(None) if self.token_stream.is_context_full() => Some(FinishReason::Length),
+            Ok(None) => Some(FinishReason::EndOfText),
+            // The forward pass was interrupted by the cancel token.
+            Err(_) if cancel.is_cancelled() => Some(FinishReason::Stopped),
+            Err(e) => {
+                let _ = message_tx.send(Message::Error(e.to_string()));
+                Some(FinishReason::Error)
+            }
+        }
+    }
+
+    /// Sends the finished or cancelled message with the generation statistics.
+    fn finish(self, finish_reason: FinishReason, message_tx: &Sender<Message>) {
+        let stats = GenerationStats {
+            prompt_tokens: self.token_stream.prompt_tokens(),
+            prompt_time: self.prompt_time,
+            generated_tokens: self.token_stream.generated_tokens(),
+            generation_time: self.start.elapsed(),
+            finish_reason,
+        };
+        let _ = message_tx.send(finish_message(self.prompt_id, stats));
+    }
+}
+
+/// Reports a generation that failed to process the prompt, a prompt interrupted by the
+/// cancel token is reported as stopped.
+fn start_failed(
+    prompt_id: PromptId,
+    error: anyhow::Error,
+    cancel: &CancelToken,
+    message_tx: &Sender<Message>,
+) {
+    let reason = if cancel.is_cancelled() {
+        FinishReason::Stopped
+    } else {
+        let _ = message_tx.send(Message::Error(error.to_string()));
+        FinishReason::Error
+    };
+    let stats = GenerationStats::without_reply(reason);
+    let _ = message_tx.send(finish_message(prompt_id, stats));
+}
+
+fn finish_message(prompt_id: PromptId, stats: GenerationStats) -> Message {
+    match stats.finish_reason {
+        FinishReason::Stopped | FinishReason::Cancelled => Message::Cancelled(prompt_id, stats),
+        FinishReason::EndOfText | FinishReason::Length | FinishReason::Error => {
+            Message::Finished(prompt_id, stats)
+        }
+    }
+}
+
+/// The comparison targets that use the same model.
+struct CompareLane {
+    model_id: ModelId,
+    variant: QuantVariant,
+    pending: VecDeque<(PromptId, ModelConfig)>,
+    generation: Option<Generation>,
+}
+
+/// Generates tokens for a comparison prompt and returns the command that stopped
+/// generation.
+///
+/// A model has a single kv cache so the targets that use the same model are processed
+/// one after the other, while different models take turns generating a token.
+fn compare(
+    models: &mut ResidentModels,
+    prompt: &str,
+    targets: Vec<(PromptId, CompareTarget)>,
+    prefill_chunk_size: usize,
+    cancel: &CancelToken,
+    command_rx: &Receiver<Command>,
+    message_tx: &Sender<Message>,
+) -> Option<Command> {
+    let mut lanes: Vec<CompareLane> = Vec::new();
+    for (prompt_id, target) in targets {
+        let lane = lanes
+            .iter_mut()
+            .find(|l| l.model_id == target.model_id && l.variant == target.variant);
+        match lane {
+            Some(lane) => lane.pending.push_back((prompt_id, target.config)),
+            None => lanes.push(CompareLane {
+                model_id: target.model_id,
+                variant: target.variant,
+                pending: VecDeque::from([(prompt_id, target.config)]),
+                generation: None,
+            }),
+        }
+    }
+
+    loop {
+        let mut generating = false;
+        for lane in &mut lanes {
+            let Some(model) = models.get_mut(lane.model_id, lane.variant) else {
+                if !lane.pending.is_empty() {
+                    let name = lane.model_id.spec().name;
+                    let _ = message_tx.send(Message::Error(format!("{name} is not loaded")));
+                    for (prompt_id, _) in lane.pending.drain(..) {
+                        let stats = GenerationStats::without_reply(FinishReason::Error);
+                        let _ = message_tx.send(Message::Finished(prompt_id, stats));
+                    }
+                }
+                continue;
+            };
+
+            if lane.generation.is_none() {
+                let Some((prompt_id, config)) = lane.pending.pop_front() else {
+                    continue;
+                };
+
+                let _ = message_tx.send(Message::Started(prompt_id));
+                let params = config.params().with_prefill_chunk_size(prefill_chunk_size);
+                match Generation::start(model.as_mut(), prompt_id, prompt, &params) {
+                    Ok(generation) => lane.generation = Some(generation),
+                    Err(e) => {
+                        start_failed(prompt_id, e, cancel, message_tx);
+                        continue;
+                    }
+                }
+            }
+
+            generating = true;
+            if let Some(generation) = lane.generation.as_mut() {
+                if let Some(reason) =
+                    generation.next(model.as_mut(), lane.model_id, cancel, message_tx)
+                {
+                    if let Some(generation) = lane.generation.take() {
+                        generation.finish(reason, message_tx);
+                    }
+                }
+            }
+        }
+
+        if cancel.is_cancelled() {
+            stop_lanes(&mut lanes, FinishReason::Stopped, message_tx);
+            return None;
+        }
+
+        if !generating {
+            return None;
+        }
+
+        // Skip remainining tokens if there is a new command, tokenize
+        // requests are served without interrupting generation.
+        match command_rx.try_recv() {
+            Ok(Command::Tokenize(text)) => {
+                if let Some((_, model)) = models.active_mut() {
+                    tokenize(model.as_ref(), &text, message_tx);
+                }
+            }
+            Ok(Command::CountTokens(text)) => {
+                if let Some((_, model)) = models.active_mut() {
+                    count_tokens(model.as_ref(), &text, message_tx);
+                }
+            }
+            Ok(Command::ModelInfo) => {
+                if let Some((_, model)) = models.active_mut() {
+                    model_info(model.as_ref(), message_tx);
+                }
+            }
+            Ok(cmd) => {
+                stop_lanes(&mut lanes, FinishReason::from_command(&cmd), message_tx);
+                return Some(cmd);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Finishes the lanes generations and cancels their pending targets.
+fn stop_lanes(lanes: &mut [CompareLane], reason: FinishReason, message_tx: &Sender<Message>) {
+    for lane in lanes {
+        if let Some(generation) = lane.generation.take() {
+            generation.finish(reason, message_tx);
+        }
+
+        for (prompt_id, _) in lane.pending.drain(..) {
+            let stats = GenerationStats::without_reply(reason);
+            let _ = message_tx.send(Message::Cancelled(prompt_id, stats));
+        }
+    }
+}
+
+/// Builds the thread pool used for inference.
+fn build_pool(config: &PerfConfig) -> Result<ThreadPool> {
+    let low_priority = config.low_priority;
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(config.num_threads())
+        .thread_name(|idx| format!("coze-compute-{idx}"))
+        .start_handler(move |_| {
+            if low_priority {
+                lower_thread_priority();
+            }
+        })
+        .build()?;
+    Ok(pool)
+}
+
+/// Lowers the priority of the calling thread.
+#[cfg(target_os = "linux")]
+fn lower_thread_priority() {
+    // On Linux setpriority with a 0 id changes the calling thread nice value.
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+    }
+}
+
+/// Lowers the priority of the calling thread.
+#[cfg(target_os = "macos")]
+fn lower_thread_priority() {
+    // On macOS a 0 id with PRIO_PROCESS would change the whole process, the Darwin
+    // thread option moves the calling thread to the background band.
+    unsafe {
+        libc::setpriority(libc::PRIO_DARWIN_THREAD, 0, libc::PRIO_DARWIN_BG);
+    }
+}
+
+/// Lowers the priority of the calling thread.
+#[cfg(windows)]
+fn lower_thread_priority() {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_LOWEST,
+    };
+
+    unsafe {
+        SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_LOWEST);
+    }
+}
+
+/// The low priority setting is hidden where threads priority is not supported.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn lower_thread_priority() {}
+
+/// The models kept in memory, ordered from the most recently used.
+#[derive(Default)]
+struct ResidentModels {
+    models: Vec<(ResidentModel, Box<dyn Model>)>,
+    /// The weights and tokenizer files the user supplied models were loaded from.
+    local_files: HashMap<ModelId, (PathBuf, PathBuf)>,
+}
+
+impl ResidentModels {
+    /// Returns the active model, that is the most recently used.
+    fn active_mut(&mut self) -> Option<(ModelId, &mut Box<dyn Model>)> {
+        self.models.first_mut().map(|(info, m)| (info.model_id, m))
+    }
+
+    /// Returns a resident model without changing the active model.
+    fn get_mut(&mut self, model_id: ModelId, variant: QuantVariant) -> Option<&mut Box<dyn Model>> {
+        let idx = self.position(model_id, variant)?;
+        Some(&mut self.models[idx].1)
+    }
+
+    /// Makes a resident model the active model, a user supplied model loaded from
+    /// other files than the ones in the options is removed.
+    fn activate(
+        &mut self,
+        model_id: ModelId,
+        variant: QuantVariant,
+        options: &ModelOptions,
+    ) -> Option<(ModelId, &mut Box<dyn Model>)> {
+        if let Some(files) = self.local_files.get(&model_id) {
+            if *files != (options.weights_path.clone(), options.tokenizer_path.clone()) {
+                self.remove(model_id, variant);
+                return None;
+            }
+        }
+
+        let idx = self.position(model_id, variant)?;
+        let entry = self.models.remove(idx);
+        self.models.insert(0, entry);
+        self.active_mut()
+    }
+
+    /// Adds a model and makes it the active model, the model is charged the memory
+    /// of its loaded weights.
+    fn insert(
+        &mut self,
+        model_id: ModelId,
+        variant: QuantVariant,
+        options: &ModelOptions,
+        model: Box<dyn Model>,
+    ) {
+        self.remove(model_id, variant);
+        if model_id.spec().is_local() {
+            let files = (options.weights_path.clone(), options.tokenizer_path.clone());
+            self.local_files.insert(model_id, files);
+        }
+        let info = ResidentModel {
+            model_id,
+            variant,
+            memory: model.info().weights_memory,
+        };
+        self.models.insert(0, (info, model));
+    }
+
+    fn remove(&mut self, model_id: ModelId, variant: QuantVariant) {
+        if let Some(idx) = self.position(model_id, variant) {
+            self.models.remove(idx);
+        }
+    }
+
+    /// Unloads the least recently used models until there is space for a model of
+    /// the given size, the active model is unloaded only to make room for a new one.
+    fn make_room(&mut self, size: usize, budget: usize, message_tx: &Sender<Message>) {
+        let min_len = usize::from(size == 0);
+        let mut evicted = false;
+        while self.models.len() > min_len && self.memory() + size > budget {
+            self.models.pop();
+            evicted = true;
+        }
+
+        if evicted {
+            self.send_info(message_tx);
+        }
+    }
+
+    fn memory(&self) -> usize {
+        self.info().map(|info| info.memory).sum()
+    }
+
+    fn position(&self, model_id: ModelId, variant: QuantVariant) -> Option<usize> {
+        self.models
+            .iter()
+            .position(|(info, _)| info.model_id == model_id && info.variant == variant)
+    }
+
+    fn send_info(&self, message_tx: &Sender<Message>) {
+        let _ = message_tx.send(Message::ResidentModels(self.info().collect()));
+    }
+
+    /// Returns the models info with the memory used by the tensors computed from their
+    /// weights and by their kv cache, that change with the RoPE scaling and cache type.
+    fn info(&self) -> impl Iterator<Item = ResidentModel> + '_ {
+        self.models.iter().map(|(info, model)| ResidentModel {
+            memory: info.memory + model.derived_memory() + model.kv_cache_memory(),
+            ..*info
+        })
+    }
+}
+
+/// The memory expected to be used by a model before loading it, that is the size of
+/// its cached weights files or the download size when they are not cached.
+///
+/// The safetensors weights are converted to the variant type, their memory is computed
+/// from the tensors shapes, or from the download size of the 16 bits weights when they
+/// are not cached.
+fn expected_memory(model_id: ModelId, variant: QuantVariant, options: &ModelOptions) -> usize {
+    let cached_model = ModelsCache::new()
+        .ok()
+        .map(|cache| cache.cached_model(model_id, variant).with_options(options));
+    if variant.is_safetensors() {
+        let dtype = variant.dtype();
+        return cached_model
+            .and_then(|model| model.weights_paths().ok())
+            .and_then(|paths| ModelInfo::read_safetensors(&paths, dtype).ok())
+            .map(|info| info.weights_memory)
+            .unwrap_or(variant.size / 2 * dtype.size_in_bytes());
+    }
+    cached_model
+        .and_then(|model| model.weights_size())
+        .unwrap_or(variant.size)
+}
+
+/// LoRA adapters settings and cache of loaded adapters.
+#[derive(Default)]
+struct Adapters {
+    settings: Vec<(PathBuf, f32)>,
+    loaded: HashMap<PathBuf, Arc<LoraAdapter>>,
+}
+
+impl Adapters {
+    fn apply(&mut self, model: &mut dyn Model, message_tx: &Sender<Message>) {
+        if let Err(e) = self.try_apply(model) {
+            let _ = message_tx.send(Message::Error(e.to_string()));
+        }
+    }
+
+    fn try_apply(&mut self, model: &mut dyn Model) -> Result<()> {
+        // The adapters are kept for the next model that supports them.
+        if !model.supports_adapters() {
+            return Ok(());
+        }
+
+        let mut adapters = Vec::with_capacity(self.settings.len());
+        for (path, scale) in &self.settings {
+            let adapter = match self.loaded.get(path) {
+                Some(adapter) => adapter.clone(),
+                None => {
+                    let adapter = Arc::new(LoraAdapter::load(path)?);
+                    self.loaded.insert(path.clone(), adapter.clone());
+                    adapter
+                }
+            };
+            adapters.push((adapter, *scale));
+        }
+
+        model.set_adapters(&adapters)
+    }
+}
+
+fn tokenize(model: &dyn Model, text: &str, message_tx: &Sender<Message>) {
+    match model.tokenize(text) {
+        Ok(tokenization) => {
+            let _ = message_tx.send(Message::Tokenized(tokenization));
+        }
+        Err(e) => {
+            let _ = message_tx.send(Message::Error(e.to_string()));
+        }
+    }
+}
+
+/// Sends the model info with the current context length, that changes with the RoPE
+/// scaling, and the kv cache memory.
+fn model_info(model: &dyn Model, message_tx: &Sender<Message>) {
+    let info = ModelInfo {
+        context_length: model.context_length(),
+        derived_memory: model.derived_memory(),
+        kv_cache_memory: model.kv_cache_memory(),
+        ..model.info().clone()
+    };
+    let _ = message_tx.send(Message::ModelInfo(info));
+}
+
+fn count_tokens(model: &dyn Model, text: &str, message_tx: &Sender<Message>) {
+    match model.count_tokens(text) {
+        Ok(tokens) => {
+            let _ = message_tx.send(Message::TokenCount(TokenCount {
+                tokens,
+                context_length: model.context_length(),
+            }));
+        }
+        Err(e) => {
+            let _ = message_tx.send(Message::Error(e.to_string()));
+        }
+    }
+}
+
 fn load_model(
     model_id: ModelId,
+    variant: QuantVariant,
     params: ModelParams,
-    command_rx: &Receiver<Command>,
+    model_options: &ModelOptions,
+    cancel: &CancelToken,
     message_tx: &Sender<Message>,
     reload: bool,
-) -> Result<Box<dyn Model>> {
+) -> Result<Option<Box<dyn Model>>> {
     let cache = ModelsCache::new()?;
-    let cached_model = cache.cached_model(model_id);
+    let cached_model = cache
+        .cached_model(model_id, variant)
+        .with_options(model_options);
+
+    // Set when a progress callback stops the download or the loading, so that the
+    // cancellation is told apart from errors.
+    let interrupted = CancelToken::default();
 
-    if !cached_model.is_model_cached() || reload {
+    // User supplied weights are reloaded from disk.
+    let reload_model = reload && !cached_model.spec.is_local();
+    if !cached_model.is_model_cached() || reload_model {
         let _ = message_tx.send(Message::DownloadBegin("Downloading Model".to_string()));
         let _ = message_tx.send(Message::DownloadConnecting);
 
-        cached_model.download_model({
-            let message_tx = message_tx.clone();
-            let command_rx = command_rx.clone();
-            move |pct| {
-                if command_rx.is_empty() {
-                    let _ = message_tx.send(Message::DownloadProgress(pct));
-                    true
-                } else {
-                    false
-                }
-            }
-        })?;
+        let result = cached_model.download_model(download_progress(
+            cancel.clone(),
+            interrupted.clone(),
+            message_tx.clone(),
+        ));
+        if interrupted.is_cancelled() {
+            return Ok(None);
+        }
+        result?;
     }
 
     if !cached_model.is_tokenizer_cached() || reload {
         let _ = message_tx.send(Message::DownloadBegin("Downloading Tokenizer".to_string()));
         let _ = message_tx.send(Message::DownloadConnecting);
 
-        cached_model.download_tokenizer({
-            let message_tx = message_tx.clone();
-            let command_rx = command_rx.clone();
-            move |pct| {
-                if command_rx.is_empty() {
-                    let _ = message_tx.send(Message::DownloadProgress(pct));
-                    true
-                } else {
-                    false
-                }
-            }
-        })?;
+        let result = cached_model.download_tokenizer(download_progress(
+            cancel.clone(),
+            interrupted.clone(),
+            message_tx.clone(),
+        ));
+        if interrupted.is_cancelled() {
+            return Ok(None);
+        }
+        result?;
     }
 
     let _ = message_tx.send(Message::DownloadBegin("Loading Model".to_string()));
-    let finished = Arc::new(AtomicBool::new(false));
-    let task = thread::spawn({
-        let message_tx = message_tx.clone();
-        let finished = finished.clone();
-        move || {
-            for pct in 0..=1000 {
-                if finished.load(Ordering::Relaxed) {
-                    break;
-                }
+    let _ = message_tx.send(Message::DownloadProgress(0.0));
 
-                let _ = message_tx.send(Message::DownloadProgress((pct % 100) as f32 / 100.0));
-                thread::sleep(std::time::Duration::from_millis(25));
-            }
+    // Create model from the cached weights, loading is cancelled by the cancel token.
+    let start = Instant::now();
+    let mut last_update = start;
+    let model_result = model_id.model(variant, params, model_options, &mut |loaded, total| {
+        if cancel.is_cancelled() {
+            interrupted.cancel();
+            return false;
         }
+
+        // Limit updates to the UI refresh rate.
+        if last_update.elapsed() >= PROGRESS_INTERVAL {
+            last_update = Instant::now();
+            let pct = loaded as f32 / total.max(1) as f32;
+            let _ = message_tx.send(Message::DownloadProgress(pct));
+        }
+        true
     });
+    let load_time = LoadTime {
+        model_id,
+        duration: start.elapsed(),
+    };
 
-    // Create model from the loaded weights.
-    let model_result = model_id.model(params);
+    if interrupted.is_cancelled() {
+        return Ok(None);
+    }
 
-    // Stop loading thread before checking for error.
-    finished.store(true, Ordering::Relaxed);
-    let _ = task.join();
     let model = model_result?;
+    let _ = message_tx.send(Message::ModelLoaded(load_time));
 
     // Show progress and download complete, use a small delay to make it easier to
     // see in the UI.
     let _ = message_tx.send(Message::DownloadProgress(1.0));
-    thread::sleep(std::time::Duration::from_millis(150));
+    thread::sleep(Duration::from_millis(150));
     let _ = message_tx.send(Message::DownloadComplete);
 
-    Ok(model)
+    Ok(Some(model))
+}
+
+/// Creates a download progress callback that stops the download when the cancel
+/// token is set, recording the interruption in `interrupted`.
+fn download_progress(
+    cancel: CancelToken,
+    interrupted: CancelToken,
+    message_tx: Sender<Message>,
+) -> impl Fn(f32) -> bool + 'static {
+    move |pct| {
+        if cancel.is_cancelled() {
+            interrupted.cancel();
+            false
+        } else {
+            let _ = message_tx.send(Message::DownloadProgress(pct));
+            true
+        }
+    }
 }
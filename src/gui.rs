@@ -1,20 +1,25 @@
 use eframe::egui::*;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug};
 
 use crate::{
-    controller::{Controller, Message},
-    models::ModelConfig,
+    controller::{Controller, GenerationStats, LoadTime, Message, PromptId, ResidentModel},
+    models::{ExpertStats, LoadOptions, ModelConfig, ModelId, ModelInfo, ModelOptions, PerfConfig},
 };
 
 mod bubble;
+mod compare_panel;
 mod config;
+mod experts;
 mod gauge;
 mod help;
 mod history;
 mod load_panel;
+mod lora;
+mod model_info;
 mod models_panel;
 mod prompt_panel;
+mod tokenizer;
 
 #[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, PartialEq)]
 enum UiMode {
@@ -52,6 +57,21 @@ struct PersistedState {
     history: Vec<Prompt>,
     model_config: ModelConfig,
     ui_mode: UiMode,
+    #[serde(default)]
+    lora_adapters: Vec<lora::AdapterSetting>,
+    /// Selected quantization variant name for each model.
+    #[serde(default)]
+    quant_variants: HashMap<ModelId, String>,
+    /// Selected options for each model.
+    #[serde(default)]
+    model_options: HashMap<ModelId, ModelOptions>,
+    #[serde(default)]
+    load_options: LoadOptions,
+    #[serde(default)]
+    perf_config: PerfConfig,
+    /// Model comparisons with the preferred replies.
+    #[serde(default)]
+    comparisons: Vec<compare_panel::Comparison>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -59,6 +79,38 @@ struct Prompt {
     prompt: String,
     reply: String,
     info: String,
+    /// The reply generation statistics.
+    #[serde(default)]
+    stats: Option<GenerationStats>,
+    /// The controller prompt identifier for prompts sent in this session.
+    #[serde(skip)]
+    prompt_id: Option<PromptId>,
+    /// Reply generation is in progress.
+    #[serde(skip)]
+    generating: bool,
+}
+
+impl Prompt {
+    /// Updates the reply state from a controller message, returns true if the message
+    /// was for this prompt.
+    fn handle_message(&mut self, msg: &Message) -> bool {
+        match msg {
+            Message::Token(prompt_id, _, s) if self.prompt_id == Some(*prompt_id) => {
+                self.reply.push_str(s);
+            }
+            Message::Started(prompt_id) if self.prompt_id == Some(*prompt_id) => {
+                self.generating = true;
+            }
+            Message::Finished(prompt_id, stats) | Message::Cancelled(prompt_id, stats)
+                if self.prompt_id == Some(*prompt_id) =>
+            {
+                self.generating = false;
+                self.stats = Some(*stats);
+            }
+            _ => return false,
+        }
+        true
+    }
 }
 
 trait Panel: Debug {
@@ -82,6 +134,44 @@ struct AppContext {
     state: PersistedState,
     controller: Controller,
     egui_ctx: Context,
+    /// The models kept in memory by the controller.
+    resident_models: Vec<ResidentModel>,
+}
+
+impl AppContext {
+    /// Updates the replies in the history and comparisons from a controller message.
+    ///
+    /// This is done here rather than in the panels so that replies get their final
+    /// state even if the panel that sent the prompt has been closed.
+    fn update_replies(&mut self, msg: &Message) {
+        if !matches!(
+            msg,
+            Message::Token(..)
+                | Message::Started(_)
+                | Message::Finished(..)
+                | Message::Cancelled(..)
+        ) {
+            return;
+        }
+
+        // Prompts sent in this session are at the end of the history.
+        let handled = self
+            .state
+            .history
+            .iter_mut()
+            .rev()
+            .take_while(|prompt| prompt.prompt_id.is_some())
+            .any(|prompt| prompt.handle_message(msg));
+
+        if !handled {
+            self.state
+                .comparisons
+                .iter_mut()
+                .rev()
+                .take_while(|comparison| comparison.in_session())
+                .any(|comparison| comparison.handle_message(msg));
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -89,6 +179,17 @@ pub struct App {
     ctx: AppContext,
     show_config: bool,
     show_help: bool,
+    show_tokenizer: bool,
+    tokenizer: tokenizer::TokenizerInspector,
+    show_lora: bool,
+    show_experts: bool,
+    /// The experts selection counts of the last reply of a mixture of experts model.
+    expert_stats: Option<ExpertStats>,
+    show_model_info: bool,
+    /// The details of the active model.
+    model_info: Option<ModelInfo>,
+    /// The time it took to load the last model.
+    load_time: Option<LoadTime>,
     active_panel: Box<dyn Panel>,
 }
 
@@ -103,18 +204,32 @@ impl App {
 
         cc.egui_ctx.set_visuals(state.ui_mode.visuals());
 
-        let controller = Controller::new(state.model_config);
-        let state = AppContext {
+        let controller = Controller::new(state.model_config, state.load_options, state.perf_config);
+        let mut state = AppContext {
             state,
             controller,
             egui_ctx: cc.egui_ctx.clone(),
+            resident_models: Vec::new(),
         };
 
+        lora::refresh_adapters(&mut state);
+        lora::send_adapters(&state);
+
+        let active_panel = Box::new(models_panel::ModelsPanel::new(&state));
+
         Self {
             ctx: state,
             show_config: false,
             show_help: false,
-            active_panel: Box::new(models_panel::ModelsPanel::new()),
+            show_tokenizer: false,
+            tokenizer: Default::default(),
+            show_lora: false,
+            show_experts: false,
+            expert_stats: None,
+            show_model_info: false,
+            model_info: None,
+            load_time: None,
+            active_panel,
         }
     }
 }
@@ -132,9 +247,32 @@ impl eframe::App for App {
             self.ctx.controller.model_config().description()
         )));
 
-        if let Some(m) = self.ctx.controller.next_message() {
-            self.active_panel.handle_message(&mut self.ctx, m);
-        };
+        match self.ctx.controller.next_message() {
+            Some(Message::Tokenized(tokenization)) => {
+                self.tokenizer.set_tokenization(tokenization);
+            }
+            Some(Message::ModelLoaded(load_time)) => self.load_time = Some(load_time),
+            Some(Message::ResidentModels(models)) => {
+                // The active model may have changed.
+                if self.show_model_info {
+                    self.ctx.controller.model_info();
+                }
+                self.ctx.resident_models = models;
+            }
+            Some(Message::ModelInfo(info)) => self.model_info = Some(info),
+            Some(Message::ExpertStats(stats)) => self.expert_stats = Some(stats),
+            Some(m) => {
+                // Refresh the kv cache memory after a reply.
+                if self.show_model_info
+                    && matches!(m, Message::Finished(..) | Message::Cancelled(..))
+                {
+                    self.ctx.controller.model_info();
+                }
+                self.ctx.update_replies(&m);
+                self.active_panel.handle_message(&mut self.ctx, m);
+            }
+            None => {}
+        }
 
         self.active_panel.handle_input(&mut self.ctx);
 
@@ -145,7 +283,7 @@ impl eframe::App for App {
                     let arrow = RichText::new("⬅").font(FontId::new(24.0, FontFamily::Monospace));
                     if ui.add(Button::new(arrow).frame(false)).clicked() {
                         self.ctx.controller.stop();
-                        self.active_panel = Box::new(models_panel::ModelsPanel::new());
+                        self.active_panel = Box::new(models_panel::ModelsPanel::new(&self.ctx));
                     }
                 }
 
@@ -155,6 +293,30 @@ impl eframe::App for App {
                         ui.close_menu();
                     }
 
+                    if ui.button("Tokenizer").clicked() {
+                        self.show_tokenizer = true;
+                        self.ctx.controller.tokenize(self.tokenizer.text());
+                        ui.close_menu();
+                    }
+
+                    if ui.button("Model info").clicked() {
+                        self.show_model_info = true;
+                        self.model_info = None;
+                        self.ctx.controller.model_info();
+                        ui.close_menu();
+                    }
+
+                    if ui.button("LoRA adapters").clicked() {
+                        self.show_lora = true;
+                        lora::refresh_adapters(&mut self.ctx);
+                        ui.close_menu();
+                    }
+
+                    if ui.button("Expert routing").clicked() {
+                        self.show_experts = true;
+                        ui.close_menu();
+                    }
+
                     if ui.button("Clear history").clicked() {
                         self.ctx.state.history.clear();
                         ui.close_menu();
@@ -172,6 +334,10 @@ impl eframe::App for App {
 
         self.config_window(ctx);
         self.help_window(ctx);
+        self.tokenizer_window(ctx);
+        self.lora_window(ctx);
+        self.experts_window(ctx);
+        self.model_info_window(ctx);
 
         if let Some(panel) = self.active_panel.next_panel(&mut self.ctx) {
             self.active_panel = panel;
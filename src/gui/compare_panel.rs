@@ -0,0 +1,384 @@
+use chrono::prelude::*;
+use eframe::egui::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    controller::{CompareTarget, GenerationStats, Message, PromptId},
+    gui::{
+        bubble::{Bubble, BubbleContent},
+        AppContext, Panel,
+    },
+    models::{ModelConfig, ModelId},
+};
+
+const TEXT_FONT: FontId = FontId::new(15.0, FontFamily::Monospace);
+const ROUNDING: f32 = 8.0;
+const MAX_COLUMNS: usize = 4;
+
+/// A prompt sent to several models with their replies.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Comparison {
+    prompt: String,
+    replies: Vec<ComparisonReply>,
+    /// The index of the reply preferred by the user.
+    preferred: Option<usize>,
+    info: String,
+}
+
+impl Comparison {
+    /// Checks if this comparison has been sent in this session.
+    pub fn in_session(&self) -> bool {
+        self.replies.iter().any(|r| r.prompt_id.is_some())
+    }
+
+    /// Updates the replies from a controller message, returns true if the message
+    /// was for one of the replies.
+    pub fn handle_message(&mut self, msg: &Message) -> bool {
+        self.replies.iter_mut().any(|r| r.handle_message(msg))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ComparisonReply {
+    /// The model and preset that generated the reply.
+    label: String,
+    reply: String,
+    /// The reply generation statistics.
+    #[serde(default)]
+    stats: Option<GenerationStats>,
+    /// The controller prompt and model identifiers for replies in this session.
+    #[serde(skip)]
+    prompt_id: Option<(PromptId, ModelId)>,
+    /// Reply generation is in progress.
+    #[serde(skip)]
+    generating: bool,
+}
+
+impl ComparisonReply {
+    /// Updates the reply state from a controller message, returns true if the message
+    /// was for this reply.
+    fn handle_message(&mut self, msg: &Message) -> bool {
+        let Some((id, model_id)) = self.prompt_id else {
+            return false;
+        };
+
+        match msg {
+            Message::Token(prompt_id, m, s) if *prompt_id == id && *m == model_id => {
+                self.reply.push_str(s);
+            }
+            Message::Started(prompt_id) if *prompt_id == id => self.generating = true,
+            Message::Finished(prompt_id, stats) | Message::Cancelled(prompt_id, stats)
+                if *prompt_id == id =>
+            {
+                self.generating = false;
+                self.stats = Some(*stats);
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Sends the same prompt to multiple loaded models or presets and shows the replies
+/// side by side.
+#[derive(Debug)]
+pub struct ComparePanel {
+    prompt: String,
+    prompt_field_id: Id,
+    columns: Vec<CompareTarget>,
+    error: Option<String>,
+    frame_counter: usize,
+    scroll_to_bottom: bool,
+}
+
+impl ComparePanel {
+    pub fn new(ctx: &AppContext) -> Self {
+        let config = ctx.state.model_config;
+        let mut columns = ctx
+            .resident_models
+            .iter()
+            .take(MAX_COLUMNS)
+            .map(|m| CompareTarget {
+                model_id: m.model_id,
+                variant: m.variant,
+                config,
+            })
+            .collect::<Vec<_>>();
+
+        // With a single model compare two presets.
+        if let [column] = columns[..] {
+            let config = match config {
+                ModelConfig::Careful => ModelConfig::Creative,
+                _ => ModelConfig::Careful,
+            };
+            columns.push(CompareTarget { config, ..column });
+        }
+
+        Self {
+            prompt: Default::default(),
+            prompt_field_id: Id::new("compare-prompt-id"),
+            columns,
+            error: None,
+            frame_counter: 0,
+            scroll_to_bottom: false,
+        }
+    }
+
+    fn send_prompt(&mut self, ctx: &mut AppContext) {
+        let prompt = self.prompt.trim();
+        if !prompt.is_empty() && !self.columns.is_empty() {
+            let prompt_ids = ctx.controller.send_compare_prompt(prompt, &self.columns);
+
+            ctx.state.comparisons.push(Comparison {
+                prompt: prompt.to_owned(),
+                replies: self
+                    .columns
+                    .iter()
+                    .zip(prompt_ids)
+                    .map(|(target, prompt_id)| ComparisonReply {
+                        label: target_label(target),
+                        reply: Default::default(),
+                        stats: None,
+                        prompt_id: Some((prompt_id, target.model_id)),
+                        generating: true,
+                    })
+                    .collect(),
+                preferred: None,
+                info: Local::now().format("%F %T%.3f").to_string(),
+            });
+        }
+
+        self.prompt.clear();
+        let state = text_edit::TextEditState::default();
+        state.store(&ctx.egui_ctx, self.prompt_field_id);
+    }
+
+    fn columns_selector(&mut self, ui: &mut Ui, ctx: &AppContext) {
+        ui.horizontal_wrapped(|ui| {
+            let mut remove = None;
+            for (idx, column) in self.columns.iter_mut().enumerate() {
+                ui.group(|ui| {
+                    ComboBox::from_id_source(("compare-model", idx))
+                        .selected_text(model_label(column))
+                        .show_ui(ui, |ui| {
+                            for model in &ctx.resident_models {
+                                let target = CompareTarget {
+                                    model_id: model.model_id,
+                                    variant: model.variant,
+                                    config: column.config,
+                                };
+                                ui.selectable_value(column, target, model_label(&target));
+                            }
+                        });
+
+                    ComboBox::from_id_source(("compare-config", idx))
+                        .selected_text(column.config.description())
+                        .show_ui(ui, |ui| {
+                            for config in [
+                                ModelConfig::Careful,
+                                ModelConfig::Creative,
+                                ModelConfig::Deranged,
+                            ] {
+                                ui.selectable_value(
+                                    &mut column.config,
+                                    config,
+                                    config.description(),
+                                );
+                            }
+                        });
+
+                    if ui.small_button("✖").on_hover_text("Remove").clicked() {
+                        remove = Some(idx);
+                    }
+                });
+            }
+
+            if let Some(idx) = remove {
+                self.columns.remove(idx);
+            }
+
+            let can_add = self.columns.len() < MAX_COLUMNS && !ctx.resident_models.is_empty();
+            if ui.add_enabled(can_add, Button::new("➕")).clicked() {
+                let model = &ctx.resident_models[0];
+                self.columns.push(CompareTarget {
+                    model_id: model.model_id,
+                    variant: model.variant,
+                    config: ctx.state.model_config,
+                });
+            }
+        });
+    }
+
+    fn error_window(&mut self, ctx: &Context) {
+        // Show error window if any.
+        if self.error.is_some() {
+            Window::new("Error")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                        let msg = self.error.as_ref().unwrap();
+                        ui.label(RichText::new(msg).font(TEXT_FONT));
+                        ui.add_space(ui.spacing().item_spacing.y * 2.5);
+                        if ui.button("Close").clicked() {
+                            self.error = None;
+                        }
+                    });
+                });
+        }
+    }
+}
+
+impl Panel for ComparePanel {
+    fn update(&mut self, ctx: &mut AppContext) {
+        ctx.egui_ctx
+            .send_viewport_cmd(ViewportCommand::Title("Compare models".to_string()));
+
+        self.frame_counter += 1;
+
+        let egui_ctx = ctx.egui_ctx.clone();
+
+        TopBottomPanel::top("compare_columns").show(&egui_ctx, |ui| {
+            ui.add_space(ui.spacing().item_spacing.y);
+            self.columns_selector(ui, ctx);
+            ui.add_space(ui.spacing().item_spacing.y);
+        });
+
+        let prompt_frame = Frame::none()
+            .fill(ctx.egui_ctx.style().visuals.window_fill)
+            .outer_margin(Margin::same(0.0))
+            .inner_margin(Margin::same(10.0));
+
+        // Render prompt panel.
+        TopBottomPanel::bottom("compare_bottom_panel")
+            .show_separator_line(false)
+            .frame(prompt_frame)
+            .show(&egui_ctx, |ui| {
+                Frame::group(ui.style())
+                    .rounding(Rounding::same(ROUNDING))
+                    .fill(ctx.state.ui_mode.fill_color())
+                    .show(ui, |ui| {
+                        egui_ctx.memory_mut(|m| m.request_focus(self.prompt_field_id));
+
+                        // Override multiline Enter behavior
+                        if ui.input_mut(|i| i.consume_key(Modifiers::NONE, Key::Enter)) {
+                            self.send_prompt(ctx);
+                            self.scroll_to_bottom = true;
+                        }
+
+                        let text = TextEdit::multiline(&mut self.prompt)
+                            .id(self.prompt_field_id)
+                            .cursor_at_end(true)
+                            .font(TEXT_FONT)
+                            .frame(false)
+                            .margin(Vec2::new(5.0, 5.0))
+                            .desired_rows(1)
+                            .hint_text("Prompt all models! (Enter to send)");
+
+                        ui.add_sized([ui.available_width(), 10.0], text);
+                    })
+            });
+
+        // Render comparisons.
+        CentralPanel::default().show(&egui_ctx, |ui| {
+            ScrollArea::vertical()
+                .auto_shrink(false)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for comparison in &mut ctx.state.comparisons {
+                        let r = ui.add(
+                            Bubble::new(
+                                &comparison.prompt,
+                                BubbleContent::Prompt,
+                                ctx.state.ui_mode,
+                            )
+                            .with_footer(&comparison.info),
+                        );
+                        if r.clicked() {
+                            ui.ctx().copy_text(comparison.prompt.clone());
+                        }
+
+                        ui.add_space(ui.spacing().item_spacing.y);
+
+                        let num_columns = comparison.replies.len().max(1);
+                        let preferred = &mut comparison.preferred;
+                        ui.columns(num_columns, |columns| {
+                            for (col, (ui, reply)) in
+                                columns.iter_mut().zip(&comparison.replies).enumerate()
+                            {
+                                let text = if reply.generating && reply.reply.is_empty() {
+                                    // Show waiting animation until the first token.
+                                    let dots = ["⏺   ", " ⏺  ", "  ⏺ ", "   ⏺", "  ⏺ ", " ⏺  "];
+                                    dots[(self.frame_counter / 18) % dots.len()]
+                                } else {
+                                    &reply.reply
+                                };
+
+                                let footer = match &reply.stats {
+                                    _ if reply.generating => {
+                                        format!("{}\ngenerating...", reply.label)
+                                    }
+                                    Some(stats) => format!("{}\n{}", reply.label, stats.summary()),
+                                    None => reply.label.clone(),
+                                };
+                                let r = ui.add(
+                                    Bubble::new(text, BubbleContent::Reply, ctx.state.ui_mode)
+                                        .with_footer(&footer),
+                                );
+                                if r.clicked() {
+                                    ui.ctx().copy_text(reply.reply.clone());
+                                }
+
+                                let is_preferred = *preferred == Some(col);
+                                let label = if is_preferred {
+                                    "★ Preferred"
+                                } else {
+                                    "☆ Prefer"
+                                };
+                                if ui.selectable_label(is_preferred, label).clicked() {
+                                    *preferred = if is_preferred { None } else { Some(col) };
+                                }
+                            }
+                        });
+
+                        ui.add_space(ui.spacing().item_spacing.y * 2.5);
+                    }
+
+                    if self.scroll_to_bottom {
+                        ui.scroll_to_cursor(Some(Align::BOTTOM));
+                    }
+                });
+            ui.allocate_space(ui.available_size());
+        });
+
+        self.error_window(&egui_ctx);
+
+        self.scroll_to_bottom = false;
+    }
+
+    fn handle_input(&mut self, app: &mut AppContext) {
+        if app
+            .egui_ctx
+            .input_mut(|i| i.consume_key(Modifiers::NONE, Key::Escape))
+        {
+            app.controller.stop();
+        }
+    }
+
+    fn handle_message(&mut self, _app: &mut AppContext, msg: Message) {
+        match msg {
+            Message::Token(..) => self.scroll_to_bottom = true,
+            Message::Error(s) => self.error = Some(s),
+            _ => {}
+        }
+    }
+}
+
+fn model_label(target: &CompareTarget) -> String {
+    format!("{} {}", target.model_id.spec().name, target.variant.name)
+}
+
+fn target_label(target: &CompareTarget) -> String {
+    format!("{} ({})", model_label(target), target.config.description())
+}
@@ -1,8 +1,9 @@
 use eframe::egui::*;
 
 use crate::{
+    controller::LoadTime,
     gui::{App, UiMode},
-    models::ModelConfig,
+    models::{ModelConfig, PerfConfig},
 };
 
 impl App {
@@ -62,6 +63,41 @@ impl App {
                                 });
                             ctx.set_visuals(self.ctx.state.ui_mode.visuals());
                             ui.end_row();
+
+                            ui.label("Memory budget: ");
+                            let load_options = &mut self.ctx.state.load_options;
+                            let mut budget_gb = load_options.memory_budget >> 30;
+                            ui.add(Slider::new(&mut budget_gb, 1..=64).suffix("G"))
+                                .on_hover_text("Memory used to keep recently used models loaded");
+                            load_options.memory_budget = budget_gb << 30;
+                            ui.end_row();
+
+                            ui.label("Threads: ");
+                            let perf_config = &mut self.ctx.state.perf_config;
+                            let mut threads = perf_config.num_threads();
+                            ui.add(Slider::new(&mut threads, 1..=PerfConfig::max_threads()))
+                                .on_hover_text("Number of threads used for inference");
+                            perf_config.threads = threads;
+                            ui.end_row();
+
+                            if PerfConfig::LOW_PRIORITY_SUPPORTED {
+                                ui.label("Low priority: ");
+                                ui.checkbox(&mut perf_config.low_priority, "")
+                                    .on_hover_text("Run inference threads with low priority");
+                                ui.end_row();
+                            }
+
+                            ui.label("Prefill chunk: ");
+                            ui.add(
+                                Slider::new(&mut perf_config.prefill_chunk_size, 32..=4096)
+                                    .logarithmic(true),
+                            )
+                            .on_hover_text("Maximum number of prompt tokens processed at once");
+                            ui.end_row();
+
+                            ui.label("Last load: ");
+                            ui.label(load_time_text(self.load_time));
+                            ui.end_row();
                         });
 
                     ui.separator();
@@ -69,6 +105,12 @@ impl App {
                     ui.vertical_centered(|ui| {
                         if ui.button("Close").clicked() {
                             self.ctx.controller.set_config(self.ctx.state.model_config);
+                            self.ctx
+                                .controller
+                                .set_load_options(self.ctx.state.load_options);
+                            self.ctx
+                                .controller
+                                .set_perf_config(self.ctx.state.perf_config);
                             self.show_config = false;
                         }
                     });
@@ -76,3 +118,15 @@ impl App {
         }
     }
 }
+
+/// Formats a model loading time with the model name.
+fn load_time_text(load_time: Option<LoadTime>) -> String {
+    match load_time {
+        Some(load_time) => format!(
+            "{:.2}s ({})",
+            load_time.duration.as_secs_f32(),
+            load_time.model_id.spec().name
+        ),
+        None => "-".to_string(),
+    }
+}
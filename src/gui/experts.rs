@@ -0,0 +1,88 @@
+use eframe::egui::*;
+
+use crate::{gui::App, models::ExpertStats};
+
+const TEXT_FONT: FontId = FontId::new(12.0, FontFamily::Monospace);
+const CELL_COLOR: Color32 = Color32::from_rgb(15, 85, 235);
+const NO_STATS: &str = "Generate a reply with a mixture of experts model, such as \
+    Mixtral, to see how often each expert was selected.";
+
+impl App {
+    pub fn experts_window(&mut self, ctx: &Context) {
+        if self.show_experts {
+            let ui_rect = ctx.used_rect();
+
+            Window::new("Expert routing")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .max_height(ui_rect.height() * 0.8)
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    if let Some(stats) = &self.expert_stats {
+                        render_stats(ui, stats, ui_rect.height() * 0.6);
+                    } else {
+                        ui.label(NO_STATS);
+                    }
+
+                    ui.vertical_centered(|ui| {
+                        ui.add_space(ui.spacing().item_spacing.y * 2.0);
+                        if ui.button("Close").clicked() {
+                            self.show_experts = false;
+                        }
+                    });
+                });
+        }
+    }
+}
+
+fn render_stats(ui: &mut Ui, stats: &ExpertStats, max_height: f32) {
+    let tokens = stats.tokens();
+    ui.label(
+        RichText::new(format!(
+            "{tokens} tokens, {} experts selected per token",
+            stats.n_expert_used
+        ))
+        .font(TEXT_FONT),
+    );
+    ui.label(
+        RichText::new("Percentage of the tokens routed to each expert of each layer.")
+            .font(TEXT_FONT)
+            .color(ui.visuals().weak_text_color()),
+    );
+
+    ui.separator();
+
+    let n_experts = stats.selections.first().map(Vec::len).unwrap_or_default();
+    ScrollArea::vertical()
+        .max_height(max_height)
+        .auto_shrink([false, true])
+        .show(ui, |ui| {
+            Grid::new("expert_stats")
+                .spacing([2.0, 2.0])
+                .show(ui, |ui| {
+                    ui.label(RichText::new("layer").font(TEXT_FONT));
+                    for expert_idx in 0..n_experts {
+                        ui.label(RichText::new(format!("#{expert_idx}")).font(TEXT_FONT));
+                    }
+                    ui.end_row();
+
+                    for (layer_idx, selections) in stats.selections.iter().enumerate() {
+                        ui.label(RichText::new(layer_idx.to_string()).font(TEXT_FONT));
+                        for &count in selections {
+                            let share = count as f32 / tokens.max(1) as f32;
+                            // The cell opacity is relative to an expert selected for
+                            // every token.
+                            let fill = CELL_COLOR.gamma_multiply(share.min(1.0));
+                            let text = RichText::new(format!("{:3.0}%", share * 100.0))
+                                .font(TEXT_FONT)
+                                .color(ui.visuals().strong_text_color());
+                            Frame::none()
+                                .fill(fill)
+                                .inner_margin(Margin::symmetric(4.0, 2.0))
+                                .show(ui, |ui| ui.label(text));
+                        }
+                        ui.end_row();
+                    }
+                });
+        });
+}
@@ -4,9 +4,18 @@ const TEXT_FONT: FontId = FontId::new(15.0, FontFamily::Monospace);
 const HELP_TEXT: &str = "# Prompt field
 
 Enter a prompt and press return to generate reply tokens. The prompts appear as
-blue bubbles in the history area while the replies as gray bubbles.
+blue bubbles in the history area while the replies as gray bubbles. The reply footer
+shows the number of prompt tokens and the time to process them, the number of
+generated tokens with the generation speed, the total time and why generation stopped,
+for example at the end of text or because it was stopped, while generating it shows
+`generating...`.
 
-Press Escape at any time to stop the replies generation and clear the prompt field.
+While typing, a meter below the prompt field shows how many tokens the templated
+prompt uses out of the model context length, it changes color when the prompt gets
+close to or exceeds the context length.
+
+Press Escape at any time to stop the replies generation and clear the prompt field,
+this also interrupts the processing of a long prompt before the first reply token.
 
 Click on any bubble to copy its text to the clipboard, double click on a prompt
 bubble to copy its text to the prompt field.
@@ -14,10 +23,75 @@ bubble to copy its text to the prompt field.
 Use the up and down arrows to navigate the prompt history, if the prompt field
 contains some text it is used to filter the history using fuzzy matching.
 
+# Compare models
+
+When some models are loaded the models panel shows a `Compare models` button that
+opens a panel where a prompt is sent to several loaded models or generation presets,
+with the replies shown side by side. Click on `Prefer` below a reply to record the
+preferred one.
+
 # Edit menu
 
 The `Config` menu item shows a dialog with two combo boxes, one for choosing the
-token generation randomness and the other for choosing the UI light mode.
+token generation randomness and the other for choosing the UI light mode. The dialog
+also shows how long it took to load the last model. The weights files are read rather
+than memory mapped, as the candle tensors copy their data and a mapping would not
+lower the memory used.
+
+Recently used models are kept in memory up to the `Memory budget`, so switching back
+to them doesn't need a reload, the models panel shows the loaded models and their
+memory use. The `Threads` slider limits the number of cores used for inference and
+`Low priority` runs inference threads with a lower priority on Linux, macOS and
+Windows, so that other applications stay responsive. Long prompts are processed in
+chunks of at most `Prefill chunk` tokens, smaller chunks use less memory for long
+prompts. The models panel `KV cache` combo box stores the attention keys and values
+of a model as F16 or Q8 to reduce memory at long context, at the cost of slightly
+different replies. Q8 needs attention heads with a size multiple of 32 and isn't
+offered for Phi-2.
+The `RoPE scaling` combo box extends the context length of a model by scaling its
+rotary embeddings with the Linear, NTK or YaRN method, the slider sets how many times
+the context is extended, `Model` uses the scaling found in the model file. Replies that
+reach the context length stop with the `context length` reason.
+
+The full precision StableLM 2 model downloads the original safetensors weights and
+their `config.json`, the `Quantization` combo box chooses whether the weights are
+converted to F16 or F32 when loaded, both variants download the same file and the F32
+weights use twice its size in memory.
+
+The `Local Llama` model loads a user supplied Llama weights file set in the `Weights`
+field of the models panel, or copied to `~/.cache/coze/models/local_llama/model.bin`
+when the field is empty. The file can be in the GGUF or in the legacy GGML format, that
+is detected from the file content. The `Tokenizer` field sets a `tokenizer.json` file,
+when it is empty the Llama tokenizer is downloaded. The end of sequence token is read
+from the GGUF metadata, or is the tokenizer `</s>` token. GGML files don't store the
+number of attention heads that share a key and value head, set `GQA` to 8 for Llama 2
+70B models before loading them.
+
+Downloads from gated Hugging Face repos, like the Gemma tokenizers, use the access
+token in the `HF_TOKEN` environment variable, the token account must have accepted
+the model license.
+
+The `Tokenizer` menu item shows a window that tokenizes some text with the loaded
+model tokenizer, it shows the token strings with their ids and highlights special
+tokens and the tokens added by the model chat template.
+
+The `Model info` menu item shows what was loaded for the active model, read from
+its weights file: the architecture, the number of parameters, the context length,
+that changes with the RoPE scaling, the vocabulary size, the layers and attention
+heads, the weights types with their share of the parameters and the memory used by
+the weights, by the tensors computed from them when loading, the dequantized
+embeddings and the RoPE tables, and by the kv cache.
+
+The `LoRA adapters` menu item shows the adapters found in the `~/.cache/coze/adapters`
+folder, each adapter is a folder with the `adapter_model.safetensors` and
+`adapter_config.json` files. Adapters can be enabled and scaled at runtime without
+reloading the model, they are supported by the Mistral, Zephyr and user supplied
+Llama models and are kept for them while other models are loaded.
+
+The `Expert routing` menu item shows how often each expert of each layer was selected
+by the Mixtral router for the last prompt and its reply, as the percentage of the
+tokens routed to the expert. Mixtral selects 2 of the 8 experts of a layer for each
+token, it loads all the experts and needs more than 20GB of memory.
 
 The `Clear history` menu item removes all the prompts and replies from the history
 area.
@@ -2,8 +2,8 @@ use eframe::egui::*;
 
 use crate::{
     controller::Message,
-    gui::{gauge::Gauge, prompt_panel::PromptPanel, AppContext, Panel},
-    models::ModelId,
+    gui::{gauge::Gauge, models_panel::ModelsPanel, prompt_panel::PromptPanel, AppContext, Panel},
+    models::{ModelId, ModelOptions, QuantVariant},
 };
 
 const TEXT_FONT: FontId = FontId::new(20.0, FontFamily::Monospace);
@@ -16,14 +16,24 @@ pub struct LoadPanel {
     download_msg: String,
     error: Option<String>,
     complete: bool,
+    cancelled: bool,
     frame_counter: usize,
     model_name: String,
     model_id: ModelId,
+    variant: QuantVariant,
+    options: ModelOptions,
 }
 
 impl LoadPanel {
-    pub fn new(model_id: ModelId, ctx: &mut AppContext) -> Self {
-        ctx.controller.load_model(model_id);
+    pub fn new(model_id: ModelId, variant: QuantVariant, ctx: &mut AppContext) -> Self {
+        let options = ctx
+            .state
+            .model_options
+            .get(&model_id)
+            .cloned()
+            .unwrap_or_default();
+        ctx.controller
+            .load_model(model_id, variant, options.clone());
 
         Self {
             load_pct: 0.0,
@@ -31,9 +41,12 @@ impl LoadPanel {
             download_msg: Default::default(),
             error: None,
             complete: false,
+            cancelled: false,
             frame_counter: 0,
-            model_name: model_id.spec().name.to_string(),
+            model_name: format!("{} {}", model_id.spec().name, variant.name),
             model_id,
+            variant,
+            options,
         }
     }
 }
@@ -45,7 +58,7 @@ impl Panel for LoadPanel {
         ctx.egui_ctx
             .send_viewport_cmd(ViewportCommand::Title(format!(
                 "{} ({})",
-                self.model_id.spec().name,
+                self.model_name,
                 ctx.controller.model_config().description(),
             )));
 
@@ -80,6 +93,20 @@ impl Panel for LoadPanel {
                     ui.add(Gauge::new(self.load_pct).color(INFO_COLOR).width(width));
                 }
 
+                if self.error.is_none() {
+                    ui.add_space(ui.spacing().item_spacing.y * 2.5);
+
+                    let button = Button::new(
+                        RichText::new("Cancel").font(FontId::new(14.0, FontFamily::Monospace)),
+                    )
+                    .rounding(4.0);
+
+                    if ui.add(button).clicked() {
+                        ctx.controller.stop();
+                        self.cancelled = true;
+                    }
+                }
+
                 if let Some(error) = &self.error {
                     let error_color = Color32::LIGHT_RED;
                     ui.add_space(ui.spacing().item_spacing.y * 2.5);
@@ -103,7 +130,11 @@ impl Panel for LoadPanel {
                     .rounding(4.0);
 
                     if ui.add(button).clicked() {
-                        ctx.controller.reload_weights(self.model_id);
+                        ctx.controller.reload_weights(
+                            self.model_id,
+                            self.variant,
+                            self.options.clone(),
+                        );
                         self.error = None;
                     }
                 }
@@ -120,14 +151,18 @@ impl Panel for LoadPanel {
                 self.load_pct = pct;
             }
             Message::DownloadComplete => self.complete = true,
+            // The load was cancelled without the cancel button, e.g. by a stop.
+            Message::LoadCancelled => self.cancelled = true,
             Message::Error(s) => self.error = Some(s),
             _ => {}
         }
     }
 
-    fn next_panel(&mut self, _ctx: &mut AppContext) -> Option<Box<dyn Panel>> {
-        if self.complete {
-            Some(Box::new(PromptPanel::new(self.model_id)))
+    fn next_panel(&mut self, ctx: &mut AppContext) -> Option<Box<dyn Panel>> {
+        if self.cancelled {
+            Some(Box::new(ModelsPanel::new(ctx)))
+        } else if self.complete {
+            Some(Box::new(PromptPanel::new(self.model_id, self.variant)))
         } else {
             None
         }
@@ -0,0 +1,115 @@
+use eframe::egui::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::{
+    gui::{App, AppContext},
+    models::{list_adapters, ModelsCache},
+};
+
+const TEXT_FONT: FontId = FontId::new(14.0, FontFamily::Monospace);
+
+/// A LoRA adapter setting persisted across runs.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdapterSetting {
+    path: PathBuf,
+    enabled: bool,
+    scale: f32,
+}
+
+/// Lists the adapters in the cache folder merging them with the persisted settings.
+pub fn refresh_adapters(ctx: &mut AppContext) {
+    let adapters = ModelsCache::new()
+        .map(|c| list_adapters(&c.adapters_dir()))
+        .unwrap_or_default();
+
+    for path in adapters {
+        if !ctx.state.lora_adapters.iter().any(|a| a.path == path) {
+            ctx.state.lora_adapters.push(AdapterSetting {
+                path,
+                enabled: false,
+                scale: 1.0,
+            });
+        }
+    }
+
+    ctx.state.lora_adapters.retain(|a| a.path.exists());
+}
+
+/// Sends the enabled adapters to the controller.
+pub fn send_adapters(ctx: &AppContext) {
+    let adapters = ctx
+        .state
+        .lora_adapters
+        .iter()
+        .filter(|a| a.enabled)
+        .map(|a| (a.path.clone(), a.scale))
+        .collect();
+    ctx.controller.set_adapters(adapters);
+}
+
+impl App {
+    pub fn lora_window(&mut self, ctx: &Context) {
+        if self.show_lora {
+            Window::new("LoRA adapters")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    let mut changed = false;
+
+                    if self.ctx.state.lora_adapters.is_empty() {
+                        let adapters_dir = ModelsCache::new()
+                            .map(|c| c.adapters_dir().display().to_string())
+                            .unwrap_or_default();
+                        ui.label(
+                            RichText::new(format!("No adapters found in {adapters_dir}"))
+                                .font(TEXT_FONT),
+                        );
+                    }
+
+                    Grid::new("lora_adapters")
+                        .num_columns(2)
+                        .spacing([20.0, 4.0])
+                        .show(ui, |ui| {
+                            for adapter in &mut self.ctx.state.lora_adapters {
+                                let name = adapter
+                                    .path
+                                    .file_name()
+                                    .map(|n| n.to_string_lossy().to_string())
+                                    .unwrap_or_default();
+                                let r = ui.checkbox(
+                                    &mut adapter.enabled,
+                                    RichText::new(name).font(TEXT_FONT),
+                                );
+                                changed |= r.changed();
+
+                                let slider = Slider::new(&mut adapter.scale, 0.0..=2.0)
+                                    .step_by(0.05)
+                                    .text("scale");
+                                let r = ui.add_enabled(adapter.enabled, slider);
+                                // Avoid reapplying adapters at each slider step.
+                                changed |= r.drag_released() || (r.changed() && !r.dragged());
+                                ui.end_row();
+                            }
+                        });
+
+                    if changed {
+                        send_adapters(&self.ctx);
+                    }
+
+                    ui.separator();
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Refresh").clicked() {
+                            refresh_adapters(&mut self.ctx);
+                        }
+
+                        if ui.button("Close").clicked() {
+                            self.show_lora = false;
+                        }
+                    });
+                });
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use eframe::egui::*;
+
+use crate::{gui::App, models::ModelInfo};
+
+const TEXT_FONT: FontId = FontId::new(14.0, FontFamily::Monospace);
+
+impl App {
+    pub fn model_info_window(&mut self, ctx: &Context) {
+        if self.show_model_info {
+            let ui_rect = ctx.used_rect();
+
+            Window::new("Model info")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .max_height(ui_rect.height() * 0.8)
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    // The info is requested again when the loaded models change.
+                    match (self.ctx.resident_models.first(), &self.model_info) {
+                        (Some(resident), Some(info)) => {
+                            let name = format!(
+                                "{} ({})",
+                                resident.model_id.spec().name,
+                                resident.variant.name
+                            );
+                            render_info(ui, &name, info);
+                        }
+                        (Some(_), None) => {
+                            ui.spinner();
+                        }
+                        (None, _) => {
+                            ui.label("No model is loaded.");
+                        }
+                    }
+
+                    ui.vertical_centered(|ui| {
+                        ui.add_space(ui.spacing().item_spacing.y * 2.0);
+                        if ui.button("Close").clicked() {
+                            self.show_model_info = false;
+                        }
+                    });
+                });
+        }
+    }
+}
+
+fn render_info(ui: &mut Ui, name: &str, info: &ModelInfo) {
+    let known = |n: usize| {
+        if n == 0 {
+            "-".to_string()
+        } else {
+            n.to_string()
+        }
+    };
+    let heads = if info.kv_heads != info.heads {
+        format!("{} ({} key/value)", known(info.heads), known(info.kv_heads))
+    } else {
+        known(info.heads)
+    };
+    let rows = [
+        ("Model", name.to_string()),
+        ("Architecture", info.architecture.clone()),
+        ("Parameters", format_parameters(info.parameters)),
+        ("Context length", known(info.context_length)),
+        ("Vocabulary", known(info.vocab_size)),
+        ("Layers", known(info.layers)),
+        ("Attention heads", heads),
+        ("Weights memory", format_memory(info.weights_memory)),
+        ("Derived memory", format_memory(info.derived_memory)),
+        ("KV cache memory", format_memory(info.kv_cache_memory)),
+        (
+            "Resident memory",
+            format_memory(info.weights_memory + info.derived_memory + info.kv_cache_memory),
+        ),
+    ];
+
+    Grid::new("model_info").num_columns(2).show(ui, |ui| {
+        for (label, value) in rows {
+            ui.label(RichText::new(label).font(TEXT_FONT).strong());
+            ui.label(RichText::new(value).font(TEXT_FONT));
+            ui.end_row();
+        }
+    });
+
+    ui.separator();
+
+    Grid::new("model_info_tensors")
+        .num_columns(4)
+        .striped(true)
+        .show(ui, |ui| {
+            for header in ["Type", "Tensors", "Parameters", "Share"] {
+                ui.label(RichText::new(header).font(TEXT_FONT).strong());
+            }
+            ui.end_row();
+
+            for tensor_type in &info.tensor_types {
+                let share = tensor_type.parameters as f32 / info.parameters.max(1) as f32;
+                ui.label(RichText::new(&tensor_type.name).font(TEXT_FONT));
+                ui.label(RichText::new(tensor_type.tensors.to_string()).font(TEXT_FONT));
+                ui.label(RichText::new(format_parameters(tensor_type.parameters)).font(TEXT_FONT));
+                ui.label(RichText::new(format!("{:.1}%", share * 100.0)).font(TEXT_FONT));
+                ui.end_row();
+            }
+        });
+}
+
+fn format_parameters(n: usize) -> String {
+    if n >= 1_000_000_000 {
+        format!("{:.2}B", n as f64 / 1e9)
+    } else {
+        format!("{:.1}M", n as f64 / 1e6)
+    }
+}
+
+fn format_memory(bytes: usize) -> String {
+    format!("{}M", bytes / (1 << 20))
+}
@@ -1,35 +1,57 @@
 use eframe::egui::*;
+use std::path::PathBuf;
 
 use crate::{
-    gui::{load_panel::LoadPanel, AppContext, Panel},
-    models::{ModelId, ModelSpec, ModelsCache},
+    controller::ResidentModel,
+    gui::{compare_panel::ComparePanel, load_panel::LoadPanel, AppContext, Panel},
+    models::{
+        KvCacheType, ModelId, ModelOptions, ModelSpec, ModelsCache, QuantVariant, RopeScaling,
+        RopeScalingType,
+    },
 };
 
 const ROUNDING: f32 = 8.0;
+const PADDING: f32 = 10.0;
 
 #[derive(Debug)]
 pub struct ModelsPanel {
-    selected: Option<ModelId>,
+    selected: Option<(ModelId, QuantVariant)>,
+    compare: bool,
     models: Vec<ModelData>,
 }
 
 impl ModelsPanel {
-    pub fn new() -> Self {
+    pub fn new(ctx: &AppContext) -> Self {
         let models = ModelId::models()
             .into_iter()
             .map(|model_id| {
                 let spec = model_id.spec();
-                // Checks if this model is cached on disk, this is done once at
-                // construction time to avoid accessing the disk at every frame.
-                let cached = ModelsCache::new()
-                    .map(|c| c.cached_model(model_id).is_cached())
-                    .unwrap_or(false);
-                ModelData { spec, cached }
+                let options = ctx
+                    .state
+                    .model_options
+                    .get(&model_id)
+                    .cloned()
+                    .unwrap_or_default();
+                let variant = ctx
+                    .state
+                    .quant_variants
+                    .get(&model_id)
+                    .map(|name| spec.variant(name))
+                    .unwrap_or(spec.variants[0]);
+                let mut model = ModelData {
+                    spec,
+                    cached: vec![],
+                    variant,
+                    weights_file: String::new(),
+                };
+                model.check_cached(&options);
+                model
             })
             .collect();
 
         Self {
             selected: None,
+            compare: false,
             models,
         }
     }
@@ -43,19 +65,186 @@ impl Panel for ModelsPanel {
                 .stick_to_bottom(true)
                 .show(ui, |ui| {
                     let width = ui.available_width();
-                    for model in &self.models {
-                        let r = ui.add(model.button(ui).min_size(Vec2::new(width, 120.0)));
+
+                    if !ctx.resident_models.is_empty() {
+                        let memory = ctx.resident_models.iter().map(|m| m.memory).sum::<usize>();
+                        ui.horizontal(|ui| {
+                            ui.add_space(PADDING);
+                            ui.label(format!(
+                                "Loaded models memory: {}M of {}M",
+                                memory / (1 << 20),
+                                ctx.state.load_options.memory_budget / (1 << 20)
+                            ));
+
+                            if ui.button("Compare models").clicked() {
+                                self.compare = true;
+                            }
+                        });
+                        ui.add_space(ui.spacing().item_spacing.y);
+                    }
+
+                    for model in &mut self.models {
+                        let resident = ctx.resident_models.iter().find(|m| {
+                            m.model_id == model.spec.model_id && m.variant == model.variant
+                        });
+                        let button = model.button(ui, resident);
+                        let r = ui.add(button.min_size(Vec2::new(width, 120.0)));
                         if r.clicked() {
-                            self.selected = Some(model.spec.model_id);
+                            self.selected = Some((model.spec.model_id, model.variant));
+                        }
+
+                        let model_id = model.spec.model_id;
+                        let options = ctx
+                            .state
+                            .model_options
+                            .get(&model_id)
+                            .cloned()
+                            .unwrap_or_default();
+                        let mut kv_cache = options.kv_cache;
+                        // A cache type saved before the model check falls back to F16.
+                        if !model.spec.model_id.kv_cache_types().contains(&kv_cache) {
+                            kv_cache = KvCacheType::F16;
+                        }
+                        let mut rope_scaling = options.rope_scaling;
+                        let mut gqa = options.gqa;
+                        let mut weights_path = options.weights_path.display().to_string();
+                        let mut tokenizer_path = options.tokenizer_path.display().to_string();
+
+                        ui.horizontal(|ui| {
+                            ui.add_space(PADDING);
+                            if model.spec.variants.len() > 1 {
+                                ui.label("Quantization:");
+                                ComboBox::from_id_source(model.spec.cache_dir)
+                                    .selected_text(model.variant.name)
+                                    .show_ui(ui, |ui| {
+                                        for variant in model.spec.variants {
+                                            let r = ui.selectable_value(
+                                                &mut model.variant,
+                                                *variant,
+                                                variant.name,
+                                            );
+                                            if r.changed() {
+                                                ctx.state.quant_variants.insert(
+                                                    model.spec.model_id,
+                                                    variant.name.to_string(),
+                                                );
+                                            }
+                                        }
+                                    });
+                            }
+
+                            ui.label("KV cache:");
+                            ComboBox::from_id_source((model.spec.cache_dir, "kv-cache"))
+                                .selected_text(kv_cache.description())
+                                .show_ui(ui, |ui| {
+                                    for &cache_type in model.spec.model_id.kv_cache_types() {
+                                        ui.selectable_value(
+                                            &mut kv_cache,
+                                            cache_type,
+                                            cache_type.description(),
+                                        );
+                                    }
+                                })
+                                .response
+                                .on_hover_text("How the attention keys and values are stored");
+                        });
+
+                        ui.horizontal(|ui| {
+                            ui.add_space(PADDING);
+                            ui.label("RoPE scaling:");
+                            ComboBox::from_id_source((model.spec.cache_dir, "rope-scaling"))
+                                .selected_text(
+                                    rope_scaling.map_or("Model", |s| s.scaling_type.description()),
+                                )
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut rope_scaling, None, "Model");
+                                    for scaling_type in [
+                                        RopeScalingType::Linear,
+                                        RopeScalingType::Ntk,
+                                        RopeScalingType::Yarn,
+                                    ] {
+                                        let factor = rope_scaling.map_or(2.0, |s| s.factor);
+                                        ui.selectable_value(
+                                            &mut rope_scaling,
+                                            Some(RopeScaling {
+                                                scaling_type,
+                                                factor,
+                                            }),
+                                            scaling_type.description(),
+                                        );
+                                    }
+                                })
+                                .response
+                                .on_hover_text("Extends the context length of the model");
+                            if let Some(scaling) = &mut rope_scaling {
+                                ui.add(Slider::new(&mut scaling.factor, 1.0..=8.0).prefix("x"))
+                                    .on_hover_text("How many times the context length is extended");
+                            }
+                        });
+
+                        if model.spec.is_local() {
+                            ui.horizontal(|ui| {
+                                ui.add_space(PADDING);
+                                ui.label("GQA:");
+                                ui.add(DragValue::new(&mut gqa).clamp_range(1..=64))
+                                    .on_hover_text(
+                                        "Attention heads for each key and value head of GGML \
+                                        files, 8 for Llama 2 70B, used when the model is loaded",
+                                    );
+                            });
+
+                            ui.horizontal(|ui| {
+                                ui.add_space(PADDING);
+                                ui.label("Weights:");
+                                ui.add(
+                                    TextEdit::singleline(&mut weights_path)
+                                        .hint_text(format!(
+                                            "{} in the cache",
+                                            model.variant.filename
+                                        ))
+                                        .desired_width(ui.available_width() - PADDING),
+                                )
+                                .on_hover_text("Path of a GGUF or GGML weights file");
+                            });
+
+                            ui.horizontal(|ui| {
+                                ui.add_space(PADDING);
+                                ui.label("Tokenizer:");
+                                ui.add(
+                                    TextEdit::singleline(&mut tokenizer_path)
+                                        .hint_text("Llama tokenizer")
+                                        .desired_width(ui.available_width() - PADDING),
+                                )
+                                .on_hover_text("Path of a tokenizer.json file");
+                            });
+                        }
+
+                        let selected = ModelOptions {
+                            kv_cache,
+                            rope_scaling,
+                            gqa,
+                            weights_path: PathBuf::from(weights_path),
+                            tokenizer_path: PathBuf::from(tokenizer_path),
+                        };
+                        if selected != options {
+                            if selected.weights_path != options.weights_path
+                                || selected.tokenizer_path != options.tokenizer_path
+                            {
+                                model.check_cached(&selected);
+                            }
+                            ctx.state.model_options.insert(model_id, selected);
                         }
+                        ui.add_space(ui.spacing().item_spacing.y);
                     }
                 })
         });
     }
 
     fn next_panel(&mut self, ctx: &mut AppContext) -> Option<Box<dyn Panel>> {
-        if let Some(model_id) = self.selected {
-            Some(Box::new(LoadPanel::new(model_id, ctx)))
+        if self.compare {
+            Some(Box::new(ComparePanel::new(ctx)))
+        } else if let Some((model_id, variant)) = self.selected {
+            Some(Box::new(LoadPanel::new(model_id, variant, ctx)))
         } else {
             None
         }
@@ -69,13 +258,47 @@ impl Panel for ModelsPanel {
 #[derive(Debug)]
 struct ModelData {
     spec: ModelSpec,
-    cached: bool,
+    cached: Vec<bool>,
+    variant: QuantVariant,
+    /// The weights file of user supplied models.
+    weights_file: String,
 }
 
 impl ModelData {
-    fn button(&self, ui: &Ui) -> Button<'_> {
-        const PADDING: f32 = 10.0;
+    /// Checks if the model variants are cached on disk, this is done when the panel is
+    /// created and when the files options change to avoid accessing the disk at every
+    /// frame. User supplied models are cached when their files exist.
+    fn check_cached(&mut self, options: &ModelOptions) {
+        self.weights_file = if options.weights_path.as_os_str().is_empty() {
+            self.variant.filename.to_string()
+        } else {
+            options.weights_path.display().to_string()
+        };
+        self.cached = self
+            .spec
+            .variants
+            .iter()
+            .map(|variant| {
+                ModelsCache::new()
+                    .map(|c| {
+                        c.cached_model(self.spec.model_id, *variant)
+                            .with_options(options)
+                            .is_cached()
+                    })
+                    .unwrap_or(false)
+            })
+            .collect();
+    }
 
+    fn is_cached(&self) -> bool {
+        self.spec
+            .variants
+            .iter()
+            .zip(&self.cached)
+            .any(|(v, cached)| *v == self.variant && *cached)
+    }
+
+    fn button(&self, ui: &Ui, resident: Option<&ResidentModel>) -> Button<'_> {
         let mut job = text::LayoutJob::default();
 
         let font_id = FontId::new(22.0, FontFamily::Monospace);
@@ -101,8 +324,17 @@ impl ModelData {
 
         let font_id = FontId::new(18.0, FontFamily::Monospace);
 
+        let size = if self.spec.is_local() {
+            format!("User supplied file ({})", self.weights_file)
+        } else {
+            format!(
+                "Size: {}M ({})",
+                self.variant.size / (1 << 20),
+                self.variant.name
+            )
+        };
         job.append(
-            &format!("Size: {}M", self.spec.size / (1 << 20)),
+            &size,
             PADDING,
             TextFormat {
                 font_id: font_id.clone(),
@@ -111,7 +343,17 @@ impl ModelData {
             },
         );
 
-        if self.cached {
+        if let Some(resident) = resident {
+            job.append(
+                &format!("(Loaded {}M)", resident.memory / (1 << 20)),
+                PADDING,
+                TextFormat {
+                    font_id,
+                    color: ui.visuals().text_color(),
+                    ..Default::default()
+                },
+            );
+        } else if self.is_cached() {
             job.append(
                 "(Cached)",
                 PADDING,
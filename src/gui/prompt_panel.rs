@@ -1,58 +1,68 @@
 use chrono::prelude::*;
 use eframe::egui::*;
+use std::time::{Duration, Instant};
 
 use crate::{
-    controller::{Message, PromptId},
+    controller::{Message, TokenCount},
     gui::{
         bubble::{Bubble, BubbleContent},
         history::HistoryNavigator,
         AppContext, Panel, Prompt,
     },
-    models::ModelId,
+    models::{ModelId, QuantVariant},
 };
 
 const TEXT_FONT: FontId = FontId::new(15.0, FontFamily::Monospace);
 const ROUNDING: f32 = 8.0;
+const METER_FONT: FontId = FontId::new(11.0, FontFamily::Monospace);
+// Minimum interval between token count requests while typing.
+const COUNT_INTERVAL: Duration = Duration::from_millis(250);
+// Context usage above which a warning is shown.
+const CONTEXT_WARNING: f32 = 0.9;
 
 #[derive(Debug)]
 pub struct PromptPanel {
     prompt: String,
     prompt_field_id: Id,
-    last_prompt_id: PromptId,
     error: Option<String>,
     history: HistoryNavigator,
     frame_counter: usize,
     scroll_to_bottom: bool,
     model_name: String,
+    token_count: Option<TokenCount>,
+    count_pending: bool,
+    last_count_request: Instant,
 }
 
 impl PromptPanel {
-    pub fn new(model_id: ModelId) -> Self {
+    pub fn new(model_id: ModelId, variant: QuantVariant) -> Self {
         Self {
             prompt_field_id: Id::new("prompt-id"),
-            last_prompt_id: PromptId::default(),
             error: None,
             prompt: Default::default(),
             history: HistoryNavigator::new(),
             frame_counter: 0,
             scroll_to_bottom: false,
-            model_name: model_id.spec().name.to_string(),
+            model_name: format!("{} {}", model_id.spec().name, variant.name),
+            token_count: None,
+            count_pending: false,
+            last_count_request: Instant::now(),
         }
     }
 
     fn send_prompt(&mut self, ctx: &mut AppContext) {
         let prompt = self.prompt.trim();
         if !prompt.is_empty() {
-            // Flush tokens from previous prompt
-            while ctx.controller.next_message().is_some() {}
-
-            self.last_prompt_id = ctx.controller.send_prompt(prompt);
+            let prompt_id = ctx.controller.send_prompt(prompt);
 
             let info = format!("{} - {}", self.model_name, Local::now().format("%F %T%.3f"));
             ctx.state.history.push(Prompt {
                 prompt: prompt.to_owned(),
                 reply: Default::default(),
                 info,
+                stats: None,
+                prompt_id: Some(prompt_id),
+                generating: true,
             });
         }
 
@@ -62,11 +72,59 @@ impl PromptPanel {
 
     fn reset_prompt(&mut self, ctx: &Context, prompt: String) {
         self.prompt = prompt;
+        self.count_pending = true;
 
         let state = text_edit::TextEditState::default();
         state.store(ctx, self.prompt_field_id);
     }
 
+    /// Requests a prompt token count, throttled to avoid a request per keystroke.
+    fn request_token_count(&mut self, ctx: &AppContext) {
+        if self.count_pending && self.last_count_request.elapsed() >= COUNT_INTERVAL {
+            self.count_pending = false;
+            if self.prompt.trim().is_empty() {
+                self.token_count = None;
+            } else {
+                self.last_count_request = Instant::now();
+                ctx.controller.count_tokens(self.prompt.trim());
+            }
+        }
+
+        // Schedule a frame to send the count of the last edit once the interval ends.
+        if self.count_pending {
+            let remaining = COUNT_INTERVAL.saturating_sub(self.last_count_request.elapsed());
+            ctx.egui_ctx.request_repaint_after(remaining);
+        }
+    }
+
+    fn context_meter(&self, ui: &mut Ui) {
+        if let Some(count) = self.token_count {
+            let usage = count.tokens as f32 / count.context_length.max(1) as f32;
+            let (color, warning) = if usage > 1.0 {
+                (Color32::LIGHT_RED, " - exceeds context length")
+            } else if usage >= CONTEXT_WARNING {
+                (
+                    Color32::from_rgb(230, 150, 20),
+                    " - close to context length",
+                )
+            } else {
+                (Color32::from_rgb(20, 140, 255), "")
+            };
+
+            let text = format!(
+                "{} / {} tokens{warning}",
+                count.tokens, count.context_length
+            );
+
+            ui.add(
+                ProgressBar::new(usage.min(1.0))
+                    .desired_height(14.0)
+                    .fill(color)
+                    .text(RichText::new(text).font(METER_FONT)),
+            );
+        }
+    }
+
     fn error_window(&mut self, ctx: &Context) {
         // Show error window if any.
         if self.error.is_some() {
@@ -134,18 +192,22 @@ impl Panel for PromptPanel {
                         let r = ui.add_sized([ui.available_width(), 10.0], text);
                         if r.changed() {
                             self.history.reset(&self.prompt);
+                            self.count_pending = true;
                         }
+
+                        self.context_meter(ui);
                     })
             });
 
+        self.request_token_count(ctx);
+
         // Render message panel.
         CentralPanel::default().show(&egui_ctx, |ui| {
             ScrollArea::vertical()
                 .auto_shrink(false)
                 .stick_to_bottom(true)
                 .show(ui, |ui| {
-                    let mut iter = ctx.state.history.iter().peekable();
-                    while let Some(prompt) = iter.next() {
+                    for prompt in &ctx.state.history {
                         let r = ui.add(
                             Bubble::new(&prompt.prompt, BubbleContent::Prompt, ctx.state.ui_mode)
                                 .with_footer(&prompt.info),
@@ -161,29 +223,30 @@ impl Panel for PromptPanel {
 
                         ui.add_space(ui.spacing().item_spacing.y);
 
-                        if !prompt.reply.is_empty() {
-                            let r = ui.add(Bubble::new(
-                                &prompt.reply,
+                        if prompt.generating && prompt.reply.is_empty() {
+                            // Show waiting animation until the first token.
+                            let dots = ["⏺   ", " ⏺  ", "  ⏺ ", "   ⏺", "  ⏺ ", " ⏺  "];
+                            ui.add(Bubble::new(
+                                dots[(self.frame_counter / 18) % dots.len()],
                                 BubbleContent::Reply,
                                 ctx.state.ui_mode,
                             ));
-                            if r.clicked() {
-                                ui.ctx().copy_text(prompt.reply.clone());
+                        } else if !prompt.reply.is_empty() || prompt.stats.is_some() {
+                            let mut bubble =
+                                Bubble::new(&prompt.reply, BubbleContent::Reply, ctx.state.ui_mode);
+                            if prompt.generating {
+                                bubble = bubble.with_footer("generating...");
+                            } else if let Some(stats) = &prompt.stats {
+                                bubble = bubble.with_footer(&stats.summary());
                             }
 
-                            ui.add_space(ui.spacing().item_spacing.y * 2.5);
-                        } else {
-                            // Show waiting animation for last entry.
-                            if iter.peek().is_none() {
-                                let dots = ["⏺   ", " ⏺  ", "  ⏺ ", "   ⏺", "  ⏺ ", " ⏺  "];
-                                ui.add(Bubble::new(
-                                    dots[(self.frame_counter / 18) % dots.len()],
-                                    BubbleContent::Reply,
-                                    ctx.state.ui_mode,
-                                ));
+                            let r = ui.add(bubble);
+                            if r.clicked() {
+                                ui.ctx().copy_text(prompt.reply.clone());
                             }
-                            ui.add_space(ui.spacing().item_spacing.y * 2.5);
                         }
+
+                        ui.add_space(ui.spacing().item_spacing.y * 2.5);
                     }
 
                     if self.scroll_to_bottom {
@@ -228,16 +291,11 @@ impl Panel for PromptPanel {
         }
     }
 
-    fn handle_message(&mut self, app: &mut AppContext, msg: Message) {
+    fn handle_message(&mut self, _app: &mut AppContext, msg: Message) {
         match msg {
-            Message::Token(prompt_id, s) => {
-                // Skip tokens from a previous prompt.
-                if self.last_prompt_id == prompt_id {
-                    if let Some(prompt) = app.state.history.last_mut() {
-                        prompt.reply.push_str(&s);
-                        self.scroll_to_bottom = true;
-                    }
-                }
+            Message::Token(..) => self.scroll_to_bottom = true,
+            Message::TokenCount(count) if !self.prompt.trim().is_empty() => {
+                self.token_count = Some(count);
             }
             Message::Error(s) => self.error = Some(s),
             _ => {}
@@ -0,0 +1,157 @@
+use eframe::egui::*;
+
+use crate::{gui::App, models::Tokenization};
+
+const TEXT_FONT: FontId = FontId::new(14.0, FontFamily::Monospace);
+const ID_FONT: FontId = FontId::new(10.0, FontFamily::Monospace);
+const SPECIAL_COLOR: Color32 = Color32::from_rgb(230, 120, 20);
+const TEMPLATE_COLOR: Color32 = Color32::from_rgb(140, 90, 210);
+
+/// Tokenizer inspector window state.
+#[derive(Debug, Default)]
+pub struct TokenizerInspector {
+    text: String,
+    tokenization: Option<Tokenization>,
+}
+
+impl TokenizerInspector {
+    /// Returns the text being inspected.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Sets the tokenization result received from the controller.
+    pub fn set_tokenization(&mut self, tokenization: Tokenization) {
+        // Skip results for text that has been edited since the request.
+        if tokenization.text == self.text {
+            self.tokenization = Some(tokenization);
+        }
+    }
+}
+
+impl App {
+    pub fn tokenizer_window(&mut self, ctx: &Context) {
+        if self.show_tokenizer {
+            let ui_rect = ctx.used_rect();
+
+            Window::new("Tokenizer")
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .min_width(ui_rect.width() * 0.6)
+                .max_height(ui_rect.height() * 0.8)
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    let text = TextEdit::multiline(&mut self.tokenizer.text)
+                        .font(TEXT_FONT)
+                        .desired_rows(3)
+                        .desired_width(f32::INFINITY)
+                        .hint_text("Text to tokenize");
+
+                    if ui.add(text).changed() {
+                        self.tokenizer.tokenization = None;
+                        self.ctx.controller.tokenize(&self.tokenizer.text);
+                    }
+
+                    ui.add_space(ui.spacing().item_spacing.y * 2.0);
+
+                    if let Some(tokenization) = &self.tokenizer.tokenization {
+                        render_tokenization(ui, tokenization, ui_rect.height() * 0.5);
+                    }
+
+                    ui.vertical_centered(|ui| {
+                        ui.add_space(ui.spacing().item_spacing.y * 2.0);
+                        if ui.button("Close").clicked() {
+                            self.show_tokenizer = false;
+                        }
+                    });
+                });
+        }
+    }
+}
+
+fn render_tokenization(ui: &mut Ui, tokenization: &Tokenization, max_height: f32) {
+    ui.horizontal(|ui| {
+        ui.label(RichText::new(format!("Tokens: {}", tokenization.tokens.len())).font(TEXT_FONT));
+        ui.add_space(ui.spacing().item_spacing.x * 4.0);
+        ui.label(
+            RichText::new("special")
+                .font(TEXT_FONT)
+                .color(SPECIAL_COLOR),
+        );
+        ui.label(
+            RichText::new("template")
+                .font(TEXT_FONT)
+                .color(TEMPLATE_COLOR),
+        );
+    });
+
+    ui.separator();
+
+    ScrollArea::vertical()
+        .max_height(max_height)
+        .auto_shrink([false, true])
+        .show(ui, |ui| {
+            // Show the templated text highlighting the template parts.
+            let templated = &tokenization.templated;
+            let text_start = templated.find(&tokenization.text).unwrap_or_default();
+            let text_end = text_start + tokenization.text.len();
+            let mut job = text::LayoutJob::default();
+            for (range, color) in [
+                (0..text_start, TEMPLATE_COLOR),
+                (text_start..text_end, ui.visuals().text_color()),
+                (text_end..templated.len(), TEMPLATE_COLOR),
+            ] {
+                job.append(
+                    &templated[range],
+                    0.0,
+                    TextFormat {
+                        font_id: TEXT_FONT,
+                        color,
+                        ..Default::default()
+                    },
+                );
+            }
+            ui.label(job);
+
+            ui.separator();
+
+            ui.horizontal_wrapped(|ui| {
+                for token in &tokenization.tokens {
+                    let color = if token.special {
+                        SPECIAL_COLOR
+                    } else if token.template {
+                        TEMPLATE_COLOR
+                    } else {
+                        ui.visuals().text_color()
+                    };
+
+                    // Make whitespace visible.
+                    let text = token.token.replace('\n', "\\n").replace('\t', "\\t");
+
+                    let mut job = text::LayoutJob::default();
+                    job.append(
+                        &text,
+                        0.0,
+                        TextFormat {
+                            font_id: TEXT_FONT,
+                            color,
+                            ..Default::default()
+                        },
+                    );
+                    job.append(
+                        &token.id.to_string(),
+                        4.0,
+                        TextFormat {
+                            font_id: ID_FONT,
+                            color: ui.visuals().weak_text_color(),
+                            ..Default::default()
+                        },
+                    );
+
+                    Frame::group(ui.style())
+                        .inner_margin(Margin::symmetric(4.0, 2.0))
+                        .show(ui, |ui| ui.label(job));
+                }
+            });
+        });
+}
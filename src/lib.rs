@@ -5,3 +5,4 @@ mod gui;
 mod models;
 
 pub use gui::App;
+pub use models::{bench, quantize};
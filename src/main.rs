@@ -1,7 +1,26 @@
 #![warn(clippy::all, rust_2018_idioms)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use std::path::Path;
+
 fn main() -> eframe::Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("quantize") {
+        if let Err(e) = quantize(&args[2..]) {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("bench") {
+        if let Err(e) = bench(&args[2..]) {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     const INIT_SIZE: [f32; 2] = [450.0, 450.0];
     let native_options = eframe::NativeOptions {
         viewport: eframe::egui::ViewportBuilder::default()
@@ -16,3 +35,61 @@ fn main() -> eframe::Result<()> {
         Box::new(|cc| Box::new(coze::App::new(cc))),
     )
 }
+
+/// Runs the quantize subcommand: coze quantize <input.gguf> <output.gguf> <type>
+fn quantize(args: &[String]) -> anyhow::Result<()> {
+    let [input, output, dtype] = args else {
+        anyhow::bail!("Usage: coze quantize <input.gguf> <output.gguf> <type>");
+    };
+
+    let dtype = coze::quantize::parse_dtype(dtype)?;
+    coze::quantize::quantize_gguf(Path::new(input), Path::new(output), dtype, |n, total| {
+        eprint!("\rQuantized {n}/{total} tensors");
+    })?;
+    eprintln!();
+
+    Ok(())
+}
+
+/// Runs the bench subcommand: coze bench <model> [variant]
+fn bench(args: &[String]) -> anyhow::Result<()> {
+    let (name, variant) = match args {
+        [name] => (name, None),
+        [name, variant] => (name, Some(variant)),
+        _ => anyhow::bail!("Usage: coze bench <model> [variant]"),
+    };
+
+    let Some(model_id) = coze::bench::find_model(name) else {
+        anyhow::bail!("Unknown model {name}");
+    };
+    let spec = model_id.spec();
+    let variant = variant.map(|v| spec.variant(v)).unwrap_or(spec.variants[0]);
+
+    eprintln!("Benchmarking {} {}", spec.name, variant.name);
+    coze::bench::bench_model(
+        model_id,
+        variant,
+        &coze::bench::CONTEXTS,
+        &coze::bench::CACHE_TYPES,
+        |report| {
+            let prompt_secs = report.prompt_time.as_secs_f64();
+            println!(
+                "{} cache: {} MB, prompt: {} tokens in {prompt_secs:.2}s ({:.2} tokens/s)",
+                report.cache_type.description(),
+                report.cache_memory >> 20,
+                report.prompt_tokens,
+                report.prompt_tokens as f64 / prompt_secs
+            );
+            for result in &report.generation {
+                println!(
+                    "  context {:>5}: {:.2} tokens/s, {:.0}% tokens match F32",
+                    result.context,
+                    result.tokens_per_second,
+                    result.agreement * 100.0
+                );
+            }
+        },
+    )?;
+
+    Ok(())
+}
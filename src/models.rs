@@ -1,27 +1,53 @@
 //! Models configuration and loading.
-use anyhow::Result;
+use anyhow::{bail, Result};
 use candle::{DType, Tensor};
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
+use std::sync::Arc;
 use strum::{EnumIter, IntoEnumIterator};
 
-pub use cache::ModelsCache;
-pub use config::{ModelConfig, ModelParams};
+pub use cache::{CachedModel, ModelsCache};
+pub use cancel::CancelToken;
+pub use config::{
+    KvCacheType, ModelConfig, ModelOptions, ModelParams, PerfConfig, RopeScaling, RopeScalingType,
+};
+pub use info::ModelInfo;
+pub use lora::{list_adapters, LoraAdapter};
 
+pub mod bench;
 mod cache;
+mod cancel;
 mod config;
+mod info;
+mod loader;
+mod lora;
+mod qgemma;
+mod qllama;
 mod qmistral;
-mod qstablelm;
+mod qphi;
+mod qqwen2;
+pub mod quantize;
 mod qzephyr;
+mod stablelm;
 mod transformers;
 
-#[derive(Debug, Clone, Copy, EnumIter)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter, Serialize, Deserialize)]
 pub enum ModelId {
     Mistral7bInstructV02,
     Mistral7B,
     Zephyr7bBeta,
     StableLm2Zephyr,
+    StableLm2ZephyrFull,
+    Phi2,
+    Phi3Mini4kInstruct,
+    Gemma2bIt,
+    Gemma7bIt,
+    Qwen2_1_5bInstruct,
+    Qwen2_7bInstruct,
+    Mixtral8x7bInstruct,
+    LocalLlama,
 }
 
 impl ModelId {
@@ -31,43 +57,321 @@ impl ModelId {
             ModelId::Mistral7bInstructV02 => ModelSpec {
                 model_id: *self,
                 name: "Mistral Instruct 7B v0.2",
-                size: 4140374304,
                 cache_dir: "mistral_instruct_7b_v02",
                 model_repo: "TheBloke/Mistral-7B-Instruct-v0.2-GGUF",
-                model_filename: "mistral-7b-instruct-v0.2.Q4_K_S.gguf",
+                variants: &[
+                    QuantVariant {
+                        name: "Q4_K_S",
+                        filename: "mistral-7b-instruct-v0.2.Q4_K_S.gguf",
+                        size: 4140374304,
+                    },
+                    QuantVariant {
+                        name: "Q3_K_M",
+                        filename: "mistral-7b-instruct-v0.2.Q3_K_M.gguf",
+                        size: 3518985920,
+                    },
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "mistral-7b-instruct-v0.2.Q4_K_M.gguf",
+                        size: 4368438976,
+                    },
+                    QuantVariant {
+                        name: "Q5_K_M",
+                        filename: "mistral-7b-instruct-v0.2.Q5_K_M.gguf",
+                        size: 5131409696,
+                    },
+                    QuantVariant {
+                        name: "Q8_0",
+                        filename: "mistral-7b-instruct-v0.2.Q8_0.gguf",
+                        size: 7695857952,
+                    },
+                ],
                 tokenizer_repo: "mistralai/Mistral-7B-Instruct-v0.2",
                 tokenizer_filename: "tokenizer.json",
             },
             ModelId::Mistral7B => ModelSpec {
                 model_id: *self,
                 name: "Mistral 7B v0.1",
-                size: 4074411744,
                 cache_dir: "mistral_7b_v01",
-                model_repo: "lmz/candle-mistral",
-                model_filename: "model-q4k.gguf",
+                model_repo: "TheBloke/Mistral-7B-v0.1-GGUF",
+                // The default variant is the original candle file, so that the cached
+                // downloads are still used, it is in its own repo.
+                variants: &[
+                    QuantVariant {
+                        name: "Q4K",
+                        filename: "model-q4k.gguf",
+                        size: 4074411744,
+                    },
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "mistral-7b-v0.1.Q4_K_M.gguf",
+                        size: 4368438976,
+                    },
+                    QuantVariant {
+                        name: "Q3_K_M",
+                        filename: "mistral-7b-v0.1.Q3_K_M.gguf",
+                        size: 3518985920,
+                    },
+                    QuantVariant {
+                        name: "Q5_K_M",
+                        filename: "mistral-7b-v0.1.Q5_K_M.gguf",
+                        size: 5131409696,
+                    },
+                    QuantVariant {
+                        name: "Q8_0",
+                        filename: "mistral-7b-v0.1.Q8_0.gguf",
+                        size: 7695857952,
+                    },
+                ],
                 tokenizer_repo: "mistralai/Mistral-7B-v0.1",
                 tokenizer_filename: "tokenizer.json",
             },
             ModelId::Zephyr7bBeta => ModelSpec {
                 model_id: *self,
                 name: "Zephyr 7B β",
-                size: 4368438976,
                 cache_dir: "zephyr-7b-beta",
                 model_repo: "TheBloke/zephyr-7B-beta-GGUF",
-                model_filename: "zephyr-7b-beta.Q4_K_M.gguf",
+                variants: &[
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "zephyr-7b-beta.Q4_K_M.gguf",
+                        size: 4368438976,
+                    },
+                    QuantVariant {
+                        name: "Q3_K_M",
+                        filename: "zephyr-7b-beta.Q3_K_M.gguf",
+                        size: 3518985920,
+                    },
+                    QuantVariant {
+                        name: "Q5_K_M",
+                        filename: "zephyr-7b-beta.Q5_K_M.gguf",
+                        size: 5131409696,
+                    },
+                    QuantVariant {
+                        name: "Q8_0",
+                        filename: "zephyr-7b-beta.Q8_0.gguf",
+                        size: 7695857952,
+                    },
+                ],
                 tokenizer_repo: "mistralai/Mistral-7B-Instruct-v0.2",
                 tokenizer_filename: "tokenizer.json",
             },
             ModelId::StableLm2Zephyr => ModelSpec {
                 model_id: *self,
                 name: "Stablelm 2 Zephyr 1.6B",
-                size: 1029022272,
                 cache_dir: "stablelm2_zephyr_1_6b",
                 model_repo: "vincevas/coze-stablelm-2-1_6b",
-                model_filename: "stablelm-2-zephyr-1_6b-Q4_1.gguf",
+                variants: &[QuantVariant {
+                    name: "Q4_1",
+                    filename: "stablelm-2-zephyr-1_6b-Q4_1.gguf",
+                    size: 1029022272,
+                }],
                 tokenizer_repo: "stabilityai/stablelm-2-zephyr-1_6b",
                 tokenizer_filename: "tokenizer.json",
             },
+            ModelId::StableLm2ZephyrFull => ModelSpec {
+                model_id: *self,
+                name: "Stablelm 2 Zephyr 1.6B (full precision)",
+                cache_dir: "stablelm2_zephyr_1_6b_full",
+                model_repo: "stabilityai/stablelm-2-zephyr-1_6b",
+                // The BF16 weights are converted when loaded, as the CPU backend has no
+                // BF16 matmul.
+                variants: &[
+                    QuantVariant {
+                        name: "F16",
+                        filename: "model.safetensors",
+                        size: 3289069520,
+                    },
+                    QuantVariant {
+                        name: "F32",
+                        filename: "model.safetensors",
+                        size: 3289069520,
+                    },
+                ],
+                tokenizer_repo: "stabilityai/stablelm-2-zephyr-1_6b",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::Phi2 => ModelSpec {
+                model_id: *self,
+                name: "Phi-2",
+                cache_dir: "phi_2",
+                model_repo: "TheBloke/phi-2-GGUF",
+                // The sizes are rounded from the repo listing, the exact byte counts
+                // still have to be read from the repo.
+                variants: &[
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "phi-2.Q4_K_M.gguf",
+                        size: 1790000000,
+                    },
+                    QuantVariant {
+                        name: "Q5_K_M",
+                        filename: "phi-2.Q5_K_M.gguf",
+                        size: 2070000000,
+                    },
+                    QuantVariant {
+                        name: "Q8_0",
+                        filename: "phi-2.Q8_0.gguf",
+                        size: 2960000000,
+                    },
+                ],
+                tokenizer_repo: "microsoft/phi-2",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::Phi3Mini4kInstruct => ModelSpec {
+                model_id: *self,
+                name: "Phi-3 Mini 4k Instruct",
+                cache_dir: "phi_3_mini_4k_instruct",
+                model_repo: "microsoft/Phi-3-mini-4k-instruct-gguf",
+                // The sizes are rounded from the repo listing, the exact byte counts
+                // still have to be read from the repo.
+                variants: &[QuantVariant {
+                    name: "Q4",
+                    filename: "Phi-3-mini-4k-instruct-q4.gguf",
+                    size: 2390000000,
+                }],
+                tokenizer_repo: "microsoft/Phi-3-mini-4k-instruct",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::Gemma2bIt => ModelSpec {
+                model_id: *self,
+                name: "Gemma 2B Instruct",
+                cache_dir: "gemma_2b_it",
+                model_repo: "lmstudio-ai/gemma-2b-it-GGUF",
+                // The sizes are rounded from the repo listing, the exact byte counts
+                // still have to be read from the repo.
+                variants: &[
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "gemma-2b-it-q4_k_m.gguf",
+                        size: 1500000000,
+                    },
+                    QuantVariant {
+                        name: "Q8_0",
+                        filename: "gemma-2b-it-q8_0.gguf",
+                        size: 2670000000,
+                    },
+                ],
+                // The Gemma repos are gated, see the help for the access token.
+                tokenizer_repo: "google/gemma-2b-it",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::Gemma7bIt => ModelSpec {
+                model_id: *self,
+                name: "Gemma 7B Instruct",
+                cache_dir: "gemma_7b_it",
+                model_repo: "mlabonne/gemma-7b-it-GGUF",
+                // The sizes are rounded from the repo listing, the exact byte counts
+                // still have to be read from the repo.
+                variants: &[
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "gemma-7b-it.Q4_K_M.gguf",
+                        size: 5330000000,
+                    },
+                    QuantVariant {
+                        name: "Q5_K_M",
+                        filename: "gemma-7b-it.Q5_K_M.gguf",
+                        size: 6140000000,
+                    },
+                ],
+                tokenizer_repo: "google/gemma-7b-it",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::Qwen2_1_5bInstruct => ModelSpec {
+                model_id: *self,
+                name: "Qwen2 1.5B Instruct",
+                cache_dir: "qwen2_1_5b_instruct",
+                model_repo: "Qwen/Qwen2-1.5B-Instruct-GGUF",
+                // The sizes are rounded from the repo listing, the exact byte counts
+                // still have to be read from the repo.
+                variants: &[
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "qwen2-1_5b-instruct-q4_k_m.gguf",
+                        size: 990000000,
+                    },
+                    QuantVariant {
+                        name: "Q8_0",
+                        filename: "qwen2-1_5b-instruct-q8_0.gguf",
+                        size: 1650000000,
+                    },
+                ],
+                tokenizer_repo: "Qwen/Qwen2-1.5B-Instruct",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::Qwen2_7bInstruct => ModelSpec {
+                model_id: *self,
+                name: "Qwen2 7B Instruct",
+                cache_dir: "qwen2_7b_instruct",
+                model_repo: "Qwen/Qwen2-7B-Instruct-GGUF",
+                // The sizes are rounded from the repo listing, the exact byte counts
+                // still have to be read from the repo.
+                variants: &[
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "qwen2-7b-instruct-q4_k_m.gguf",
+                        size: 4680000000,
+                    },
+                    QuantVariant {
+                        name: "Q5_K_M",
+                        filename: "qwen2-7b-instruct-q5_k_m.gguf",
+                        size: 5440000000,
+                    },
+                ],
+                tokenizer_repo: "Qwen/Qwen2-7B-Instruct",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::Mixtral8x7bInstruct => ModelSpec {
+                model_id: *self,
+                name: "Mixtral 8x7B Instruct v0.1",
+                cache_dir: "mixtral_8x7b_instruct_v01",
+                model_repo: "TheBloke/Mixtral-8x7B-Instruct-v0.1-GGUF",
+                // The 8 experts of each layer are all loaded, this needs more than
+                // 20GB of memory.
+                // The sizes are rounded from the repo listing, the exact byte counts
+                // still have to be read from the repo.
+                variants: &[
+                    QuantVariant {
+                        name: "Q3_K_M",
+                        filename: "mixtral-8x7b-instruct-v0.1.Q3_K_M.gguf",
+                        size: 20360000000,
+                    },
+                    QuantVariant {
+                        name: "Q4_K_M",
+                        filename: "mixtral-8x7b-instruct-v0.1.Q4_K_M.gguf",
+                        size: 26440000000,
+                    },
+                ],
+                tokenizer_repo: "mistralai/Mixtral-8x7B-Instruct-v0.1",
+                tokenizer_filename: "tokenizer.json",
+            },
+            ModelId::LocalLlama => ModelSpec {
+                model_id: *self,
+                name: "Local Llama (GGUF or GGML)",
+                cache_dir: "local_llama",
+                // The weights file is copied to the cache folder by the user, the
+                // format is detected from the file content.
+                model_repo: "",
+                variants: &[QuantVariant {
+                    name: "Local",
+                    filename: "model.bin",
+                    size: 0,
+                }],
+                tokenizer_repo: "hf-internal-testing/llama-tokenizer",
+                tokenizer_filename: "tokenizer.json",
+            },
+        }
+    }
+
+    /// The kv cache types the model can use, the Q8 cache needs attention heads with
+    /// a size multiple of 32 and Phi-2 heads have 80 dimensions.
+    ///
+    /// The heads of user supplied models are checked when the cache type is set.
+    pub fn kv_cache_types(&self) -> &'static [KvCacheType] {
+        match self {
+            ModelId::Phi2 => &[KvCacheType::F32, KvCacheType::F16],
+            _ => &[KvCacheType::F32, KvCacheType::F16, KvCacheType::Q8],
         }
     }
 
@@ -76,15 +380,84 @@ impl ModelId {
         Self::iter().collect()
     }
 
-    /// Create a model instance.
-    pub fn model(&self, params: ModelParams) -> Result<Box<dyn Model>> {
+    /// Create a model instance using the given quantization variant and the model
+    /// options that are needed to load it.
+    ///
+    /// The progress callback is called with the loaded and total amounts while the
+    /// weights are loaded, returning false cancels loading.
+    pub fn model(
+        &self,
+        variant: QuantVariant,
+        params: ModelParams,
+        model_options: &ModelOptions,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Box<dyn Model>> {
+        let cache = ModelsCache::new()?;
+        let cached_model = cache
+            .cached_model(*self, variant)
+            .with_options(model_options);
+
         match self {
-            ModelId::StableLm2Zephyr => Ok(Box::new(qstablelm::QuantizedStableLM::new(params)?)),
-            ModelId::Zephyr7bBeta => Ok(Box::new(qzephyr::QuantizedZephyr::new(params)?)),
-            ModelId::Mistral7bInstructV02 => {
-                Ok(Box::new(qmistral::QuantizedMistralInstruct::new(params)?))
-            }
-            ModelId::Mistral7B => Ok(Box::new(qmistral::QuantizedMistral7B::new(params)?)),
+            ModelId::StableLm2Zephyr | ModelId::StableLm2ZephyrFull => Ok(Box::new(
+                stablelm::StableLM::new(&cached_model, params, progress)?,
+            )),
+            ModelId::Phi2 => Ok(Box::new(qphi::QuantizedPhi::new(
+                &transformers::quantized_phi::Config::phi_2(),
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            ModelId::Phi3Mini4kInstruct => Ok(Box::new(qphi::QuantizedPhi::new(
+                &transformers::quantized_phi::Config::phi_3_mini_4k(),
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            ModelId::Gemma2bIt => Ok(Box::new(qgemma::QuantizedGemma::new(
+                &transformers::quantized_gemma::Config::gemma_2b(),
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            ModelId::Gemma7bIt => Ok(Box::new(qgemma::QuantizedGemma::new(
+                &transformers::quantized_gemma::Config::gemma_7b(),
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            ModelId::Qwen2_1_5bInstruct => Ok(Box::new(qqwen2::QuantizedQwen2::new(
+                &transformers::quantized_qwen2::Config::qwen2_1_5b(),
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            ModelId::Qwen2_7bInstruct => Ok(Box::new(qqwen2::QuantizedQwen2::new(
+                &transformers::quantized_qwen2::Config::qwen2_7b(),
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            ModelId::Zephyr7bBeta => Ok(Box::new(qzephyr::QuantizedZephyr::new(
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            // Mixtral uses the Mistral instruct template, the experts are in the
+            // GGUF metadata.
+            ModelId::Mistral7bInstructV02 | ModelId::Mixtral8x7bInstruct => Ok(Box::new(
+                qmistral::QuantizedMistralInstruct::new(&cached_model, params, progress)?,
+            )),
+            ModelId::Mistral7B => Ok(Box::new(qmistral::QuantizedMistral7B::new(
+                &cached_model,
+                params,
+                progress,
+            )?)),
+            ModelId::LocalLlama => Ok(Box::new(qllama::QuantizedLlama::new(
+                &cached_model,
+                params,
+                model_options.gqa,
+                progress,
+            )?)),
         }
     }
 }
@@ -96,62 +469,347 @@ pub struct ModelSpec {
     pub model_id: ModelId,
     /// The model model
     pub name: &'static str,
-    /// The model size in GB
-    pub size: usize,
     /// Cache dir
     pub cache_dir: &'static str,
     /// Repo identifier
     pub model_repo: &'static str,
-    /// Model path.
-    pub model_filename: &'static str,
+    /// Quantization variants, the first one is the default.
+    pub variants: &'static [QuantVariant],
     /// Tokenizer repo
     pub tokenizer_repo: &'static str,
     /// Tokenizer path
     pub tokenizer_filename: &'static str,
 }
 
+impl ModelSpec {
+    /// Checks if the weights are supplied by the user rather than downloaded.
+    pub fn is_local(&self) -> bool {
+        self.model_repo.is_empty()
+    }
+
+    /// The repo a variant is downloaded from, that is the model repo except for the
+    /// variants kept from an earlier repo.
+    pub fn variant_repo(&self, variant: QuantVariant) -> &'static str {
+        match (self.model_id, variant.filename) {
+            (ModelId::Mistral7B, "model-q4k.gguf") => "lmz/candle-mistral",
+            _ => self.model_repo,
+        }
+    }
+
+    /// Gets a variant by name, returns the default variant if not found.
+    pub fn variant(&self, name: &str) -> QuantVariant {
+        self.variants
+            .iter()
+            .find(|v| v.name == name)
+            .copied()
+            .unwrap_or(self.variants[0])
+    }
+}
+
+/// Options used when loading a model.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LoadOptions {
+    /// Memory budget in bytes for the models kept loaded, the least recently used
+    /// models are unloaded when the budget is exceeded.
+    #[serde(default = "LoadOptions::default_memory_budget")]
+    pub memory_budget: usize,
+}
+
+impl LoadOptions {
+    fn default_memory_budget() -> usize {
+        8 << 30
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            memory_budget: Self::default_memory_budget(),
+        }
+    }
+}
+
+/// A quantization variant of the model weights.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantVariant {
+    /// The quantization name, e.g. Q4_K_M.
+    pub name: &'static str,
+    /// Model path in the repo.
+    pub filename: &'static str,
+    /// The model size in bytes.
+    pub size: usize,
+}
+
+impl QuantVariant {
+    /// Checks if the variant has full precision safetensors weights, the filename is
+    /// either the weights file or the index of the weights shards.
+    pub fn is_safetensors(&self) -> bool {
+        self.filename.ends_with(".safetensors")
+            || self.filename.ends_with(".safetensors.index.json")
+    }
+
+    /// The type the weights of a safetensors variant are converted to, named after
+    /// the variant.
+    pub fn dtype(&self) -> DType {
+        match self.name {
+            "F16" => DType::F16,
+            "BF16" => DType::BF16,
+            _ => DType::F32,
+        }
+    }
+}
+
 /// Interface to an inference model.
-pub trait Model {
+pub trait Model: Send {
     /// Initialize the model with a prompt.
     fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream>;
 
     /// Runs the forward step for the given tokens.
     fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32>;
 
+    /// Runs the forward step for the given tokens to fill the kv cache, without
+    /// computing the logits.
+    fn fill_kv_cache(&mut self, tokens: &[u32], pos: usize) -> Result<()>;
+
+    /// Processes the prompt tokens in chunks of at most `chunk_size` tokens through
+    /// the kv cache, so that a long prompt doesn't need a single large attention
+    /// matrix, returns the token sampled after the last chunk.
+    fn prefill(&mut self, tokens: &[u32], chunk_size: usize) -> Result<u32> {
+        if tokens.is_empty() {
+            return Ok(0);
+        }
+
+        // Only the last chunk needs the logits to sample the next token.
+        let chunk_size = chunk_size.max(1);
+        let last = (tokens.len() - 1) / chunk_size * chunk_size;
+        for (idx, chunk) in tokens[..last].chunks(chunk_size).enumerate() {
+            self.fill_kv_cache(chunk, idx * chunk_size)?;
+        }
+        self.forward(&tokens[last..], last)
+    }
+
     /// Decode the given tokens.
     fn decode(&mut self, tokens: &[u32]) -> Result<String>;
+
+    /// Returns the model tokenizer.
+    fn tokenizer(&self) -> &tokenizers::Tokenizer;
+
+    /// Applies the model chat template to a prompt.
+    fn chat_template(&self, prompt: &str) -> String;
+
+    /// The maximum number of tokens the model can process.
+    fn context_length(&self) -> usize;
+
+    /// What has been loaded for the model, read from the weights file.
+    fn info(&self) -> &ModelInfo;
+
+    /// Whether LoRA adapters can be applied to the model.
+    fn supports_adapters(&self) -> bool {
+        false
+    }
+
+    /// Applies LoRA adapters with their scales, an empty list removes all adapters.
+    fn set_adapters(&mut self, adapters: &[(Arc<LoraAdapter>, f32)]) -> Result<()> {
+        if adapters.is_empty() {
+            Ok(())
+        } else {
+            bail!("LoRA adapters are not supported by this model")
+        }
+    }
+
+    /// Rolls back the kv cache to the first `len` tokens, so that generation can
+    /// continue from an earlier position without processing the prompt again.
+    fn truncate_kv_cache(&mut self, _len: usize) -> Result<()> {
+        bail!("Cache truncation is not supported by this model")
+    }
+
+    /// Sets how the kv cache is stored, this clears the cache.
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        match cache_type {
+            KvCacheType::F32 => Ok(()),
+            KvCacheType::F16 | KvCacheType::Q8 => {
+                bail!("A reduced precision kv cache is not supported by this model")
+            }
+        }
+    }
+
+    /// The memory used by the kv cache in bytes.
+    fn kv_cache_memory(&self) -> usize {
+        0
+    }
+
+    /// The memory used by the tensors computed from the weights while loading in
+    /// bytes, such as dequantized embeddings and rotary embeddings tables, that is
+    /// not part of the weights memory.
+    fn derived_memory(&self) -> usize {
+        0
+    }
+
+    /// Sets the scaling of the rotary embeddings to extend the context length, None
+    /// uses the scaling in the model metadata, this clears the kv cache.
+    fn set_rope_scaling(&mut self, scaling: Option<RopeScaling>) -> Result<()> {
+        match scaling {
+            None => Ok(()),
+            Some(_) => bail!("RoPE scaling is not supported by this model"),
+        }
+    }
+
+    /// Sets the token used to stop prompt processing and generation, models that
+    /// don't support it are stopped between tokens.
+    fn set_cancel_token(&mut self, _cancel: CancelToken) {}
+
+    /// The experts selected by the router of each layer for the last prompt and its
+    /// reply, None for models without a mixture of experts.
+    fn expert_stats(&self) -> Option<ExpertStats> {
+        None
+    }
+
+    /// Counts the tokens used by the templated prompt.
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        let encoding = self
+            .tokenizer()
+            .encode(self.chat_template(text), true)
+            .map_err(anyhow::Error::msg)?;
+        Ok(encoding.len())
+    }
+
+    /// Tokenizes the templated prompt reporting each token details.
+    fn tokenize(&self, text: &str) -> Result<Tokenization> {
+        let tokenizer = self.tokenizer();
+        let templated = self.chat_template(text);
+        let encoding = tokenizer
+            .encode(templated.as_str(), true)
+            .map_err(anyhow::Error::msg)?;
+
+        // Tokens outside the prompt text range come from the template.
+        let text_start = templated.find(text).unwrap_or_default();
+        let text_end = text_start + text.len();
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+
+        let tokens = encoding
+            .get_ids()
+            .iter()
+            .zip(encoding.get_offsets())
+            .zip(encoding.get_special_tokens_mask())
+            .map(|((&id, &(start, end)), &special_mask)| TokenInfo {
+                id,
+                token: tokenizer.id_to_token(id).unwrap_or_default(),
+                special: special_mask == 1
+                    || added_tokens.get(&id).map(|t| t.special).unwrap_or(false),
+                template: start == end || end <= text_start || start >= text_end,
+            })
+            .collect();
+
+        Ok(Tokenization {
+            text: text.to_string(),
+            templated,
+            tokens,
+        })
+    }
+}
+
+/// The result of tokenizing a prompt with a model tokenizer.
+#[derive(Debug, Clone)]
+pub struct Tokenization {
+    /// The tokenized text.
+    pub text: String,
+    /// The text after applying the chat template.
+    pub templated: String,
+    /// The templated text tokens.
+    pub tokens: Vec<TokenInfo>,
+}
+
+/// Information about a single token.
+#[derive(Debug, Clone)]
+pub struct TokenInfo {
+    /// The token id.
+    pub id: u32,
+    /// The token string from the tokenizer vocabulary.
+    pub token: String,
+    /// Whether this is a special token.
+    pub special: bool,
+    /// Whether this token was added by the chat template.
+    pub template: bool,
+}
+
+/// How often the experts of a mixture of experts model have been selected.
+#[derive(Debug, Clone, Default)]
+pub struct ExpertStats {
+    /// The number of experts selected for each token.
+    pub n_expert_used: usize,
+    /// The selection counts of each expert, one list for each layer.
+    pub selections: Vec<Vec<usize>>,
+}
+
+impl ExpertStats {
+    /// The number of tokens routed through each layer.
+    pub fn tokens(&self) -> usize {
+        self.selections
+            .first()
+            .map(|layer| layer.iter().sum::<usize>() / self.n_expert_used.max(1))
+            .unwrap_or_default()
+    }
 }
 
 /// Generates tokens for a model.
 #[derive(Debug)]
 pub struct TokensStream {
-    eos_token: u32,
+    eos_tokens: Vec<u32>,
     prompt_tokens_len: usize,
     tokens: Vec<u32>,
     consumed: bool,
+    context_full: bool,
 }
 
 impl TokensStream {
     /// Creates a new stream.
     pub fn new(eos_token: u32, prompt_tokens_len: usize) -> Self {
+        Self::with_eos_tokens(vec![eos_token], prompt_tokens_len)
+    }
+
+    /// Creates a new stream that stops at any of the given tokens, for models that end
+    /// their turn and the text with different tokens.
+    pub fn with_eos_tokens(eos_tokens: Vec<u32>, prompt_tokens_len: usize) -> Self {
         Self {
-            eos_token,
+            eos_tokens,
             prompt_tokens_len,
             tokens: vec![0],
             consumed: false,
+            context_full: false,
         }
     }
 
+    /// Returns the number of tokens in the prompt.
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens_len
+    }
+
+    /// Returns the number of generated tokens.
+    pub fn generated_tokens(&self) -> usize {
+        // The first token is a placeholder for the prompt.
+        self.tokens.len() - 1 + usize::from(self.consumed)
+    }
+
+    /// Returns true if generation stopped at the model context length.
+    pub fn is_context_full(&self) -> bool {
+        self.context_full
+    }
+
     /// Generates the next token.
     pub fn next(&mut self, model: &mut dyn Model) -> Result<Option<String>> {
-        if self.consumed {
+        if self.consumed || self.context_full {
             Ok(None)
         } else {
             let decode_idx = self.tokens.len().saturating_sub(5);
             let prev_text = model.decode(&self.tokens[decode_idx..])?;
             loop {
+                if self.prompt_tokens_len + self.tokens.len() >= model.context_length() {
+                    self.context_full = true;
+                    return Ok(None);
+                }
+
                 let token = self.next_token(model)?;
-                if token == self.eos_token {
+                if self.eos_tokens.contains(&token) {
                     self.consumed = true;
                     return Ok(None);
                 }
@@ -0,0 +1,194 @@
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+
+use crate::models::{
+    KvCacheType, Model, ModelConfig, ModelId, ModelOptions, ModelParams, ModelsCache, QuantVariant,
+};
+
+/// The context lengths measured by default.
+pub const CONTEXTS: [usize; 4] = [512, 1024, 2048, 4096];
+
+/// The kv cache types measured by default.
+pub const CACHE_TYPES: [KvCacheType; 3] = [KvCacheType::F32, KvCacheType::F16, KvCacheType::Q8];
+
+/// Number of tokens generated at each context length.
+const GENERATED_TOKENS: usize = 32;
+
+/// Text repeated to fill the context.
+const FILL_TEXT: &str = "The quick brown fox jumps over the lazy dog while the cat \
+    watches from the window, counting the leaves that fall from the old oak tree. ";
+
+/// Benchmark results for a model with a kv cache type.
+#[derive(Debug)]
+pub struct BenchReport {
+    /// The kv cache type.
+    pub cache_type: KvCacheType,
+    /// Memory used by the kv cache with the context filled.
+    pub cache_memory: usize,
+    /// Number of prompt tokens processed to fill the context.
+    pub prompt_tokens: usize,
+    /// Time to process the prompt tokens.
+    pub prompt_time: Duration,
+    /// The generation results for each context length.
+    pub generation: Vec<ContextResult>,
+}
+
+/// Generation results at a context length.
+#[derive(Debug)]
+pub struct ContextResult {
+    /// The number of tokens in the context.
+    pub context: usize,
+    /// The generation speed in tokens per second.
+    pub tokens_per_second: f64,
+    /// The fraction of generated tokens that match the ones generated with the F32
+    /// cache, measures the quality impact of the cache type.
+    pub agreement: f64,
+}
+
+/// Finds a model by its cache folder name, e.g. `zephyr-7b-beta`.
+pub fn find_model(name: &str) -> Option<ModelId> {
+    ModelId::models()
+        .into_iter()
+        .find(|m| m.spec().cache_dir == name)
+}
+
+/// Measures the prompt processing and generation speed of a cached model with each
+/// kv cache type it supports.
+///
+/// The context is filled once with the longest prompt, then for each context length,
+/// from the longest to the shortest, the kv cache is rolled back to that length and
+/// some tokens are generated from there. The generated tokens are compared with the
+/// ones generated using the F32 cache, that is measured first.
+pub fn bench_model(
+    model_id: ModelId,
+    variant: QuantVariant,
+    contexts: &[usize],
+    cache_types: &[KvCacheType],
+    mut report_fn: impl FnMut(&BenchReport),
+) -> Result<()> {
+    let spec = model_id.spec();
+    if !ModelsCache::new()?
+        .cached_model(model_id, variant)
+        .is_cached()
+    {
+        bail!(
+            "{} {} is not cached, load it from the app first",
+            spec.name,
+            variant.name
+        );
+    }
+
+    let params = ModelConfig::default().params();
+    let mut model = model_id.model(variant, params, &ModelOptions::default(), &mut |_, _| true)?;
+
+    let max_context = model.context_length().saturating_sub(GENERATED_TOKENS);
+    let mut contexts = contexts
+        .iter()
+        .map(|&c| c.clamp(1, max_context))
+        .collect::<Vec<_>>();
+    contexts.sort_unstable();
+    contexts.dedup();
+    let Some(&prompt_len) = contexts.last() else {
+        bail!("No context lengths to measure");
+    };
+
+    let fill = model
+        .tokenizer()
+        .encode(FILL_TEXT, false)
+        .map_err(anyhow::Error::msg)?;
+    let tokens = fill
+        .get_ids()
+        .iter()
+        .copied()
+        .cycle()
+        .take(prompt_len)
+        .collect::<Vec<_>>();
+
+    let mut reference = None;
+    // The cache types the model heads can't use are skipped.
+    let supported = model_id.kv_cache_types();
+    let others = cache_types
+        .iter()
+        .filter(|&&t| t != KvCacheType::F32 && supported.contains(&t));
+    for &cache_type in [KvCacheType::F32].iter().chain(others) {
+        let (report, generated) =
+            bench_cache(model.as_mut(), cache_type, &params, &tokens, &contexts)?;
+        let reference = reference.get_or_insert_with(|| generated.clone());
+        if cache_type != KvCacheType::F32 || cache_types.contains(&KvCacheType::F32) {
+            let generation = report
+                .generation
+                .into_iter()
+                .zip(generated.iter().zip(reference.iter()))
+                .map(|(result, (tokens, reference))| ContextResult {
+                    agreement: agreement(tokens, reference),
+                    ..result
+                })
+                .collect();
+            report_fn(&BenchReport {
+                generation,
+                ..report
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the benchmark with a cache type, returns the report and the generated tokens
+/// for each context length.
+fn bench_cache(
+    model: &mut dyn Model,
+    cache_type: KvCacheType,
+    params: &ModelParams,
+    tokens: &[u32],
+    contexts: &[usize],
+) -> Result<(BenchReport, Vec<Vec<u32>>)> {
+    model.set_kv_cache_type(cache_type)?;
+
+    let start = Instant::now();
+    model.prefill(tokens, params.prefill_chunk_size)?;
+    let prompt_time = start.elapsed();
+    let cache_memory = model.kv_cache_memory();
+
+    let mut generation = Vec::with_capacity(contexts.len());
+    let mut generated = Vec::with_capacity(contexts.len());
+    for &context in contexts.iter().rev() {
+        // The last context token is fed again to sample the first generated token.
+        model.truncate_kv_cache(context - 1)?;
+
+        let start = Instant::now();
+        let mut context_tokens = Vec::with_capacity(GENERATED_TOKENS);
+        let mut token = tokens[context - 1];
+        for pos in context - 1..context - 1 + GENERATED_TOKENS {
+            token = model.forward(&[token], pos)?;
+            context_tokens.push(token);
+        }
+        generation.push(ContextResult {
+            context,
+            tokens_per_second: GENERATED_TOKENS as f64 / start.elapsed().as_secs_f64(),
+            agreement: 1.0,
+        });
+        generated.push(context_tokens);
+    }
+    generation.reverse();
+    generated.reverse();
+
+    let report = BenchReport {
+        cache_type,
+        cache_memory,
+        prompt_tokens: tokens.len(),
+        prompt_time,
+        generation,
+    };
+    Ok((report, generated))
+}
+
+/// The fraction of equal tokens up to the first difference.
+fn agreement(tokens: &[u32], reference: &[u32]) -> f64 {
+    let equal = tokens
+        .iter()
+        .zip(reference)
+        .take_while(|(t, r)| t == r)
+        .count();
+    equal as f64 / reference.len().max(1) as f64
+}
@@ -1,13 +1,18 @@
 use anyhow::{anyhow, bail, Result};
 use hf_hub::api::sync::ApiBuilder;
 use std::{
-    fs, io,
+    collections::{BTreeSet, HashMap},
+    env, fs, io,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
-use crate::models::{ModelId, ModelSpec};
+use crate::models::{ModelId, ModelOptions, ModelSpec, QuantVariant};
 
 const MODELS_PATH: &str = "models";
+const ADAPTERS_PATH: &str = "adapters";
+const CONFIG_FILENAME: &str = "config.json";
+const HF_TOKEN_VAR: &str = "HF_TOKEN";
 
 /// Models files cache.
 #[derive(Debug)]
@@ -27,25 +32,39 @@ impl ModelsCache {
         Ok(Self { cache_dir })
     }
 
-    /// Gets a cached model.
+    /// The folder where LoRA adapters are stored, one adapter per sub folder.
+    pub fn adapters_dir(&self) -> PathBuf {
+        self.cache_dir.join(ADAPTERS_PATH)
+    }
+
+    /// Gets a cached model for the given quantization variant.
     ///
-    /// The model may be empty and needs to be downloaded.
-    pub fn cached_model(&self, model_id: ModelId) -> CachedModel {
+    /// The model may be empty and needs to be downloaded, each variant is cached in its
+    /// own file under the model cache folder.
+    pub fn cached_model(&self, model_id: ModelId, variant: QuantVariant) -> CachedModel {
         let spec = model_id.spec();
 
         let cache_path = self.cache_dir.join(MODELS_PATH).join(spec.cache_dir);
-        let model_path = cache_path.join(spec.model_filename);
+        let model_path = cache_path.join(variant.filename);
         let tokenizer_path = if !spec.tokenizer_filename.is_empty() {
             cache_path.join(spec.tokenizer_filename)
         } else {
             PathBuf::new()
         };
+        let config_path = if variant.is_safetensors() {
+            cache_path.join(CONFIG_FILENAME)
+        } else {
+            PathBuf::new()
+        };
 
         CachedModel {
             cache_path,
             model_path,
             tokenizer_path,
+            config_path,
             spec,
+            variant,
+            user_tokenizer: false,
         }
     }
 }
@@ -59,19 +78,86 @@ pub struct CachedModel {
     pub model_path: PathBuf,
     /// Tokenizer file path, may be empty for models without a tokenizer.
     pub tokenizer_path: PathBuf,
+    /// The config.json path of safetensors models, empty for GGUF models.
+    pub config_path: PathBuf,
     /// Model specifications.
     pub spec: ModelSpec,
+    /// The quantization variant.
+    pub variant: QuantVariant,
+    /// Set when the tokenizer file is supplied by the user and is not downloaded.
+    pub user_tokenizer: bool,
 }
 
 impl CachedModel {
+    /// Uses the weights and tokenizer files set in the options for user supplied
+    /// models, the files in the cache folder are used for the paths that are not set.
+    pub fn with_options(mut self, options: &ModelOptions) -> Self {
+        if self.spec.is_local() {
+            if !options.weights_path.as_os_str().is_empty() {
+                self.model_path = options.weights_path.clone();
+            }
+            if !options.tokenizer_path.as_os_str().is_empty() {
+                self.tokenizer_path = options.tokenizer_path.clone();
+                self.user_tokenizer = true;
+            }
+        }
+        self
+    }
+
     /// Checks if this model has been cached to disk.
     pub fn is_cached(&self) -> bool {
         self.is_model_cached() && self.is_tokenizer_cached()
     }
 
-    /// Checks if this model file is cached.
+    /// Checks if this model files are cached, for safetensors models these are the
+    /// config and all the weights shards.
     pub fn is_model_cached(&self) -> bool {
-        self.model_path.exists()
+        if !self.variant.is_safetensors() {
+            return self.model_path.exists();
+        }
+        self.config_path.exists()
+            && self
+                .weights_paths()
+                .map(|paths| paths.iter().all(|p| p.exists()))
+                .unwrap_or(false)
+    }
+
+    /// The safetensors weights files, a sharded model lists its shards in the
+    /// `model.safetensors.index.json` weights map.
+    pub fn weights_paths(&self) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .shard_filenames()?
+            .into_iter()
+            .map(|filename| self.cache_path.join(filename))
+            .collect())
+    }
+
+    fn shard_filenames(&self) -> Result<Vec<String>> {
+        if !self.variant.filename.ends_with(".index.json") {
+            return Ok(vec![self.variant.filename.to_string()]);
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Index {
+            weight_map: HashMap<String, String>,
+        }
+
+        let index: Index = serde_json::from_reader(fs::File::open(&self.model_path)?)?;
+        let shards = index.weight_map.into_values().collect::<BTreeSet<_>>();
+        Ok(shards.into_iter().collect())
+    }
+
+    /// The size in bytes of the cached weights files, None if they are not cached.
+    pub fn weights_size(&self) -> Option<usize> {
+        let paths = if self.variant.is_safetensors() {
+            self.weights_paths().ok()?
+        } else {
+            vec![self.model_path.clone()]
+        };
+        paths
+            .iter()
+            .map(|path| fs::metadata(path).ok().map(|md| md.len() as usize))
+            .sum()
     }
 
     /// Checks if this model tokenizer file is cached.
@@ -83,10 +169,20 @@ impl CachedModel {
         }
     }
 
-    /// Downloads model file from Hugging Face.
+    /// Downloads model file from Hugging Face, safetensors models also download the
+    /// config and the weights shards, user supplied weights are an error.
     ///
     /// The update_fn reports percentage progress to the caller.
     pub fn download_model(&self, update_fn: impl Fn(f32) -> bool + 'static) -> Result<()> {
+        if self.spec.is_local() {
+            bail!(
+                "{} is not a download, {} doesn't exist, set a GGUF or GGML weights file \
+                in the models panel or copy it there",
+                self.spec.name,
+                self.model_path.display()
+            );
+        }
+
         fs::create_dir_all(&self.cache_path)
             .map_err(|e| anyhow!("Unable to create model cache dir: {e}"))?;
 
@@ -94,19 +190,47 @@ impl CachedModel {
             .with_progress(false)
             .build()
             .map_err(|e| anyhow!("Hub api error: {e}"))?;
+        let repo = api.model(self.spec.variant_repo(self.variant).to_string());
+
+        if !self.variant.is_safetensors() {
+            let weights_url = repo.url(self.variant.filename);
+            return download_from_repo(weights_url, &self.model_path, update_fn);
+        }
 
-        let weights_url = api
-            .model(self.spec.model_repo.to_string())
-            .url(self.spec.model_filename);
+        download_from_repo(repo.url(CONFIG_FILENAME), &self.config_path, |_| true)?;
+        if self.variant.filename.ends_with(".index.json") {
+            let index_url = repo.url(self.variant.filename);
+            download_from_repo(index_url, &self.model_path, |_| true)?;
+        }
 
-        download_from_repo(weights_url, &self.model_path, update_fn)
+        // The progress of each file is reported as a fraction of all files.
+        let update_fn = Rc::new(update_fn);
+        let filenames = self.shard_filenames()?;
+        let n_files = filenames.len();
+        for (idx, filename) in filenames.into_iter().enumerate() {
+            let update_fn = update_fn.clone();
+            download_from_repo(
+                repo.url(&filename),
+                &self.cache_path.join(filename),
+                move |pct| update_fn((idx as f32 + pct) / n_files as f32),
+            )?;
+        }
+        Ok(())
     }
 
-    /// Downloads tokenizer file from Hugging Face.
+    /// Downloads tokenizer file from Hugging Face, a user supplied tokenizer is not
+    /// downloaded and is an error when it is missing.
     ///
     /// The update_fn reports percentage progress to the caller.
     pub fn download_tokenizer(&self, update_fn: impl Fn(f32) -> bool + 'static) -> Result<()> {
-        if self.has_tokenizer() {
+        if self.user_tokenizer {
+            if !self.tokenizer_path.exists() {
+                bail!(
+                    "The tokenizer {} doesn't exist",
+                    self.tokenizer_path.display()
+                );
+            }
+        } else if self.has_tokenizer() {
             // If the spec has a tokenizer the path should not be empty.
             assert!(!self.tokenizer_path.as_os_str().is_empty());
 
@@ -141,7 +265,12 @@ pub fn download_from_repo(
 ) -> Result<()> {
     let agent = ureq::builder().try_proxy_from_env(true).build();
 
-    let response = agent.get(&url).call()?;
+    // Gated repos need the access token of an account that accepted their license.
+    let mut request = agent.get(&url);
+    if let Ok(token) = env::var(HF_TOKEN_VAR) {
+        request = request.set("Authorization", &format!("Bearer {token}"));
+    }
+    let response = request.call()?;
     let content_length = response
         .header("content-length")
         .and_then(|s| s.parse::<usize>().ok())
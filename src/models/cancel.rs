@@ -0,0 +1,36 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A flag shared between the UI and the models to stop long running work.
+///
+/// Models check the token between transformer layers so that a stop request takes
+/// effect even in the middle of a long prompt processing.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Requests cancellation.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears a cancellation request.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    /// Checks if cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Returns an error if cancellation has been requested.
+    pub fn check(&self) -> candle::Result<()> {
+        if self.is_cancelled() {
+            candle::bail!("Generation cancelled");
+        }
+        Ok(())
+    }
+}
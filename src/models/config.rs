@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 /// The model configuration that defines how tokens are generated.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -42,15 +43,26 @@ pub struct ModelParams {
     pub repeat_penalty: f32,
     /// The context size to consider for the repeat penalty.
     pub repeat_last_n: usize,
+    /// Maximum number of prompt tokens processed in a single forward pass.
+    pub prefill_chunk_size: usize,
 }
 
 impl ModelParams {
+    /// Sets the prompt chunk size, see [`PerfConfig::prefill_chunk_size`].
+    pub fn with_prefill_chunk_size(self, prefill_chunk_size: usize) -> Self {
+        Self {
+            prefill_chunk_size,
+            ..self
+        }
+    }
+
     fn careful() -> Self {
         Self {
             top_k: 1,
             temperature: 1.,
             repeat_penalty: 1.2,
             repeat_last_n: 64,
+            prefill_chunk_size: PerfConfig::DEFAULT_PREFILL_CHUNK_SIZE,
         }
     }
 
@@ -60,6 +72,7 @@ impl ModelParams {
             temperature: 2.,
             repeat_penalty: 1.2,
             repeat_last_n: 64,
+            prefill_chunk_size: PerfConfig::DEFAULT_PREFILL_CHUNK_SIZE,
         }
     }
 
@@ -69,6 +82,162 @@ impl ModelParams {
             temperature: 5.,
             repeat_penalty: 2.,
             repeat_last_n: 128,
+            prefill_chunk_size: PerfConfig::DEFAULT_PREFILL_CHUNK_SIZE,
+        }
+    }
+}
+
+/// How the attention keys and values are stored in the kv cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvCacheType {
+    /// Full precision.
+    #[default]
+    F32,
+    /// Half precision, uses half the memory of F32.
+    F16,
+    /// 8 bits blocks, uses about a quarter of the memory of F32.
+    Q8,
+}
+
+impl KvCacheType {
+    /// Gets the value description.
+    pub fn description(&self) -> &'static str {
+        match self {
+            KvCacheType::F32 => "F32",
+            KvCacheType::F16 => "F16",
+            KvCacheType::Q8 => "Q8",
+        }
+    }
+}
+
+/// How the rotary position embeddings are scaled to extend the context length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RopeScalingType {
+    /// Positions are divided by the factor.
+    Linear,
+    /// The frequency base is increased so that the low frequencies are interpolated
+    /// and the high frequencies are kept.
+    Ntk,
+    /// Frequencies are interpolated by wavelength and the attention is scaled (YaRN).
+    Yarn,
+}
+
+impl RopeScalingType {
+    /// Gets the value description.
+    pub fn description(&self) -> &'static str {
+        match self {
+            RopeScalingType::Linear => "Linear",
+            RopeScalingType::Ntk => "NTK",
+            RopeScalingType::Yarn => "YaRN",
+        }
+    }
+
+    /// Parses the `rope.scaling.type` GGUF metadata value.
+    pub fn from_gguf(value: &str) -> Option<Self> {
+        match value {
+            "linear" => Some(RopeScalingType::Linear),
+            "ntk" => Some(RopeScalingType::Ntk),
+            "yarn" => Some(RopeScalingType::Yarn),
+            _ => None,
+        }
+    }
+}
+
+/// Scaling of the rotary position embeddings.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RopeScaling {
+    /// The scaling method.
+    pub scaling_type: RopeScalingType,
+    /// How many times the context length is extended.
+    pub factor: f32,
+}
+
+/// Options chosen for each model in the models panel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelOptions {
+    /// How the kv cache is stored.
+    pub kv_cache: KvCacheType,
+    /// Scaling of the rotary embeddings, None uses the scaling in the model metadata.
+    pub rope_scaling: Option<RopeScaling>,
+    /// The number of attention heads that share a key and value head for GGML files,
+    /// that don't store it, e.g. 8 for Llama 2 70B. Used when the model is loaded.
+    #[serde(default = "ModelOptions::default_gqa")]
+    pub gqa: usize,
+    /// The weights file of user supplied models, empty uses the file in the models
+    /// cache folder.
+    #[serde(default)]
+    pub weights_path: PathBuf,
+    /// The tokenizer file of user supplied models, empty uses the tokenizer of the
+    /// model spec.
+    #[serde(default)]
+    pub tokenizer_path: PathBuf,
+}
+
+impl ModelOptions {
+    fn default_gqa() -> usize {
+        1
+    }
+}
+
+impl Default for ModelOptions {
+    fn default() -> Self {
+        Self {
+            kv_cache: KvCacheType::default(),
+            rope_scaling: None,
+            gqa: Self::default_gqa(),
+            weights_path: PathBuf::new(),
+            tokenizer_path: PathBuf::new(),
+        }
+    }
+}
+
+/// Compute resources used for inference.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PerfConfig {
+    /// Number of compute threads, 0 uses all cores.
+    pub threads: usize,
+    /// Run compute threads with low priority to keep the machine responsive.
+    pub low_priority: bool,
+    /// Maximum number of prompt tokens processed in a single forward pass, long
+    /// prompts are split in chunks to limit the memory used by attention.
+    #[serde(default = "PerfConfig::default_prefill_chunk_size")]
+    pub prefill_chunk_size: usize,
+}
+
+impl PerfConfig {
+    pub const DEFAULT_PREFILL_CHUNK_SIZE: usize = 512;
+
+    /// Whether the compute threads priority can be lowered on this platform.
+    pub const LOW_PRIORITY_SUPPORTED: bool =
+        cfg!(any(target_os = "linux", target_os = "macos", windows));
+
+    fn default_prefill_chunk_size() -> usize {
+        Self::DEFAULT_PREFILL_CHUNK_SIZE
+    }
+
+    /// Returns the number of available cores.
+    pub fn max_threads() -> usize {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    }
+
+    /// Returns the number of compute threads to use.
+    pub fn num_threads(&self) -> usize {
+        if self.threads == 0 {
+            Self::max_threads()
+        } else {
+            self.threads.min(Self::max_threads())
+        }
+    }
+}
+
+impl Default for PerfConfig {
+    fn default() -> Self {
+        Self {
+            threads: 0,
+            low_priority: false,
+            prefill_chunk_size: Self::DEFAULT_PREFILL_CHUNK_SIZE,
         }
     }
 }
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use candle::{
+    quantized::{ggml_file, gguf_file},
+    DType,
+};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::models::loader::SafetensorsHeader;
+
+/// What has been loaded for a model, read from the weights file while loading.
+#[derive(Debug, Clone, Default)]
+pub struct ModelInfo {
+    /// The architecture name, e.g. llama.
+    pub architecture: String,
+    /// Number of weights parameters.
+    pub parameters: usize,
+    /// The maximum number of tokens of a sequence, 0 if unknown.
+    pub context_length: usize,
+    /// Number of tokens in the vocabulary, 0 if unknown.
+    pub vocab_size: usize,
+    /// Number of transformer layers.
+    pub layers: usize,
+    /// Number of attention heads, 0 if unknown.
+    pub heads: usize,
+    /// Number of key and value heads, fewer than the attention heads with grouped
+    /// query attention.
+    pub kv_heads: usize,
+    /// The weights types with their tensors, from the largest.
+    pub tensor_types: Vec<TensorType>,
+    /// Memory used by the weights in bytes.
+    pub weights_memory: usize,
+    /// Memory used by the tensors computed from the weights in bytes, such as the
+    /// dequantized embeddings and the rotary embeddings tables.
+    pub derived_memory: usize,
+    /// Memory used by the kv cache in bytes.
+    pub kv_cache_memory: usize,
+}
+
+/// The tensors stored with a weights type.
+#[derive(Debug, Clone)]
+pub struct TensorType {
+    /// The type name, e.g. Q4K or F16.
+    pub name: String,
+    /// Number of tensors.
+    pub tensors: usize,
+    /// Number of parameters of the tensors.
+    pub parameters: usize,
+}
+
+impl ModelInfo {
+    /// Reads the info from the metadata and tensors of a GGUF file, the tensors data
+    /// is not read.
+    pub fn read_gguf(path: &Path) -> Result<Self> {
+        let mut file = fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(path))?;
+        Ok(Self::from_gguf(&content))
+    }
+
+    /// Gets the info from the GGUF metadata, the values missing from the metadata
+    /// are derived from the tensors when possible.
+    pub fn from_gguf(content: &gguf_file::Content) -> Self {
+        let md_u32 = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .and_then(|v| v.to_u32().ok())
+                .map(|v| v as usize)
+        };
+
+        let architecture = content
+            .metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+            .unwrap_or_default();
+        let layers = md_u32(&format!("{architecture}.block_count")).unwrap_or_else(|| {
+            content
+                .tensor_infos
+                .keys()
+                .filter_map(|name| name.strip_prefix("blk.")?.split('.').next())
+                .collect::<HashSet<_>>()
+                .len()
+        });
+        let vocab_size = content
+            .tensor_infos
+            .get("token_embd.weight")
+            .map(|info| info.shape.dims()[0])
+            .or_else(|| {
+                let tokens = content.metadata.get("tokenizer.ggml.tokens")?;
+                tokens.to_vec().ok().map(Vec::len)
+            })
+            .unwrap_or_default();
+        let heads = md_u32(&format!("{architecture}.attention.head_count")).unwrap_or_default();
+
+        let mut info = Self {
+            context_length: md_u32(&format!("{architecture}.context_length")).unwrap_or_default(),
+            vocab_size,
+            layers,
+            heads,
+            kv_heads: md_u32(&format!("{architecture}.attention.head_count_kv")).unwrap_or(heads),
+            architecture,
+            ..Default::default()
+        };
+        for tensor in content.tensor_infos.values() {
+            let elems = tensor.shape.elem_count();
+            let dtype = tensor.ggml_dtype;
+            info.add_tensor(
+                format!("{dtype:?}"),
+                elems,
+                elems / dtype.block_size() * dtype.type_size(),
+            );
+        }
+        info.sort_tensor_types();
+        info
+    }
+
+    /// Gets the info from the hyper parameters of a legacy GGML llama file, that
+    /// doesn't store the key and value heads.
+    pub fn from_ggml(content: &ggml_file::Content, gqa: usize) -> Self {
+        let hparams = &content.hparams;
+        let mut info = Self {
+            architecture: "llama".to_string(),
+            vocab_size: hparams.n_vocab as usize,
+            layers: hparams.n_layer as usize,
+            heads: hparams.n_head as usize,
+            kv_heads: hparams.n_head as usize / gqa.max(1),
+            ..Default::default()
+        };
+        for tensor in content.tensors.values() {
+            info.add_tensor(
+                format!("{:?}", tensor.dtype()),
+                tensor.shape().elem_count(),
+                tensor.storage_size_in_bytes(),
+            );
+        }
+        info.sort_tensor_types();
+        info
+    }
+
+    /// Reads the tensors info from the headers of safetensors files, the tensors are
+    /// converted to the given type when loaded. The architecture details are not in
+    /// the weights files and are left empty.
+    pub fn read_safetensors(paths: &[PathBuf], dtype: DType) -> Result<Self> {
+        let mut info = Self::default();
+        for path in paths {
+            let mut file = fs::File::open(path)?;
+            let header = SafetensorsHeader::read(&mut file)
+                .map_err(|e| anyhow!("{e} in {}", path.display()))?;
+            for (_, tensor) in header.tensors {
+                let elems = tensor.shape.iter().product::<usize>();
+                info.add_tensor(format!("{dtype:?}"), elems, elems * dtype.size_in_bytes());
+            }
+        }
+        info.sort_tensor_types();
+        Ok(info)
+    }
+
+    fn add_tensor(&mut self, type_name: String, elems: usize, bytes: usize) {
+        self.parameters += elems;
+        self.weights_memory += bytes;
+        match self.tensor_types.iter_mut().find(|t| t.name == type_name) {
+            Some(tensor_type) => {
+                tensor_type.tensors += 1;
+                tensor_type.parameters += elems;
+            }
+            None => self.tensor_types.push(TensorType {
+                name: type_name,
+                tensors: 1,
+                parameters: elems,
+            }),
+        }
+    }
+
+    fn sort_tensor_types(&mut self) {
+        self.tensor_types
+            .sort_by(|a, b| b.parameters.cmp(&a.parameters).then(a.name.cmp(&b.name)));
+    }
+}
@@ -0,0 +1,297 @@
+use anyhow::{anyhow, bail, Result};
+use candle::{
+    quantized::{
+        ggml_file::{self, HParams, VersionedMagic, Vocab},
+        gguf_file, GgmlDType,
+    },
+    DType, Device,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crate::models::transformers::weights::{QVarBuilder, VarBuilder};
+
+/// The format of a quantized weights file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeightsFormat {
+    Gguf,
+    /// The legacy format used before GGUF, in its unversioned, GGMF and GGJT variants.
+    Ggml,
+}
+
+impl WeightsFormat {
+    /// Detects the format of a weights file from its magic bytes.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut magic = [0; 4];
+        fs::File::open(path)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .map_err(|e| anyhow!("Unable to read {}: {e}", path.display()))?;
+
+        match u32::from_le_bytes(magic) {
+            0x4655_4747 => Ok(Self::Gguf),
+            0x6767_6d6c | 0x6767_6d66 | 0x6767_6a74 => Ok(Self::Ggml),
+            _ => bail!("{} is neither a GGUF nor a GGML file", path.display()),
+        }
+    }
+}
+
+/// Creates a quantized var builder for a GGUF file.
+///
+/// The tensors are read one at a time and the progress callback reports the number of
+/// tensors loaded, it returns false to cancel loading.
+///
+/// The weights are not memory mapped: candle 0.4 quantized tensors own their data, so a
+/// tensor created from a mapping is copied anyway and the mapping neither defers the
+/// reads nor lowers the resident memory.
+pub fn var_builder(
+    path: &Path,
+    device: &Device,
+    progress: &mut dyn FnMut(usize, usize) -> bool,
+) -> Result<VarBuilder> {
+    let mut reader = fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut reader).map_err(|e| e.with_path(path))?;
+    let n_tensors = content.tensor_infos.len();
+    let mut tensors = HashMap::with_capacity(n_tensors);
+    for name in content.tensor_infos.keys() {
+        tensors.insert(name.clone(), content.tensor(&mut reader, name, device)?);
+        if !progress(tensors.len(), n_tensors) {
+            bail!("Model loading cancelled");
+        }
+    }
+    Ok(VarBuilder::Quantized(QVarBuilder::from_tensors(
+        tensors, device,
+    )))
+}
+
+/// Reads a legacy GGML file like `ggml_file::Content::read`, one tensor at a time so
+/// that the progress callback reports the bytes read out of the file size, it returns
+/// false to cancel loading.
+pub fn read_ggml<R: Read + Seek>(
+    reader: &mut R,
+    device: &Device,
+    progress: &mut dyn FnMut(usize, usize) -> bool,
+) -> Result<ggml_file::Content> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let magic = match read_u32(reader)? {
+        0x6767_6d6c => VersionedMagic::GgmlUnversioned,
+        magic => match (magic, read_u32(reader)?) {
+            (0x6767_6d66, 1) => VersionedMagic::GgmfV1,
+            (0x6767_6a74, 1) => VersionedMagic::GgjtV1,
+            (0x6767_6a74, 2) => VersionedMagic::GgjtV2,
+            (0x6767_6a74, 3) => VersionedMagic::GgjtV3,
+            (magic, version) => bail!("Unsupported GGML magic {magic:08x} version {version}"),
+        },
+    };
+    // The GGJT tensors data is aligned to 32 bytes.
+    let align32 = !matches!(
+        magic,
+        VersionedMagic::GgmlUnversioned | VersionedMagic::GgmfV1
+    );
+
+    let hparams = HParams {
+        n_vocab: read_u32(reader)?,
+        n_embd: read_u32(reader)?,
+        n_mult: read_u32(reader)?,
+        n_head: read_u32(reader)?,
+        n_layer: read_u32(reader)?,
+        n_rot: read_u32(reader)?,
+        ftype: read_u32(reader)?,
+    };
+
+    let mut token_score_pairs = Vec::with_capacity(hparams.n_vocab as usize);
+    for _ in 0..hparams.n_vocab {
+        let mut word = vec![0; read_u32(reader)? as usize];
+        reader.read_exact(&mut word)?;
+        let score = f32::from_bits(read_u32(reader)?);
+        token_score_pairs.push((word, score));
+    }
+
+    let mut tensors = HashMap::new();
+    while reader.stream_position()? != file_size {
+        let n_dims = read_u32(reader)?;
+        let name_len = read_u32(reader)?;
+        let dtype = ggml_dtype(read_u32(reader)?)?;
+        // The dimensions are stored in reverse order.
+        let mut dims = (0..n_dims)
+            .map(|_| Ok(read_u32(reader)? as usize))
+            .collect::<Result<Vec<_>>>()?;
+        dims.reverse();
+        let mut name = vec![0; name_len as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8_lossy(&name).into_owned();
+
+        if align32 {
+            let pos = reader.stream_position()?;
+            reader.seek(SeekFrom::Current(((32 - pos % 32) % 32) as i64))?;
+        }
+
+        let elems = dims.iter().product::<usize>();
+        let mut data = vec![0; elems / dtype.block_size() * dtype.type_size()];
+        reader.read_exact(&mut data)?;
+        let tensor = ggml_file::qtensor_from_ggml(dtype, &data, dims, device)
+            .map_err(|e| anyhow!("Error creating tensor {name}: {e}"))?;
+        tensors.insert(name, tensor);
+
+        if !progress(reader.stream_position()? as usize, file_size as usize) {
+            bail!("Model loading cancelled");
+        }
+    }
+
+    Ok(ggml_file::Content {
+        magic,
+        hparams,
+        vocab: Vocab { token_score_pairs },
+        tensors,
+        device: device.clone(),
+    })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// The GGML type of a tensor type id.
+fn ggml_dtype(id: u32) -> Result<GgmlDType> {
+    Ok(match id {
+        0 => GgmlDType::F32,
+        1 => GgmlDType::F16,
+        2 => GgmlDType::Q4_0,
+        3 => GgmlDType::Q4_1,
+        6 => GgmlDType::Q5_0,
+        7 => GgmlDType::Q5_1,
+        8 => GgmlDType::Q8_0,
+        9 => GgmlDType::Q8_1,
+        10 => GgmlDType::Q2K,
+        11 => GgmlDType::Q3K,
+        12 => GgmlDType::Q4K,
+        13 => GgmlDType::Q5K,
+        14 => GgmlDType::Q6K,
+        15 => GgmlDType::Q8K,
+        _ => bail!("Unknown GGML tensor type {id}"),
+    })
+}
+
+/// Creates a var builder for the safetensors weights files, the tensors are converted
+/// to the given type.
+///
+/// The tensors are read and converted one at a time, so only one tensor is kept in both
+/// types, and the progress callback reports the number of bytes read out of the tensors
+/// size, it returns false to cancel loading.
+pub fn safetensors_var_builder(
+    paths: &[PathBuf],
+    dtype: DType,
+    device: &Device,
+    progress: &mut dyn FnMut(usize, usize) -> bool,
+) -> Result<VarBuilder> {
+    let files = paths
+        .iter()
+        .map(|path| {
+            let mut file = fs::File::open(path)?;
+            let header = SafetensorsHeader::read(&mut file)
+                .map_err(|e| anyhow!("{e} in {}", path.display()))?;
+            Ok((file, header))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let total = files.iter().map(|(_, h)| h.tensors_size()).sum::<usize>();
+    let mut loaded = 0;
+    let mut tensors = HashMap::new();
+    for (mut file, header) in files {
+        for (name, info) in header.tensors {
+            let (start, end) = (info.data_offsets[0], info.data_offsets[1]);
+            let mut buffer = vec![0; end - start];
+            file.seek(SeekFrom::Start((header.data_start + start) as u64))?;
+            file.read_exact(&mut buffer)?;
+
+            let tensor =
+                candle::Tensor::from_raw_buffer(&buffer, info.dtype()?, &info.shape, device)?;
+            tensors.insert(name, tensor.to_dtype(dtype)?);
+
+            loaded += buffer.len();
+            if !progress(loaded, total) {
+                bail!("Model loading cancelled");
+            }
+        }
+    }
+    Ok(VarBuilder::Full(candle_nn::VarBuilder::from_tensors(
+        tensors, dtype, device,
+    )))
+}
+
+/// The header of a safetensors file, with the tensors type, shape and position.
+pub struct SafetensorsHeader {
+    /// The tensors sorted by their position in the file.
+    pub tensors: Vec<(String, SafetensorsInfo)>,
+    /// Offset of the tensors data in the file.
+    pub data_start: usize,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SafetensorsInfo {
+    pub dtype: String,
+    pub shape: Vec<usize>,
+    /// Start and end of the tensor data from the data start.
+    pub data_offsets: [usize; 2],
+}
+
+impl SafetensorsHeader {
+    /// Reads the header at the start of the file: its size as a little endian u64
+    /// followed by a json map from the tensors names to their info.
+    pub fn read(file: &mut fs::File) -> Result<Self> {
+        let mut size = [0; 8];
+        file.read_exact(&mut size)?;
+        let size = u64::from_le_bytes(size) as usize;
+        if size > fs::File::metadata(file)?.len() as usize {
+            bail!("Invalid safetensors header size {size}");
+        }
+
+        let mut header = vec![0; size];
+        file.read_exact(&mut header)?;
+        let header: HashMap<String, serde_json::Value> = serde_json::from_slice(&header)
+            .map_err(|e| anyhow!("Invalid safetensors header: {e}"))?;
+        let mut tensors = header
+            .into_iter()
+            .filter(|(name, _)| name != "__metadata__")
+            .map(|(name, info)| Ok((name, serde_json::from_value(info)?)))
+            .collect::<Result<Vec<(String, SafetensorsInfo)>>>()?;
+        // Reads the tensors in the order they are stored.
+        tensors.sort_by_key(|(_, info)| info.data_offsets[0]);
+
+        Ok(Self {
+            tensors,
+            data_start: 8 + size,
+        })
+    }
+
+    /// The size of the tensors data in bytes.
+    fn tensors_size(&self) -> usize {
+        self.tensors
+            .iter()
+            .map(|(_, info)| info.data_offsets[1] - info.data_offsets[0])
+            .sum()
+    }
+}
+
+impl SafetensorsInfo {
+    /// The type of the stored tensor.
+    pub fn dtype(&self) -> Result<DType> {
+        Ok(match self.dtype.as_str() {
+            "U8" => DType::U8,
+            "U32" => DType::U32,
+            "I64" => DType::I64,
+            "BF16" => DType::BF16,
+            "F16" => DType::F16,
+            "F32" => DType::F32,
+            "F64" => DType::F64,
+            dtype => bail!("Unsupported safetensors type {dtype}"),
+        })
+    }
+}
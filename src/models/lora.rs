@@ -0,0 +1,93 @@
+use anyhow::{anyhow, Result};
+use candle::{DType, Device, Tensor};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const WEIGHTS_FILENAME: &str = "adapter_model.safetensors";
+const CONFIG_FILENAME: &str = "adapter_config.json";
+
+/// A LoRA adapter stored in a folder using the PEFT layout, with the A/B matrices
+/// in `adapter_model.safetensors` and rank and alpha in `adapter_config.json`.
+#[derive(Debug)]
+pub struct LoraAdapter {
+    /// The adapter folder name.
+    pub name: String,
+    /// The adapter scaling factor (alpha / rank).
+    pub scaling: f64,
+    weights: HashMap<String, (Tensor, Tensor)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdapterConfig {
+    r: usize,
+    lora_alpha: f64,
+}
+
+impl LoraAdapter {
+    /// Loads an adapter from the given folder.
+    pub fn load(path: &Path) -> Result<Self> {
+        let config = fs::read_to_string(path.join(CONFIG_FILENAME))
+            .map_err(|e| anyhow!("Unable to read adapter config: {e}"))?;
+        let config: AdapterConfig = serde_json::from_str(&config)?;
+
+        let tensors = candle::safetensors::load(path.join(WEIGHTS_FILENAME), &Device::Cpu)?;
+
+        // PEFT names look like base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight,
+        // we index the A/B pairs by module name starting at layers.
+        let mut a_weights = HashMap::new();
+        let mut b_weights = HashMap::new();
+        for (name, tensor) in tensors {
+            let Some(start) = name.find("layers.") else {
+                continue;
+            };
+
+            let tensor = tensor.to_dtype(DType::F32)?;
+            if let Some(module) = name.strip_suffix(".lora_A.weight") {
+                a_weights.insert(module[start..].to_string(), tensor);
+            } else if let Some(module) = name.strip_suffix(".lora_B.weight") {
+                b_weights.insert(module[start..].to_string(), tensor);
+            }
+        }
+
+        let weights = a_weights
+            .into_iter()
+            .filter_map(|(module, a)| b_weights.remove(&module).map(|b| (module, (a, b))))
+            .collect::<HashMap<_, _>>();
+
+        if weights.is_empty() {
+            return Err(anyhow!("No LoRA weights found in {}", path.display()));
+        }
+
+        Ok(Self {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            scaling: config.lora_alpha / config.r.max(1) as f64,
+            weights,
+        })
+    }
+
+    /// Gets the A and B matrices for a module, e.g. `layers.0.self_attn.q_proj`.
+    pub fn weights(&self, module: &str) -> Option<&(Tensor, Tensor)> {
+        self.weights.get(module)
+    }
+}
+
+/// Lists the adapters folders in the given directory.
+pub fn list_adapters(dir: &Path) -> Vec<PathBuf> {
+    let mut adapters = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.join(WEIGHTS_FILENAME).exists() && p.join(CONFIG_FILENAME).exists())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    adapters.sort();
+    adapters
+}
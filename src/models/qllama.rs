@@ -0,0 +1,181 @@
+use anyhow::{anyhow, bail, Result};
+use candle::{quantized::gguf_file, Device, Tensor};
+
+use std::{fs, sync::Arc};
+
+use crate::models::{
+    loader::{self, WeightsFormat},
+    sample_token,
+    transformers::quantized_llama,
+    CachedModel, CancelToken, ExpertStats, KvCacheType, LoraAdapter, Model, ModelInfo, ModelParams,
+    RopeScaling, TokensStream,
+};
+
+/// Quantized Llama model from a user supplied GGUF or legacy GGML file.
+pub struct QuantizedLlama {
+    model: quantized_llama::Transformer,
+    params: ModelParams,
+    tokenizer: tokenizers::Tokenizer,
+    info: ModelInfo,
+    eos_token: u32,
+}
+
+impl QuantizedLlama {
+    /// Loads the model detecting the file format, `gqa` is the number of attention
+    /// heads for each key and value head of GGML files, GGUF files have it in their
+    /// metadata.
+    pub fn new(
+        cached_model: &CachedModel,
+        params: ModelParams,
+        gqa: usize,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
+        let device = Device::Cpu;
+
+        let path = &cached_model.model_path;
+        let mut reader =
+            fs::File::open(path).map_err(|e| anyhow!("Unable to open {}: {e}", path.display()))?;
+        let (model, info, eos_token) = match WeightsFormat::detect(path)? {
+            WeightsFormat::Gguf => {
+                let content =
+                    gguf_file::Content::read(&mut reader).map_err(|e| e.with_path(path))?;
+                let info = ModelInfo::from_gguf(&content);
+                let eos_token = content
+                    .metadata
+                    .get("tokenizer.ggml.eos_token_id")
+                    .and_then(|v| v.to_u32().ok());
+                let model = quantized_llama::Transformer::from_gguf(
+                    content,
+                    &mut reader,
+                    &device,
+                    progress,
+                )?;
+                (model, info, eos_token)
+            }
+            WeightsFormat::Ggml => {
+                let content = loader::read_ggml(&mut reader, &device, progress)
+                    .map_err(|e| anyhow!("{e} in {}", path.display()))?;
+                let info = ModelInfo::from_ggml(&content, gqa);
+                let model = quantized_llama::Transformer::from_ggml(content, gqa)?;
+                (model, info, None)
+            }
+        };
+
+        let tokenizer_path = &cached_model.tokenizer_path;
+        let tokenizer = tokenizers::Tokenizer::from_file(tokenizer_path)
+            .map_err(|e| anyhow!("Unable to read {}: {e}", tokenizer_path.display()))?;
+
+        // GGML files don't store the end of sequence token, Llama tokenizers use </s>.
+        let eos_token = match eos_token.or_else(|| tokenizer.token_to_id("</s>")) {
+            Some(eos_token) => eos_token,
+            None => bail!(
+                "The model has no end of sequence token and the tokenizer {} has no </s> token",
+                tokenizer_path.display()
+            ),
+        };
+
+        Ok(Self {
+            model,
+            params,
+            tokenizer,
+            info,
+            eos_token,
+        })
+    }
+}
+
+impl Model for QuantizedLlama {
+    fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
+        self.params = *params;
+        self.model.clear_kv_cache();
+
+        let tokens = self
+            .tokenizer
+            .encode(self.chat_template(prompt), true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        self.prefill(&tokens, params.prefill_chunk_size)?;
+
+        Ok(TokensStream::new(self.eos_token, tokens.len()))
+    }
+
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, pos)?;
+        sample_token(logits, tokens, &self.params)
+    }
+
+    fn fill_kv_cache(&mut self, tokens: &[u32], pos: usize) -> Result<()> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        self.model.fill_kv_cache(&input, pos)?;
+        Ok(())
+    }
+
+    fn decode(&mut self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
+    }
+
+    fn chat_template(&self, prompt: &str) -> String {
+        // Fine-tunes use different templates, the prompt is passed as is.
+        prompt.to_string()
+    }
+
+    fn info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    fn context_length(&self) -> usize {
+        self.model.context_length()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.model.truncate_kv_cache(len)?;
+        Ok(())
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.model.set_kv_cache_type(cache_type)?;
+        Ok(())
+    }
+
+    fn kv_cache_memory(&self) -> usize {
+        self.model.kv_cache_memory()
+    }
+
+    fn derived_memory(&self) -> usize {
+        self.model.derived_memory()
+    }
+
+    fn set_rope_scaling(&mut self, scaling: Option<RopeScaling>) -> Result<()> {
+        self.model.set_rope_scaling(scaling)?;
+        Ok(())
+    }
+
+    fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.model.set_cancel_token(cancel);
+    }
+
+    fn expert_stats(&self) -> Option<ExpertStats> {
+        self.model.expert_stats()
+    }
+
+    fn supports_adapters(&self) -> bool {
+        true
+    }
+
+    fn set_adapters(&mut self, adapters: &[(Arc<LoraAdapter>, f32)]) -> Result<()> {
+        let adapters = adapters
+            .iter()
+            .map(|(adapter, scale)| (adapter.as_ref(), *scale as f64))
+            .collect::<Vec<_>>();
+        self.model.set_lora(&adapters)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+use candle::{Device, Tensor};
+
+use crate::models::{
+    loader, sample_token,
+    transformers::quantized_phi::{self, Architecture},
+    CachedModel, CancelToken, KvCacheType, Model, ModelInfo, ModelParams, TokensStream,
+};
+
+/// Quantized Phi-2 and Phi-3 models.
+pub struct QuantizedPhi {
+    model: quantized_phi::Transformer,
+    architecture: Architecture,
+    params: ModelParams,
+    tokenizer: tokenizers::Tokenizer,
+    info: ModelInfo,
+    eos_tokens: Vec<u32>,
+}
+
+impl QuantizedPhi {
+    pub fn new(
+        cfg: &quantized_phi::Config,
+        cached_model: &CachedModel,
+        params: ModelParams,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
+        let device = Device::Cpu;
+        let info = ModelInfo::read_gguf(&cached_model.model_path)?;
+        // Reading the weights takes most of the time, the layers creation is reported
+        // in the last tenth of the progress.
+        let vb = loader::var_builder(&cached_model.model_path, &device, &mut |n, total| {
+            progress(n * 900 / total.max(1), 1000)
+        })?;
+        let model = quantized_phi::Transformer::new(cfg, vb, &mut |n, total| {
+            progress(900 + n * 100 / total.max(1), 1000)
+        })?;
+        let tokenizer = tokenizers::Tokenizer::from_file(&cached_model.tokenizer_path)
+            .map_err(anyhow::Error::msg)?;
+
+        // Phi-3 ends its turn with <|end|> and the text with <|endoftext|>.
+        let eos_names: &[&str] = match cfg.architecture {
+            Architecture::Phi2 => &["<|endoftext|>"],
+            Architecture::Phi3 => &["<|end|>", "<|endoftext|>"],
+        };
+        let vocab = tokenizer.get_vocab(true);
+        let eos_tokens = eos_names
+            .iter()
+            .map(|name| {
+                vocab
+                    .get(*name)
+                    .copied()
+                    .ok_or_else(|| anyhow!("The tokenizer has no {name} token"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            model,
+            architecture: cfg.architecture,
+            params,
+            tokenizer,
+            info,
+            eos_tokens,
+        })
+    }
+}
+
+impl Model for QuantizedPhi {
+    fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
+        self.params = *params;
+        self.model.clear_kv_cache();
+
+        let template = self.chat_template(prompt);
+        let tokens = self
+            .tokenizer
+            .encode(template, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        self.prefill(&tokens, params.prefill_chunk_size)?;
+
+        Ok(TokensStream::with_eos_tokens(
+            self.eos_tokens.clone(),
+            tokens.len(),
+        ))
+    }
+
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, pos)?;
+        sample_token(logits, tokens, &self.params)
+    }
+
+    fn fill_kv_cache(&mut self, tokens: &[u32], pos: usize) -> Result<()> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        self.model.fill_kv_cache(&input, pos)?;
+        Ok(())
+    }
+
+    fn decode(&mut self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
+    }
+
+    fn chat_template(&self, prompt: &str) -> String {
+        match self.architecture {
+            // Phi-2 is not chat tuned, this is the QA format from its model card.
+            Architecture::Phi2 => format!("Instruct: {prompt}\nOutput:"),
+            Architecture::Phi3 => format!("<|user|>\n{prompt}<|end|>\n<|assistant|>\n"),
+        }
+    }
+
+    fn info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    fn context_length(&self) -> usize {
+        self.model.max_seq_len()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.model.truncate_kv_cache(len)?;
+        Ok(())
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.model.set_kv_cache_type(cache_type)?;
+        Ok(())
+    }
+
+    fn kv_cache_memory(&self) -> usize {
+        self.model.kv_cache_memory()
+    }
+
+    fn derived_memory(&self) -> usize {
+        self.model.derived_memory()
+    }
+
+    fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.model.set_cancel_token(cancel);
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Result};
+use candle::{Device, Tensor};
+
+use crate::models::{
+    loader, sample_token, transformers::quantized_qwen2, CachedModel, CancelToken, KvCacheType,
+    Model, ModelInfo, ModelParams, TokensStream,
+};
+
+/// Quantized Qwen2 model.
+pub struct QuantizedQwen2 {
+    model: quantized_qwen2::Transformer,
+    params: ModelParams,
+    tokenizer: tokenizers::Tokenizer,
+    info: ModelInfo,
+    eos_tokens: Vec<u32>,
+}
+
+impl QuantizedQwen2 {
+    pub fn new(
+        cfg: &quantized_qwen2::Config,
+        cached_model: &CachedModel,
+        params: ModelParams,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
+        let device = Device::Cpu;
+        let info = ModelInfo::read_gguf(&cached_model.model_path)?;
+        // Reading the weights takes most of the time, the layers creation is reported
+        // in the last tenth of the progress.
+        let vb = loader::var_builder(&cached_model.model_path, &device, &mut |n, total| {
+            progress(n * 900 / total.max(1), 1000)
+        })?;
+        let model = quantized_qwen2::Transformer::new(cfg, vb, &mut |n, total| {
+            progress(900 + n * 100 / total.max(1), 1000)
+        })?;
+        let tokenizer = tokenizers::Tokenizer::from_file(&cached_model.tokenizer_path)
+            .map_err(anyhow::Error::msg)?;
+
+        // Qwen2 ends its turn with <|im_end|> and the text with <|endoftext|>.
+        let vocab = tokenizer.get_vocab(true);
+        let eos_tokens = ["<|im_end|>", "<|endoftext|>"]
+            .iter()
+            .map(|name| {
+                vocab
+                    .get(*name)
+                    .copied()
+                    .ok_or_else(|| anyhow!("The tokenizer has no {name} token"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            model,
+            params,
+            tokenizer,
+            info,
+            eos_tokens,
+        })
+    }
+}
+
+impl Model for QuantizedQwen2 {
+    fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
+        self.params = *params;
+        self.model.clear_kv_cache();
+
+        let template = self.chat_template(prompt);
+        let tokens = self
+            .tokenizer
+            .encode(template, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        self.prefill(&tokens, params.prefill_chunk_size)?;
+
+        Ok(TokensStream::with_eos_tokens(
+            self.eos_tokens.clone(),
+            tokens.len(),
+        ))
+    }
+
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, pos)?;
+        sample_token(logits, tokens, &self.params)
+    }
+
+    fn fill_kv_cache(&mut self, tokens: &[u32], pos: usize) -> Result<()> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        self.model.fill_kv_cache(&input, pos)?;
+        Ok(())
+    }
+
+    fn decode(&mut self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
+    }
+
+    fn chat_template(&self, prompt: &str) -> String {
+        format!(
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+             <|im_start|>user\n{prompt}<|im_end|>\n<|im_start|>assistant\n"
+        )
+    }
+
+    fn info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    fn context_length(&self) -> usize {
+        self.model.max_seq_len()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.model.truncate_kv_cache(len)?;
+        Ok(())
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.model.set_kv_cache_type(cache_type)?;
+        Ok(())
+    }
+
+    fn kv_cache_memory(&self) -> usize {
+        self.model.kv_cache_memory()
+    }
+
+    fn derived_memory(&self) -> usize {
+        self.model.derived_memory()
+    }
+
+    fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.model.set_cancel_token(cancel);
+    }
+}
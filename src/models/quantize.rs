@@ -0,0 +1,430 @@
+use anyhow::{anyhow, bail, Result};
+use candle::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    Device,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use super::loader::WeightsFormat;
+
+/// The alignment of the tensors data in a GGUF file.
+const ALIGNMENT: usize = 32;
+
+/// Quantizes a GGUF model file to the given type writing the result to a new GGUF
+/// file with the same metadata.
+///
+/// Only GGUF input is supported, safetensors weights have to be converted to an F16
+/// or F32 GGUF file first, for example with the llama.cpp convert script.
+///
+/// One dimensional tensors such as norms and biases are kept in F32, the token
+/// embeddings are stored as F16 as they are dequantized at load time, and the output
+/// projection uses at least 6 bits as it is very sensitive to quantization. Tensors
+/// whose rows are not a multiple of the quantization block size are stored as F16.
+///
+/// The tensors are written as they are quantized so that only one tensor at a time
+/// is kept in memory.
+///
+/// The update_fn reports the number of processed tensors and the total.
+pub fn quantize_gguf(
+    input: &Path,
+    output: &Path,
+    dtype: GgmlDType,
+    update_fn: impl Fn(usize, usize),
+) -> Result<()> {
+    if !matches!(WeightsFormat::detect(input), Ok(WeightsFormat::Gguf)) {
+        bail!(
+            "{} is not a GGUF file, only GGUF weights can be quantized, \
+             convert safetensors weights to GGUF first (e.g. with llama.cpp convert.py)",
+            input.display()
+        );
+    }
+
+    let mut reader = fs::File::open(input)?;
+    let content = gguf_file::Content::read(&mut reader).map_err(|e| e.with_path(input))?;
+
+    let temp_path = output.with_extension("tmp");
+    let mut writer = io::BufWriter::new(fs::File::create(&temp_path)?);
+    if let Err(e) = write_quantized(&content, &mut reader, &mut writer, dtype, update_fn) {
+        drop(writer);
+        let _ = fs::remove_file(&temp_path);
+        bail!("Unable to write {}: {e}", output.display());
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| anyhow!("Unable to write {}: {e}", output.display()))?
+        .sync_all()?;
+    fs::rename(temp_path, output)?;
+
+    Ok(())
+}
+
+/// Writes the GGUF header with the tensor infos computed from the target types, then
+/// quantizes and writes the tensors one at a time.
+fn write_quantized(
+    content: &gguf_file::Content,
+    reader: &mut fs::File,
+    writer: &mut impl Write,
+    dtype: GgmlDType,
+    update_fn: impl Fn(usize, usize),
+) -> Result<()> {
+    let device = Device::Cpu;
+
+    // Keep the original tensors order.
+    let mut tensor_infos = content.tensor_infos.iter().collect::<Vec<_>>();
+    tensor_infos.sort_by_key(|(_, info)| info.offset);
+
+    let tensors = tensor_infos
+        .iter()
+        .map(|(name, info)| {
+            let dims = info.shape.dims();
+            (name.as_str(), dims, tensor_dtype(name, dims, dtype))
+        })
+        .collect::<Vec<_>>();
+
+    let mut metadata = content
+        .metadata
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.clone()))
+        .collect::<Vec<_>>();
+    metadata.sort_by_key(|(k, _)| *k);
+
+    // Update the file type to reflect the new quantization if known.
+    if let Some(file_type) = file_type(dtype) {
+        metadata.retain(|(k, _)| *k != "general.file_type");
+        metadata.push(("general.file_type", gguf_file::Value::U32(file_type)));
+    }
+
+    writer.write_all(&gguf_header(&metadata, &tensors)?)?;
+
+    for (idx, (name, dims, tensor_dtype)) in tensors.iter().enumerate() {
+        let tensor = content.tensor(reader, name, &device)?;
+        let tensor = if tensor.dtype() == *tensor_dtype {
+            tensor
+        } else {
+            QTensor::quantize(&tensor.dequantize(&device)?, *tensor_dtype)?
+        };
+
+        let data = tensor.data()?;
+        if data.len() != tensor_size(dims, *tensor_dtype) {
+            bail!("Unexpected size {} for tensor {name}", data.len());
+        }
+
+        writer.write_all(&data)?;
+        writer.write_all(&vec![0; padding(data.len())])?;
+        update_fn(idx + 1, tensors.len());
+    }
+
+    Ok(())
+}
+
+/// Encodes the GGUF header, the metadata and the tensor infos, padded to the tensors
+/// data alignment.
+fn gguf_header(
+    metadata: &[(&str, gguf_file::Value)],
+    tensors: &[(&str, &[usize], GgmlDType)],
+) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    buf.extend_from_slice(&0x4655_4747u32.to_le_bytes());
+    buf.extend_from_slice(&3u32.to_le_bytes());
+    buf.extend_from_slice(&(tensors.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&(metadata.len() as u64).to_le_bytes());
+
+    for (key, value) in metadata {
+        write_string(&mut buf, key);
+        buf.extend_from_slice(&value_type(value).to_le_bytes());
+        write_value(&mut buf, value)?;
+    }
+
+    let mut offset = 0;
+    for (name, dims, dtype) in tensors {
+        write_string(&mut buf, name);
+        buf.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+        for &dim in dims.iter().rev() {
+            buf.extend_from_slice(&(dim as u64).to_le_bytes());
+        }
+        buf.extend_from_slice(&ggml_type(*dtype).to_le_bytes());
+        buf.extend_from_slice(&(offset as u64).to_le_bytes());
+
+        let size = tensor_size(dims, *dtype);
+        offset += size + padding(size);
+    }
+
+    buf.resize(buf.len() + padding(buf.len()), 0);
+    Ok(buf)
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(buf: &mut Vec<u8>, value: &gguf_file::Value) -> Result<()> {
+    use gguf_file::Value;
+
+    match value {
+        Value::U8(v) => buf.push(*v),
+        Value::I8(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::U16(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::I16(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::U32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::I32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::U64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::I64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::F32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::F64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        Value::Bool(v) => buf.push(u8::from(*v)),
+        Value::String(v) => write_string(buf, v),
+        Value::Array(values) => {
+            // The type of an empty array doesn't matter.
+            let elem_type = values.first().map_or(4, value_type);
+            if values.iter().any(|v| value_type(v) != elem_type) {
+                bail!("Multiple value types in the same array");
+            }
+
+            buf.extend_from_slice(&elem_type.to_le_bytes());
+            buf.extend_from_slice(&(values.len() as u64).to_le_bytes());
+            for value in values {
+                write_value(buf, value)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The GGUF metadata value types.
+fn value_type(value: &gguf_file::Value) -> u32 {
+    use gguf_file::Value;
+
+    match value {
+        Value::U8(_) => 0,
+        Value::I8(_) => 1,
+        Value::U16(_) => 2,
+        Value::I16(_) => 3,
+        Value::U32(_) => 4,
+        Value::I32(_) => 5,
+        Value::F32(_) => 6,
+        Value::Bool(_) => 7,
+        Value::String(_) => 8,
+        Value::Array(_) => 9,
+        Value::U64(_) => 10,
+        Value::I64(_) => 11,
+        Value::F64(_) => 12,
+    }
+}
+
+// The GGML tensor types.
+fn ggml_type(dtype: GgmlDType) -> u32 {
+    match dtype {
+        GgmlDType::F32 => 0,
+        GgmlDType::F16 => 1,
+        GgmlDType::Q4_0 => 2,
+        GgmlDType::Q4_1 => 3,
+        GgmlDType::Q5_0 => 6,
+        GgmlDType::Q5_1 => 7,
+        GgmlDType::Q8_0 => 8,
+        GgmlDType::Q8_1 => 9,
+        GgmlDType::Q2K => 10,
+        GgmlDType::Q3K => 11,
+        GgmlDType::Q4K => 12,
+        GgmlDType::Q5K => 13,
+        GgmlDType::Q6K => 14,
+        GgmlDType::Q8K => 15,
+    }
+}
+
+fn tensor_size(dims: &[usize], dtype: GgmlDType) -> usize {
+    dims.iter().product::<usize>() / dtype.block_size() * dtype.type_size()
+}
+
+fn padding(size: usize) -> usize {
+    (ALIGNMENT - size % ALIGNMENT) % ALIGNMENT
+}
+
+/// Parses a quantization type name such as q4k or q8_0.
+pub fn parse_dtype(name: &str) -> Result<GgmlDType> {
+    let dtype = match name.to_lowercase().replace(['_', '-'], "").as_str() {
+        "f16" => GgmlDType::F16,
+        "q40" => GgmlDType::Q4_0,
+        "q41" => GgmlDType::Q4_1,
+        "q50" => GgmlDType::Q5_0,
+        "q51" => GgmlDType::Q5_1,
+        "q80" => GgmlDType::Q8_0,
+        "q2k" => GgmlDType::Q2K,
+        "q3k" => GgmlDType::Q3K,
+        "q4k" => GgmlDType::Q4K,
+        "q5k" => GgmlDType::Q5K,
+        "q6k" => GgmlDType::Q6K,
+        _ => bail!(
+            "Unknown quantization type {name}, expected one of: \
+             f16, q4_0, q4_1, q5_0, q5_1, q8_0, q2k, q3k, q4k, q5k, q6k"
+        ),
+    };
+    Ok(dtype)
+}
+
+fn tensor_dtype(name: &str, dims: &[usize], dtype: GgmlDType) -> GgmlDType {
+    let row_size = dims.last().copied().unwrap_or_default();
+    let dtype = if dims.len() < 2 {
+        GgmlDType::F32
+    } else if name.starts_with("token_embd") {
+        GgmlDType::F16
+    } else if name.starts_with("output.") && bits_per_weight(dtype) < 6.0 {
+        GgmlDType::Q6K
+    } else {
+        dtype
+    };
+
+    if row_size % dtype.block_size() == 0 {
+        dtype
+    } else if dtype == GgmlDType::F32 {
+        GgmlDType::F32
+    } else {
+        GgmlDType::F16
+    }
+}
+
+fn bits_per_weight(dtype: GgmlDType) -> f32 {
+    (dtype.type_size() * 8) as f32 / dtype.block_size() as f32
+}
+
+// The llama.cpp file types for the given quantization.
+fn file_type(dtype: GgmlDType) -> Option<u32> {
+    match dtype {
+        GgmlDType::F32 => Some(0),
+        GgmlDType::F16 => Some(1),
+        GgmlDType::Q4_0 => Some(2),
+        GgmlDType::Q4_1 => Some(3),
+        GgmlDType::Q8_0 => Some(7),
+        GgmlDType::Q5_0 => Some(8),
+        GgmlDType::Q5_1 => Some(9),
+        GgmlDType::Q2K => Some(10),
+        GgmlDType::Q3K => Some(12),
+        GgmlDType::Q4K => Some(15),
+        GgmlDType::Q5K => Some(17),
+        GgmlDType::Q6K => Some(18),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Tensor;
+
+    const N_EMBD: usize = 256;
+    const N_VOCAB: usize = 64;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("coze-{}-{name}", std::process::id()))
+    }
+
+    fn f32_tensor(dims: &[usize]) -> Result<QTensor> {
+        let len = dims.iter().product::<usize>();
+        let data = (0..len)
+            .map(|i| ((i * 37) % 101) as f32 / 50. - 1.)
+            .collect::<Vec<_>>();
+        let tensor = Tensor::from_vec(data, dims, &Device::Cpu)?;
+        Ok(QTensor::quantize(&tensor, GgmlDType::F32)?)
+    }
+
+    /// Writes a small F32 model with the tensors names used by llama.cpp.
+    fn write_model(path: &Path) -> Result<()> {
+        let tensors = [
+            ("token_embd.weight", vec![N_VOCAB, N_EMBD]),
+            ("blk.0.attn_norm.weight", vec![N_EMBD]),
+            ("blk.0.attn_q.weight", vec![N_EMBD, N_EMBD]),
+            ("blk.0.ffn_down.weight", vec![N_EMBD, 96]),
+            ("output_norm.weight", vec![N_EMBD]),
+            ("output.weight", vec![N_VOCAB, N_EMBD]),
+        ];
+        let tensors = tensors
+            .iter()
+            .map(|(name, dims)| Ok((*name, f32_tensor(dims)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let tensors = tensors.iter().map(|(n, t)| (*n, t)).collect::<Vec<_>>();
+
+        use gguf_file::Value;
+        let arch = Value::String("llama".into());
+        let file_type = Value::U32(0);
+        let tokens = Value::Array(vec![Value::String("a".into()), Value::String("b".into())]);
+        let metadata = [
+            ("general.architecture", &arch),
+            ("general.file_type", &file_type),
+            ("tokenizer.ggml.tokens", &tokens),
+        ];
+
+        let mut file = fs::File::create(path)?;
+        gguf_file::write(&mut file, &metadata, &tensors)?;
+        Ok(())
+    }
+
+    #[test]
+    fn quantize_round_trip() -> Result<()> {
+        let input = temp_path("f32.gguf");
+        let output = temp_path("q4k.gguf");
+        write_model(&input)?;
+        quantize_gguf(&input, &output, GgmlDType::Q4K, |_, _| {})?;
+
+        let mut reader = fs::File::open(&output)?;
+        let content = gguf_file::Content::read(&mut reader)?;
+
+        let expected = [
+            ("token_embd.weight", GgmlDType::F16),
+            ("blk.0.attn_norm.weight", GgmlDType::F32),
+            ("blk.0.attn_q.weight", GgmlDType::Q4K),
+            ("blk.0.ffn_down.weight", GgmlDType::F16),
+            ("output_norm.weight", GgmlDType::F32),
+            ("output.weight", GgmlDType::Q6K),
+        ];
+        assert_eq!(content.tensor_infos.len(), expected.len());
+        for (name, dtype) in expected {
+            assert_eq!(content.tensor_infos[name].ggml_dtype, dtype, "{name}");
+
+            // The quantized tensors are close to the original ones.
+            let original =
+                f32_tensor(content.tensor_infos[name].shape.dims())?.dequantize(&Device::Cpu)?;
+            let tensor = content.tensor(&mut reader, name, &Device::Cpu)?;
+            let tensor = tensor.dequantize(&Device::Cpu)?;
+            assert_eq!(tensor.dims(), original.dims(), "{name}");
+            let diff = (tensor - original)?.abs()?.flatten_all()?.max(0)?;
+            assert!(diff.to_scalar::<f32>()? < 0.1, "{name}");
+        }
+
+        let file_type = content.metadata["general.file_type"].to_u32()?;
+        assert_eq!(file_type, 15);
+        let tokens = content.metadata["tokenizer.ggml.tokens"].to_vec()?;
+        assert_eq!(tokens.len(), 2);
+
+        // The models load the file with the GGUF var builder.
+        let vb = super::super::loader::var_builder(&output, &Device::Cpu, &mut |_, _| true)?;
+        let q = vb.get((N_EMBD, N_EMBD), "blk.0.attn_q.weight")?;
+        assert_eq!(q.dims(), [N_EMBD, N_EMBD]);
+
+        fs::remove_file(input)?;
+        fs::remove_file(output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn quantize_rejects_safetensors() -> Result<()> {
+        let input = temp_path("model.safetensors");
+        let output = temp_path("model.gguf");
+        let header = b"{}";
+        let mut data = (header.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(header);
+        fs::write(&input, data)?;
+
+        let err = quantize_gguf(&input, &output, GgmlDType::Q4K, |_, _| {}).unwrap_err();
+        assert!(err.to_string().contains("only GGUF weights"));
+        assert!(!output.exists());
+
+        fs::remove_file(input)?;
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
 use anyhow::Result;
 use candle::{quantized::gguf_file, Device, Tensor};
 
+use std::{fs, sync::Arc};
+
 use crate::models::{
-    sample_token, transformers::quantized_llama, Model, ModelId, ModelParams, ModelsCache,
-    TokensStream,
+    qmistral, sample_token, transformers::quantized_llama, CachedModel, CancelToken, KvCacheType,
+    LoraAdapter, Model, ModelInfo, ModelParams, RopeScaling, TokensStream,
 };
 
 /// Quantized Zephyr model.
@@ -11,22 +13,28 @@ pub struct QuantizedZephyr {
     model: quantized_llama::Transformer,
     params: ModelParams,
     tokenizer: tokenizers::Tokenizer,
+    info: ModelInfo,
     eos_token: u32,
 }
 
 impl QuantizedZephyr {
-    pub fn new(params: ModelParams) -> Result<Self> {
-        let cache = ModelsCache::new()?;
-        let cached_model = cache.cached_model(ModelId::Zephyr7bBeta);
-
+    pub fn new(
+        cached_model: &CachedModel,
+        params: ModelParams,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
         let device = Device::Cpu;
 
-        let mut file = std::fs::File::open(&cached_model.model_path)?;
-        let gguf_content = gguf_file::Content::read(&mut file)
-            .map_err(|e| e.with_path(cached_model.model_path))?;
-        let model = quantized_llama::Transformer::from_gguf(gguf_content, &mut file, &device)?;
+        let mut reader = fs::File::open(&cached_model.model_path)?;
+        let gguf_content = gguf_file::Content::read(&mut reader)
+            .map_err(|e| e.with_path(&cached_model.model_path))?;
+        let info = ModelInfo::from_gguf(&gguf_content);
+        // Zephyr is fine tuned from Mistral 7B v0.1.
+        let mut model =
+            quantized_llama::Transformer::from_gguf(gguf_content, &mut reader, &device, progress)?;
+        model.set_sliding_window(Some(qmistral::SLIDING_WINDOW));
 
-        let tokenizer = tokenizers::Tokenizer::from_file(cached_model.tokenizer_path)
+        let tokenizer = tokenizers::Tokenizer::from_file(&cached_model.tokenizer_path)
             .map_err(anyhow::Error::msg)?;
 
         let eos_token = *tokenizer.get_vocab(true).get("</s>").unwrap();
@@ -35,6 +43,7 @@ impl QuantizedZephyr {
             model,
             params,
             tokenizer,
+            info,
             eos_token,
         })
     }
@@ -45,14 +54,14 @@ impl Model for QuantizedZephyr {
         self.params = *params;
         self.model.clear_kv_cache();
 
-        let template = format!("<|system|>\n</s>\n<|user|>\n{prompt}</s>\n<|assistant|> ");
+        let template = self.chat_template(prompt);
         let tokens = self
             .tokenizer
             .encode(template, true)
             .map_err(anyhow::Error::msg)?
             .get_ids()
             .to_vec();
-        self.forward(&tokens, 0)?;
+        self.prefill(&tokens, params.prefill_chunk_size)?;
 
         Ok(TokensStream::new(self.eos_token, tokens.len()))
     }
@@ -63,9 +72,71 @@ impl Model for QuantizedZephyr {
         sample_token(logits, tokens, &self.params)
     }
 
+    fn fill_kv_cache(&mut self, tokens: &[u32], pos: usize) -> Result<()> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        self.model.fill_kv_cache(&input, pos)?;
+        Ok(())
+    }
+
     fn decode(&mut self, tokens: &[u32]) -> Result<String> {
         self.tokenizer
             .decode(tokens, true)
             .map_err(anyhow::Error::msg)
     }
+
+    fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
+    }
+
+    fn chat_template(&self, prompt: &str) -> String {
+        format!("<|system|>\n</s>\n<|user|>\n{prompt}</s>\n<|assistant|> ")
+    }
+
+    fn info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    fn context_length(&self) -> usize {
+        self.model.context_length()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.model.truncate_kv_cache(len)?;
+        Ok(())
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.model.set_kv_cache_type(cache_type)?;
+        Ok(())
+    }
+
+    fn kv_cache_memory(&self) -> usize {
+        self.model.kv_cache_memory()
+    }
+
+    fn derived_memory(&self) -> usize {
+        self.model.derived_memory()
+    }
+
+    fn set_rope_scaling(&mut self, scaling: Option<RopeScaling>) -> Result<()> {
+        self.model.set_rope_scaling(scaling)?;
+        Ok(())
+    }
+
+    fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.model.set_cancel_token(cancel);
+    }
+
+    fn supports_adapters(&self) -> bool {
+        true
+    }
+
+    fn set_adapters(&mut self, adapters: &[(Arc<LoraAdapter>, f32)]) -> Result<()> {
+        let adapters = adapters
+            .iter()
+            .map(|(adapter, scale)| (adapter.as_ref(), *scale as f64))
+            .collect::<Vec<_>>();
+        self.model.set_lora(&adapters)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,141 @@
+use anyhow::Result;
+use candle::{Device, Tensor};
+
+use crate::models::{
+    loader, sample_token, transformers::quantized_stable_lm, CachedModel, CancelToken, KvCacheType,
+    Model, ModelInfo, ModelParams, TokensStream,
+};
+
+/// StableLM model, loaded from quantized GGUF weights or from full precision
+/// safetensors weights.
+pub struct StableLM {
+    model: quantized_stable_lm::Transformer,
+    params: ModelParams,
+    tokenizer: tokenizers::Tokenizer,
+    info: ModelInfo,
+    eos_token: u32,
+}
+
+impl StableLM {
+    pub fn new(
+        cached_model: &CachedModel,
+        params: ModelParams,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
+        let device = Device::Cpu;
+        // Reading the weights takes most of the time, the layers creation is reported
+        // in the last tenth of the progress.
+        let mut read_progress = |n, total: usize| progress(n * 900 / total.max(1), 1000);
+        let (cfg, vb, info) = if cached_model.variant.is_safetensors() {
+            let cfg = serde_json::from_reader(std::fs::File::open(&cached_model.config_path)?)?;
+            let paths = cached_model.weights_paths()?;
+            let dtype = cached_model.variant.dtype();
+            let info = ModelInfo::read_safetensors(&paths, dtype)?;
+            let vb = loader::safetensors_var_builder(&paths, dtype, &device, &mut read_progress)?;
+            (cfg, vb, info)
+        } else {
+            let info = ModelInfo::read_gguf(&cached_model.model_path)?;
+            let vb = loader::var_builder(&cached_model.model_path, &device, &mut read_progress)?;
+            let cfg = quantized_stable_lm::Config::stablelm_2_1_6b();
+            (cfg, vb, info)
+        };
+        // Neither weights file has the architecture details, they are in the config.
+        let info = ModelInfo {
+            architecture: "stablelm".to_string(),
+            context_length: cfg.max_position_embeddings,
+            vocab_size: cfg.vocab_size,
+            layers: cfg.num_hidden_layers,
+            heads: cfg.num_attention_heads,
+            kv_heads: cfg.num_key_value_heads,
+            ..info
+        };
+        let model = quantized_stable_lm::Transformer::new(&cfg, vb, &mut |n, total| {
+            progress(900 + n * 100 / total.max(1), 1000)
+        })?;
+        let tokenizer = tokenizers::Tokenizer::from_file(&cached_model.tokenizer_path)
+            .map_err(anyhow::Error::msg)?;
+        let eos_token = *tokenizer.get_vocab(true).get("<|endoftext|>").unwrap();
+
+        Ok(Self {
+            model,
+            params,
+            tokenizer,
+            info,
+            eos_token,
+        })
+    }
+}
+
+impl Model for StableLM {
+    fn prompt(&mut self, prompt: &str, params: &ModelParams) -> Result<TokensStream> {
+        self.params = *params;
+        self.model.clear_kv_cache();
+
+        let template = self.chat_template(prompt);
+        let tokens = self
+            .tokenizer
+            .encode(template, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        self.prefill(&tokens, params.prefill_chunk_size)?;
+
+        Ok(TokensStream::new(self.eos_token, tokens.len()))
+    }
+
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<u32> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, pos)?;
+        sample_token(logits, tokens, &self.params)
+    }
+
+    fn fill_kv_cache(&mut self, tokens: &[u32], pos: usize) -> Result<()> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        self.model.fill_kv_cache(&input, pos)?;
+        Ok(())
+    }
+
+    fn decode(&mut self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, false)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
+    }
+
+    fn chat_template(&self, prompt: &str) -> String {
+        format!("<|user|>\n{prompt}<|endoftext|>\n")
+    }
+
+    fn info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    fn context_length(&self) -> usize {
+        self.model.max_seq_len()
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.model.truncate_kv_cache(len)?;
+        Ok(())
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.model.set_kv_cache_type(cache_type)?;
+        Ok(())
+    }
+
+    fn kv_cache_memory(&self) -> usize {
+        self.model.kv_cache_memory()
+    }
+
+    fn derived_memory(&self) -> usize {
+        self.model.derived_memory()
+    }
+
+    fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.model.set_cancel_token(cancel);
+    }
+}
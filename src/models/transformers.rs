@@ -1,2 +1,8 @@
+pub mod kv_cache;
+pub mod layers;
+pub mod quantized_gemma;
 pub mod quantized_llama;
+pub mod quantized_phi;
+pub mod quantized_qwen2;
 pub mod quantized_stable_lm;
+pub mod weights;
@@ -0,0 +1,545 @@
+// A key value cache for the attention layers that is allocated once for up to 4096
+// tokens and written in place, longer contexts add blocks as they grow.
+//
+// Candle 0.4 tensors cannot be updated in place, so the cache is split in fixed size
+// blocks of variables: a new token rewrites the block that contains it and the
+// attention is computed block by block, so the cache is never concatenated or copied
+// as it grows.
+//
+// The blocks can be stored as F16, or quantized to Q8_0 once they are full, to reduce
+// the cache memory at long context. F16 blocks are converted back to F32 when read,
+// Q8 blocks are dequantized a row at a time while they are multiplied, so a new token
+// doesn't dequantize the whole context. The Q8 keys are quantized along the head
+// dimension and the values along the tokens.
+//
+// With a sliding window the cache keeps rolling: the blocks that only contain tokens
+// out of the window are dropped and reused for the new tokens, so the cache holds at
+// most the window plus a block and the chunk being processed.
+use candle::{
+    quantized::{k_quants::BlockQ8_0, GgmlDType, GgmlType},
+    DType, Result, Shape, Tensor, Var,
+};
+use rayon::prelude::*;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::models::KvCacheType;
+
+/// Number of tokens stored in a cache block.
+const BLOCK_SIZE: usize = 128;
+
+/// Maximum number of tokens allocated with the first tokens.
+const PREALLOCATED_LEN: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct KvCache {
+    cache_type: KvCacheType,
+    /// Blocks written in place, the Q8 cache only has the block being filled.
+    blocks: Vec<(Var, Var)>,
+    /// The Q8 cache full blocks.
+    quantized: Vec<Q8Block>,
+    /// Position of the first cached token, the tokens before it have been dropped.
+    start: usize,
+    len: usize,
+    capacity: usize,
+    sliding_window: Option<usize>,
+}
+
+impl KvCache {
+    /// Creates a cache for up to `capacity` positions, the blocks are allocated when
+    /// the first tokens are added as their shape depends on the layer.
+    ///
+    /// With a sliding window each token only attends to the `sliding_window` tokens up
+    /// to itself and the older tokens are dropped.
+    pub fn new(capacity: usize, sliding_window: Option<usize>, cache_type: KvCacheType) -> Self {
+        Self {
+            cache_type,
+            blocks: vec![],
+            quantized: vec![],
+            start: 0,
+            len: 0,
+            capacity,
+            sliding_window,
+        }
+    }
+
+    /// Checks that the cache type can store heads of the given size, the Q8 blocks are
+    /// quantized along the head dimension.
+    pub fn check_type(cache_type: KvCacheType, head_dim: usize) -> Result<()> {
+        let block_size = GgmlDType::Q8_0.block_size();
+        if cache_type == KvCacheType::Q8 && !head_dim.is_multiple_of(block_size) {
+            candle::bail!(
+                "The Q8 kv cache needs a head size multiple of {block_size}, the model \
+                heads have size {head_dim}"
+            );
+        }
+        Ok(())
+    }
+
+    /// The positions of the cached tokens.
+    pub fn positions(&self) -> Range<usize> {
+        self.start..self.start + self.len
+    }
+
+    /// Makes room for `seq_len` new tokens, dropping the blocks that fall out of the
+    /// sliding window of the first new token.
+    pub fn reserve(&mut self, seq_len: usize) -> Result<()> {
+        let end = self.start + self.len;
+        if end + seq_len > self.capacity {
+            candle::bail!(
+                "The context length of {} tokens has been exceeded",
+                self.capacity
+            );
+        }
+
+        if let Some(window) = self.sliding_window {
+            let window_start = (end + 1).saturating_sub(window);
+            let n_blocks = window_start.saturating_sub(self.start) / BLOCK_SIZE;
+            if n_blocks > 0 {
+                // The dropped blocks are full so the Q8 cache has quantized them.
+                match self.cache_type {
+                    KvCacheType::Q8 => {
+                        self.quantized.drain(..n_blocks);
+                    }
+                    KvCacheType::F32 | KvCacheType::F16 => self.blocks.rotate_left(n_blocks),
+                }
+                self.start += n_blocks * BLOCK_SIZE;
+                self.len -= n_blocks * BLOCK_SIZE;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends keys and values with shape (batch, kv_heads, seq_len, head_dim).
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<()> {
+        let (b_sz, n_kv_head, seq_len, head_dim) = k.dims4()?;
+        self.reserve(seq_len)?;
+
+        // The first blocks are allocated at once and more blocks are added when the
+        // tokens don't fit in them.
+        let (n_blocks, dtype) = match self.cache_type {
+            KvCacheType::F32 => (self.dense_blocks(seq_len), DType::F32),
+            KvCacheType::F16 => (self.dense_blocks(seq_len), DType::F16),
+            KvCacheType::Q8 => (1, DType::F32),
+        };
+        let shape = (b_sz, n_kv_head, BLOCK_SIZE, head_dim);
+        while self.blocks.len() < n_blocks {
+            let k_block = Var::zeros(shape, dtype, k.device())?;
+            let v_block = Var::zeros(shape, dtype, v.device())?;
+            self.blocks.push((k_block, v_block));
+        }
+
+        let dtype = self.blocks[0].0.dtype();
+        let (k, v) = (k.to_dtype(dtype)?, v.to_dtype(dtype)?);
+
+        // Write the new tokens in the blocks that contain their positions.
+        let mut written = 0;
+        while written < seq_len {
+            let pos = self.len + written;
+            let (block_idx, offset) = (pos / BLOCK_SIZE, pos % BLOCK_SIZE);
+            let n = (BLOCK_SIZE - offset).min(seq_len - written);
+            let ranges = [0..b_sz, 0..n_kv_head, offset..offset + n, 0..head_dim];
+            let (k_block, v_block) = self.dense_block(block_idx);
+            k_block.set(&k_block.slice_assign(&ranges, &k.narrow(2, written, n)?)?)?;
+            v_block.set(&v_block.slice_assign(&ranges, &v.narrow(2, written, n)?)?)?;
+            written += n;
+
+            if self.cache_type == KvCacheType::Q8 && offset + n == BLOCK_SIZE {
+                self.quantized.push(Q8Block::quantize(k_block, v_block)?);
+            }
+        }
+
+        self.len += seq_len;
+        Ok(())
+    }
+
+    /// Computes the query and cached keys dot products, the query has shape
+    /// (batch, n_head, seq_len, head_dim) where n_head is a multiple of the kv heads,
+    /// returns a (batch, n_head, seq_len, len) tensor.
+    pub fn scores(&self, q: &Tensor) -> Result<Tensor> {
+        let (b_sz, n_head, seq_len, head_dim) = q.dims4()?;
+        let n_kv_head = self.n_kv_head()?;
+
+        // Queries that share a kv head are stacked to avoid repeating the keys.
+        let rows = n_head / n_kv_head * seq_len;
+        let q = q.contiguous()?.reshape((b_sz, n_kv_head, rows, head_dim))?;
+
+        // The Q8 full blocks come first, they are multiplied at once.
+        let n_quantized = self.quantized.len();
+        let mut scores = vec![];
+        if n_quantized > 0 {
+            let keys = self
+                .quantized
+                .iter()
+                .map(|block| block.keys.as_slice())
+                .collect::<Vec<_>>();
+            let q = q
+                .unsqueeze(2)?
+                .broadcast_as((b_sz, n_kv_head, n_quantized, rows, head_dim))?;
+            let quantized_scores = q8_matmul(&q, &keys, BLOCK_SIZE)?
+                .permute((0, 1, 3, 2, 4))?
+                .reshape((b_sz, n_kv_head, rows, n_quantized * BLOCK_SIZE))?;
+            scores.push(quantized_scores);
+        }
+        for idx in n_quantized..self.n_blocks() {
+            let (k, _) = self.used_dense_block(idx, q.dtype())?;
+            scores.push(q.matmul(&k.t()?)?);
+        }
+        Tensor::cat(&scores, 3)?.reshape((b_sz, n_head, seq_len, self.len))
+    }
+
+    /// Computes the attention weighted sum of the cached values, the weights have shape
+    /// (batch, n_head, seq_len, len), returns a (batch, n_head, seq_len, head_dim)
+    /// tensor.
+    pub fn weighted_values(&self, att: &Tensor) -> Result<Tensor> {
+        let (b_sz, n_head, seq_len, _) = att.dims4()?;
+        let n_kv_head = self.n_kv_head()?;
+
+        let rows = n_head / n_kv_head * seq_len;
+        let att = att
+            .contiguous()?
+            .reshape((b_sz, n_kv_head, rows, self.len))?;
+        let head_dim = self.blocks[0].0.dim(3)?;
+
+        // The Q8 full blocks come first, they are multiplied at once.
+        let n_quantized = self.quantized.len();
+        let mut ys: Option<Tensor> = None;
+        if n_quantized > 0 {
+            let values = self
+                .quantized
+                .iter()
+                .map(|block| block.values.as_slice())
+                .collect::<Vec<_>>();
+            let att = att
+                .narrow(3, 0, n_quantized * BLOCK_SIZE)?
+                .reshape((b_sz, n_kv_head, rows, n_quantized, BLOCK_SIZE))?
+                .permute((0, 1, 3, 2, 4))?;
+            ys = Some(q8_matmul(&att, &values, head_dim)?.sum(2)?);
+        }
+        for idx in n_quantized..self.n_blocks() {
+            let (_, v) = self.used_dense_block(idx, att.dtype())?;
+            let att = att.narrow(3, idx * BLOCK_SIZE, v.dim(2)?)?.contiguous()?;
+            let y = att.matmul(&v)?;
+            ys = Some(match ys {
+                Some(ys) => (ys + y)?,
+                None => y,
+            });
+        }
+
+        let Some(ys) = ys else {
+            candle::bail!("The kv cache is empty");
+        };
+        ys.reshape((b_sz, n_head, seq_len, head_dim))
+    }
+
+    /// Truncates the cache to the first `len` tokens, this rolls back the cache to an
+    /// earlier position without releasing memory.
+    ///
+    /// Fails if the window of the next token has been dropped by the sliding window.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        let window_start = match self.sliding_window {
+            Some(window) => (len + 1).saturating_sub(window),
+            None => 0,
+        };
+        if window_start < self.start {
+            candle::bail!(
+                "The kv cache has dropped the tokens before position {}",
+                self.start
+            );
+        }
+        self.len = self.len.min(len - self.start);
+
+        // A quantized block that is no longer full becomes the block being filled.
+        let full_blocks = self.len / BLOCK_SIZE;
+        if self.quantized.len() > full_blocks {
+            if !self.len.is_multiple_of(BLOCK_SIZE) {
+                let (k_block, v_block) = &self.blocks[0];
+                let (k, v) = self.quantized[full_blocks].dequantize(k_block.as_tensor())?;
+                k_block.set(&k)?;
+                v_block.set(&v)?;
+            }
+            self.quantized.truncate(full_blocks);
+        }
+        Ok(())
+    }
+
+    /// Removes all tokens from the cache.
+    pub fn reset(&mut self) {
+        self.start = 0;
+        self.len = 0;
+        self.quantized.clear();
+    }
+
+    /// The memory used by the cache in bytes.
+    pub fn memory(&self) -> usize {
+        let dense = self
+            .blocks
+            .iter()
+            .map(|(k, v)| (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes())
+            .sum::<usize>();
+        let quantized = self.quantized.iter().map(Q8Block::memory).sum::<usize>();
+        dense + quantized
+    }
+
+    fn n_kv_head(&self) -> Result<usize> {
+        match self.blocks.first() {
+            Some((k, _)) => k.dim(1),
+            None => candle::bail!("The kv cache is empty"),
+        }
+    }
+
+    // Returns the number of dense blocks needed to add `seq_len` tokens.
+    fn dense_blocks(&self, seq_len: usize) -> usize {
+        let preallocated = self
+            .capacity
+            .min(self.sliding_window.unwrap_or(self.capacity))
+            .min(PREALLOCATED_LEN);
+        preallocated.max(self.len + seq_len).div_ceil(BLOCK_SIZE)
+    }
+
+    // Returns the block written in place that contains the given block index.
+    fn dense_block(&self, block_idx: usize) -> &(Var, Var) {
+        match self.cache_type {
+            KvCacheType::Q8 => &self.blocks[0],
+            KvCacheType::F32 | KvCacheType::F16 => &self.blocks[block_idx],
+        }
+    }
+
+    // Returns the number of blocks that contain cached tokens.
+    fn n_blocks(&self) -> usize {
+        self.len.div_ceil(BLOCK_SIZE)
+    }
+
+    // Returns the keys and values of the cached tokens in a dense block.
+    fn used_dense_block(&self, idx: usize, dtype: DType) -> Result<(Tensor, Tensor)> {
+        let (k, v) = self.dense_block(idx);
+        let block_len = (self.len - idx * BLOCK_SIZE).min(BLOCK_SIZE);
+        let k = k.narrow(2, 0, block_len)?.to_dtype(dtype)?;
+        let v = v.narrow(2, 0, block_len)?.to_dtype(dtype)?;
+        Ok((k, v))
+    }
+}
+
+/// A full block of the Q8 cache.
+#[derive(Debug, Clone)]
+struct Q8Block {
+    /// Rows of head size keys for each batch, kv head and token.
+    keys: Arc<Vec<BlockQ8_0>>,
+    /// Rows of `BLOCK_SIZE` values for each batch, kv head and head dimension.
+    values: Arc<Vec<BlockQ8_0>>,
+}
+
+impl Q8Block {
+    /// Quantizes keys and values blocks with shape (batch, kv_heads, BLOCK_SIZE,
+    /// head_dim).
+    fn quantize(k: &Tensor, v: &Tensor) -> Result<Self> {
+        Ok(Self {
+            keys: Arc::new(quantize_rows(k)?),
+            values: Arc::new(quantize_rows(&v.transpose(2, 3)?)?),
+        })
+    }
+
+    /// Dequantizes the keys and values to blocks with the shape and type of `block`.
+    fn dequantize(&self, block: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (b_sz, n_kv_head, block_len, head_dim) = block.dims4()?;
+        let k = dequantize_rows(&self.keys, block.shape().clone(), block)?;
+        let v = dequantize_rows(&self.values, (b_sz, n_kv_head, head_dim, block_len), block)?
+            .transpose(2, 3)?
+            .contiguous()?;
+        Ok((k, v))
+    }
+
+    /// The memory used by the block in bytes.
+    fn memory(&self) -> usize {
+        (self.keys.len() + self.values.len()) * std::mem::size_of::<BlockQ8_0>()
+    }
+}
+
+/// Quantizes the rows of the last dimension of `xs`.
+fn quantize_rows(xs: &Tensor) -> Result<Vec<BlockQ8_0>> {
+    let xs = xs
+        .to_dtype(DType::F32)?
+        .contiguous()?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let mut ys = vec![BlockQ8_0::zeros(); xs.len() / BlockQ8_0::BLCK_SIZE];
+    BlockQ8_0::from_float(&xs, &mut ys)?;
+    Ok(ys)
+}
+
+/// Dequantizes rows to a tensor with the given shape and the type and device of `like`.
+fn dequantize_rows<S: Into<Shape>>(xs: &[BlockQ8_0], shape: S, like: &Tensor) -> Result<Tensor> {
+    let mut ys = vec![0f32; xs.len() * BlockQ8_0::BLCK_SIZE];
+    BlockQ8_0::to_float(xs, &mut ys)?;
+    Tensor::from_vec(ys, shape, like.device())?.to_dtype(like.dtype())
+}
+
+/// Multiplies `lhs` with shape (batch, kv_heads, n_blocks, m, k) by the quantized
+/// rows of each block, that has n rows of k elements for each batch and kv head,
+/// returns a (batch, kv_heads, n_blocks, m, n) tensor in the type of `lhs`.
+///
+/// The quantized rows are dequantized one at a time, so the blocks are never
+/// dequantized as a whole.
+fn q8_matmul(lhs: &Tensor, blocks: &[&[BlockQ8_0]], n: usize) -> Result<Tensor> {
+    let (b_sz, n_kv_head, n_blocks, m, k) = lhs.dims5()?;
+    let lhs_data = lhs
+        .to_dtype(DType::F32)?
+        .contiguous()?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let row_len = k / BlockQ8_0::BLCK_SIZE;
+    let mut dst = vec![0f32; b_sz * n_kv_head * n_blocks * m * n];
+    dst.par_chunks_mut(m * n)
+        .enumerate()
+        .try_for_each(|(idx, dst)| {
+            let (head, block) = (idx / n_blocks, idx % n_blocks);
+            let lhs = &lhs_data[idx * m * k..(idx + 1) * m * k];
+            let rhs_t = &blocks[block][head * n * row_len..(head + 1) * n * row_len];
+            let mut row = vec![0f32; k];
+            for (col, rhs_row) in rhs_t.chunks(row_len).enumerate() {
+                BlockQ8_0::to_float(rhs_row, &mut row)?;
+                for (lhs_row, dst_row) in lhs.chunks(k).zip(dst.chunks_mut(n)) {
+                    dst_row[col] = dot(lhs_row, &row);
+                }
+            }
+            Ok::<_, candle::Error>(())
+        })?;
+    Tensor::from_vec(dst, (b_sz, n_kv_head, n_blocks, m, n), lhs.device())?.to_dtype(lhs.dtype())
+}
+
+/// Dot product with independent partial sums so that it can be vectorized.
+fn dot(xs: &[f32], ys: &[f32]) -> f32 {
+    const LANES: usize = 8;
+    let mut sums = [0f32; LANES];
+    for (xs, ys) in xs.chunks_exact(LANES).zip(ys.chunks_exact(LANES)) {
+        for lane in 0..LANES {
+            sums[lane] += xs[lane] * ys[lane];
+        }
+    }
+    let tail = xs.len() / LANES * LANES;
+    let tail = xs[tail..]
+        .iter()
+        .zip(&ys[tail..])
+        .map(|(x, y)| x * y)
+        .sum::<f32>();
+    sums.iter().sum::<f32>() + tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::{Device, IndexOp};
+
+    const N_HEAD: usize = 4;
+    const N_KV_HEAD: usize = 2;
+    const HEAD_DIM: usize = 32;
+
+    const CACHE_TYPES: [KvCacheType; 3] = [KvCacheType::F32, KvCacheType::F16, KvCacheType::Q8];
+
+    /// Keys or values that F16 and Q8_0 store exactly, along the heads and along the
+    /// tokens: -127, 0 and 127, so that the Q8_0 blocks have a scale of 1 or are all
+    /// zeros.
+    fn exact_tensor(n_head: usize, seq_len: usize, seed: usize) -> Result<Tensor> {
+        let data = (0..n_head * seq_len * HEAD_DIM)
+            .map(|i| ((i * 31 + seed * 17) % 3) as f32 * 127. - 127.)
+            .collect::<Vec<_>>();
+        Tensor::from_vec(data, (1, n_head, seq_len, HEAD_DIM), &Device::Cpu)
+    }
+
+    /// Attention weights with small integers so that the weighted sums are exact.
+    fn exact_weights(seq_len: usize, len: usize) -> Result<Tensor> {
+        let data = (0..N_HEAD * seq_len * len)
+            .map(|i| (i * 7 % 3) as f32)
+            .collect::<Vec<_>>();
+        Tensor::from_vec(data, (1, N_HEAD, seq_len, len), &Device::Cpu)
+    }
+
+    /// Checks the cache against the attention computed on the concatenated keys and
+    /// values, each query head uses the kv head of its group.
+    fn check_attention(cache: &KvCache, k: &Tensor, v: &Tensor) -> Result<()> {
+        let len = k.dim(2)?;
+        let q = exact_tensor(N_HEAD, 3, 7)?;
+        let att = exact_weights(3, len)?;
+        let group = N_HEAD / N_KV_HEAD;
+        let mut scores = vec![];
+        let mut values = vec![];
+        for head in 0..N_HEAD {
+            let kv_head = head / group;
+            let k = k.i((.., kv_head..kv_head + 1))?.contiguous()?;
+            let v = v.i((.., kv_head..kv_head + 1))?.contiguous()?;
+            let q = q.i((.., head..head + 1))?.contiguous()?;
+            let att = att.i((.., head..head + 1))?.contiguous()?;
+            scores.push(q.matmul(&k.t()?)?);
+            values.push(att.matmul(&v)?);
+        }
+
+        assert_eq!(cache.positions(), 0..len);
+        let diff = (cache.scores(&q)? - Tensor::cat(&scores, 1)?)?;
+        assert_eq!(diff.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?, 0.);
+        let diff = (cache.weighted_values(&att)? - Tensor::cat(&values, 1)?)?;
+        assert_eq!(diff.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?, 0.);
+        Ok(())
+    }
+
+    #[test]
+    fn append_matches_concat() -> Result<()> {
+        for cache_type in CACHE_TYPES {
+            let mut cache = KvCache::new(1024, None, cache_type);
+            let (mut ks, mut vs) = (vec![], vec![]);
+            // The chunks fill the first block and cross block boundaries.
+            for (idx, seq_len) in [5, 123, 1, 200].into_iter().enumerate() {
+                let k = exact_tensor(N_KV_HEAD, seq_len, idx)?;
+                let v = exact_tensor(N_KV_HEAD, seq_len, idx + 10)?;
+                cache.append(&k, &v)?;
+                ks.push(k);
+                vs.push(v);
+                check_attention(&cache, &Tensor::cat(&ks, 2)?, &Tensor::cat(&vs, 2)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn q8_cache_needs_block_sized_heads() {
+        assert!(KvCache::check_type(KvCacheType::Q8, 64).is_ok());
+        // Phi-2 heads.
+        assert!(KvCache::check_type(KvCacheType::Q8, 80).is_err());
+        assert!(KvCache::check_type(KvCacheType::F16, 80).is_ok());
+    }
+
+    #[test]
+    fn truncate_matches_concat() -> Result<()> {
+        for cache_type in CACHE_TYPES {
+            // A partial block is dense again in the Q8 cache, a full one is kept
+            // quantized.
+            for len in [200, 128, 0] {
+                let mut cache = KvCache::new(1024, None, cache_type);
+                let k = exact_tensor(N_KV_HEAD, 300, 1)?;
+                let v = exact_tensor(N_KV_HEAD, 300, 2)?;
+                cache.append(&k, &v)?;
+                cache.truncate(len)?;
+
+                let new_k = exact_tensor(N_KV_HEAD, 40, 3)?;
+                let new_v = exact_tensor(N_KV_HEAD, 40, 4)?;
+                cache.append(&new_k, &new_v)?;
+                let k = Tensor::cat(&[&k.narrow(2, 0, len)?, &new_k], 2)?;
+                let v = Tensor::cat(&[&v.narrow(2, 0, len)?, &new_v], 2)?;
+                check_attention(&cache, &k, &v)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn q8_cache_quantizes_full_blocks() -> Result<()> {
+        let mut cache = KvCache::new(1024, None, KvCacheType::Q8);
+        let k = exact_tensor(N_KV_HEAD, 300, 1)?;
+        cache.append(&k, &k)?;
+        assert_eq!(cache.quantized.len(), 2);
+        assert_eq!(cache.blocks.len(), 1);
+
+        cache.truncate(200)?;
+        assert_eq!(cache.quantized.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+// The parts of quantized_stable_lm.rs shared by the transformers built in its style,
+// the rotary embeddings, the causal mask, and the kv caches of the layers.
+use candle::{DType, Device, Result, Tensor, D};
+
+use super::kv_cache::KvCache;
+use super::weights::tensor_memory;
+use crate::models::KvCacheType;
+
+#[derive(Debug)]
+pub struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+fn rotate_half(xs: &Tensor) -> Result<Tensor> {
+    let xs = xs.chunk(2, D::Minus1)?;
+    Tensor::cat(&[&xs[1].neg()?, &xs[0]], D::Minus1)
+}
+
+impl RotaryEmbedding {
+    /// Creates the tables rotating the first `dim` dims of the heads, for positions up
+    /// to `max_seq_len`.
+    pub fn new(
+        dim: usize,
+        rope_theta: f64,
+        max_seq_len: usize,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Self> {
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / rope_theta.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        // The positions don't fit in half precision, the tables are computed in F32.
+        let freqs = t.matmul(&inv_freq)?;
+        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+        })
+    }
+
+    /// The memory used by the tables in bytes.
+    pub fn memory(&self) -> usize {
+        tensor_memory(&self.sin) + tensor_memory(&self.cos)
+    }
+
+    pub fn apply_rotary_emb(&self, xs: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_sz, _h, seq_len, _n_embd) = xs.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        xs.broadcast_mul(&cos)? + rotate_half(xs)?.broadcast_mul(&sin)
+    }
+}
+
+/// The attention mask of `tgt_len` tokens following `seqlen_offset` cached tokens.
+pub fn prepare_decoder_attention_mask(
+    b_size: usize,
+    tgt_len: usize,
+    seqlen_offset: usize,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor> {
+    let mask: Vec<_> = (0..tgt_len)
+        .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+        .collect();
+    let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), device)?;
+    let mask = if seqlen_offset > 0 {
+        let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, device)?;
+        Tensor::cat(&[&mask0, &mask], D::Minus1)?
+    } else {
+        mask
+    };
+    mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+        .to_dtype(dtype)
+}
+
+/// Rolls back the kv caches of the layers to the first `len` tokens.
+pub fn truncate_kv_caches<'a>(
+    caches: impl Iterator<Item = &'a mut KvCache>,
+    len: usize,
+) -> Result<()> {
+    for cache in caches {
+        cache.truncate(len)?;
+    }
+    Ok(())
+}
+
+/// Replaces the kv caches of the layers with empty ones of the given type, checking
+/// that the heads of size `head_dim` can use it.
+pub fn set_kv_caches_type<'a>(
+    caches: impl Iterator<Item = &'a mut KvCache>,
+    cache_type: KvCacheType,
+    head_dim: usize,
+    max_seq_len: usize,
+) -> Result<()> {
+    KvCache::check_type(cache_type, head_dim)?;
+    for cache in caches {
+        *cache = KvCache::new(max_seq_len, None, cache_type);
+    }
+    Ok(())
+}
+
+/// The memory used by the kv caches of the layers in bytes.
+pub fn kv_caches_memory<'a>(caches: impl Iterator<Item = &'a KvCache>) -> usize {
+    caches.map(KvCache::memory).sum()
+}
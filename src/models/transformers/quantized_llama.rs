@@ -2,16 +2,36 @@
 //
 // https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/quantized_llama.rs
 //
-// with some changes to rerun the same model instance on a new prompt (clear_kv_cache).
+// with some changes to rerun the same model instance on a new prompt (clear_kv_cache)
+// and to apply LoRA adapters to the projections (set_lora), to stop processing
+// between layers when cancelled (set_cancel_token), to process a prompt in chunks
+// (mask with an offset), to use a preallocated kv cache (KvCache), to support
+// sliding window attention with a rolling kv cache (set_sliding_window), to scale
+// the rotary embeddings to extend the context length (set_rope_scaling), and to count
+// the experts selected by the mixture of experts router (expert_stats).
 use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::ops::Range;
 
 use candle::quantized::QTensor;
 use candle::quantized::{ggml_file, gguf_file};
 use candle::{DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::{Embedding, Module};
 
+use super::kv_cache::KvCache;
+use super::weights::tensor_memory;
+use crate::models::{
+    lora::LoraAdapter, CancelToken, ExpertStats, KvCacheType, RopeScaling, RopeScalingType,
+};
+
+/// The context length used when the model metadata doesn't have one.
 pub const MAX_SEQ_LEN: usize = 4096;
 
+/// The YaRN interpolation ramp bounds, as the number of rotations over the original
+/// context length.
+const YARN_BETA_SLOW: f32 = 1.0;
+const YARN_BETA_FAST: f32 = 32.0;
+
 #[derive(Debug, Clone)]
 struct RmsNorm {
     inner: candle_nn::LayerNorm,
@@ -32,23 +52,94 @@ impl RmsNorm {
     }
 }
 
-// QMatMul wrapper adding some tracing.
+// A LoRA delta added to a projection output: scale * (xs A^T) B^T.
+#[derive(Debug, Clone)]
+struct Lora {
+    a: Tensor,
+    b: Tensor,
+    scale: f64,
+}
+
+// QMatMul wrapper adding some tracing and LoRA adapters.
 #[derive(Debug, Clone)]
 struct QMatMul {
     inner: candle::quantized::QMatMul,
+    /// The output and input dimensions.
+    dims: (usize, usize),
+    lora: Vec<Lora>,
     span: tracing::Span,
 }
 
 impl QMatMul {
     fn from_qtensor(qtensor: QTensor) -> Result<Self> {
+        let dims = qtensor.shape().dims2()?;
         let inner = candle::quantized::QMatMul::from_qtensor(qtensor)?;
         let span = tracing::span!(tracing::Level::TRACE, "qmatmul");
-        Ok(Self { inner, span })
+        Ok(Self {
+            inner,
+            dims,
+            lora: vec![],
+            span,
+        })
     }
 
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
-        self.inner.forward(xs)
+        let mut ys = self.inner.forward(xs)?;
+        for lora in &self.lora {
+            let delta = xs
+                .broadcast_matmul(&lora.a.t()?)?
+                .broadcast_matmul(&lora.b.t()?)?;
+            ys = (ys + (delta * lora.scale)?)?;
+        }
+        Ok(ys)
+    }
+
+    // Sets the LoRA adapters for this projection, the q and k projections need the B
+    // rows to be permuted by head as done by llama.cpp when converting the weights.
+    //
+    // The modules matched by each adapter are added to `matched`.
+    fn set_lora(
+        &mut self,
+        module: &str,
+        adapters: &[(&LoraAdapter, f64)],
+        permute_heads: Option<usize>,
+        matched: &mut [usize],
+    ) -> Result<()> {
+        let (out_dim, in_dim) = self.dims;
+        let mut lora = Vec::with_capacity(adapters.len());
+        for ((adapter, scale), matched) in adapters.iter().zip(matched) {
+            if let Some((a, b)) = adapter.weights(module) {
+                let (a_rank, a_in) = a.dims2()?;
+                let (b_out, b_rank) = b.dims2()?;
+                if a_in != in_dim || b_out != out_dim || a_rank != b_rank {
+                    candle::bail!(
+                        "LoRA adapter {} has {module} shapes {:?} and {:?}, expected \
+                        (rank, {in_dim}) and ({out_dim}, rank)",
+                        adapter.name,
+                        a.dims(),
+                        b.dims()
+                    );
+                }
+                *matched += 1;
+                let b = match permute_heads {
+                    Some(n_head) => {
+                        let (out_dim, rank) = b.dims2()?;
+                        b.reshape((n_head, 2, out_dim / n_head / 2, rank))?
+                            .transpose(1, 2)?
+                            .reshape((out_dim, rank))?
+                    }
+                    None => b.clone(),
+                };
+                lora.push(Lora {
+                    a: a.clone(),
+                    b,
+                    scale: scale * adapter.scaling,
+                });
+            }
+        }
+        self.lora = lora;
+        Ok(())
     }
 }
 
@@ -75,16 +166,19 @@ enum MlpOrMoe {
         n_expert_used: usize,
         feed_forward_gate_inp: QMatMul,
         experts: Vec<Mlp>,
+        /// How many times each expert has been selected since the last reset.
+        selections: Vec<usize>,
     },
 }
 
-impl Module for MlpOrMoe {
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+impl MlpOrMoe {
+    fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
         match self {
             Self::MoE {
                 feed_forward_gate_inp,
                 experts,
                 n_expert_used,
+                selections,
             } => {
                 let (b_size, seq_len, hidden_dim) = xs.dims3()?;
                 let xs = xs.reshape(((), hidden_dim))?;
@@ -108,6 +202,7 @@ impl Module for MlpOrMoe {
                         let routing_weight = rw[expert_idx];
                         sum_routing_weights += routing_weight;
                         top_x[expert_idx].push(row_idx as u32);
+                        selections[expert_idx] += 1;
                     }
                     for &expert_idx in dst.iter().take(*n_expert_used) {
                         let expert_idx = expert_idx as usize;
@@ -146,6 +241,12 @@ impl Module for MlpOrMoe {
             Self::Mlp(mlp) => mlp.forward(xs),
         }
     }
+
+    fn reset_selections(&mut self) {
+        if let Self::MoE { selections, .. } = self {
+            selections.fill(0);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -163,7 +264,7 @@ struct LayerWeights {
     cos: Tensor,
     sin: Tensor,
     neg_inf: Tensor,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
     span_mlp: tracing::Span,
@@ -225,51 +326,22 @@ impl LayerWeights {
         let q = self.apply_rotary_emb(&q, index_pos)?;
         let k = self.apply_rotary_emb(&k, index_pos)?;
 
-        let (k, v) = match &self.kv_cache {
-            None => (k, v),
-            Some((k_cache, v_cache)) => {
-                if index_pos == 0 {
-                    (k, v)
-                } else {
-                    let k = Tensor::cat(&[k_cache, &k], 2)?.contiguous()?;
-                    let v = Tensor::cat(&[v_cache, &v], 2)?.contiguous()?;
-                    (k, v)
-                }
-            }
-        };
-        self.kv_cache = Some((k.clone(), v.clone()));
-
-        // Support for MQA, useful for 70B models.
-        let k = self.repeat_kv(k)?;
-        let v = self.repeat_kv(v)?;
+        self.kv_cache.append(&k, &v)?;
 
-        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        // The cache handles MQA, useful for 70B models.
+        let att = (self.kv_cache.scores(&q)? / (self.head_dim as f64).sqrt())?;
         let mask = mask.broadcast_as(att.shape())?;
         let att = masked_fill(&att, &mask, &self.neg_inf)?;
         let att = candle_nn::ops::softmax_last_dim(&att)?;
-        // Convert to contiguous as matmul doesn't support strided vs for now.
-        let y = att.matmul(&v.contiguous()?)?;
+        let y = self.kv_cache.weighted_values(&att)?;
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
         let y = self.attention_wo.forward(&y)?;
         Ok(y)
     }
 
-    fn repeat_kv(&self, x: Tensor) -> Result<Tensor> {
-        let n_rep = self.n_head / self.n_kv_head;
-        if n_rep == 1 {
-            Ok(x)
-        } else {
-            let (b_sz, n_kv_head, seq_len, head_dim) = x.dims4()?;
-            let x = x
-                .unsqueeze(2)?
-                .expand((b_sz, n_kv_head, n_rep, seq_len, head_dim))?
-                .reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))?;
-            Ok(x)
-        }
-    }
-
     fn clear_kv_cache(&mut self) {
-        self.kv_cache = None;
+        self.kv_cache.reset();
+        self.mlp_or_moe.reset_selections();
     }
 }
 
@@ -279,37 +351,116 @@ pub struct Transformer {
     layers: Vec<LayerWeights>,
     norm: RmsNorm,
     output: QMatMul,
+    // The size of the quantized embeddings, that are not kept once dequantized.
+    quantized_embeddings_size: usize,
     masks: HashMap<usize, Tensor>,
+    rope: Rope,
+    max_seq_len: usize,
+    sliding_window: Option<usize>,
+    kv_cache_type: KvCacheType,
+    cancel: CancelToken,
     span: tracing::Span,
     span_output: tracing::Span,
 }
 
-fn precomput_freqs_cis(
-    head_dim: usize,
+// The rotary embeddings settings from the model metadata.
+#[derive(Debug, Clone, Copy)]
+struct Rope {
+    dim: usize,
     freq_base: f32,
-    device: &Device,
-) -> Result<(Tensor, Tensor)> {
-    let theta: Vec<_> = (0..head_dim)
-        .step_by(2)
-        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
-        .collect();
-    let theta = Tensor::new(theta.as_slice(), device)?;
-    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
-        .to_dtype(DType::F32)?
-        .reshape((MAX_SEQ_LEN, 1))?
-        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-    let cos = idx_theta.cos()?;
-    let sin = idx_theta.sin()?;
-    Ok((cos, sin))
+    // The context length the model was trained with.
+    context_length: usize,
+    // The scaling the model was trained with.
+    scaling: Option<RopeScaling>,
+    // The context length before the model scaling.
+    original_context_length: usize,
+}
+
+impl Rope {
+    // Returns the cos and sin tables and their length for the given scaling, None uses
+    // the model scaling.
+    fn tables(&self, scaling: Option<RopeScaling>, device: &Device) -> Result<(Tensor, Tensor)> {
+        let (scaling, max_seq_len) = match scaling {
+            Some(scaling) => {
+                let max_seq_len = self.original_context_length as f32 * scaling.factor;
+                (Some(scaling), max_seq_len as usize)
+            }
+            None => (self.scaling, self.context_length),
+        };
+
+        let dim = self.dim as f32;
+        let freq_base = match scaling {
+            Some(RopeScaling {
+                scaling_type: RopeScalingType::Ntk,
+                factor,
+            }) => self.freq_base * factor.powf(dim / (dim - 2.0)),
+            _ => self.freq_base,
+        };
+        let theta: Vec<_> = (0..self.dim)
+            .step_by(2)
+            .map(|i| {
+                let theta = 1f32 / freq_base.powf(i as f32 / dim);
+                match scaling {
+                    Some(RopeScaling {
+                        scaling_type: RopeScalingType::Linear,
+                        factor,
+                    }) => theta / factor,
+                    // Frequencies with few rotations over the original context are
+                    // interpolated and the ones with many rotations are kept.
+                    Some(RopeScaling {
+                        scaling_type: RopeScalingType::Yarn,
+                        factor,
+                    }) => {
+                        let rotations = self.original_context_length as f32 * theta / (2.0 * PI);
+                        let ramp = ((rotations - YARN_BETA_SLOW)
+                            / (YARN_BETA_FAST - YARN_BETA_SLOW))
+                            .clamp(0.0, 1.0);
+                        theta / factor * (1.0 - ramp) + theta * ramp
+                    }
+                    _ => theta,
+                }
+            })
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), device)?;
+        let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+
+        // YaRN also scales the attention logits, through both the queries and keys.
+        let mscale = match scaling {
+            Some(RopeScaling {
+                scaling_type: RopeScalingType::Yarn,
+                factor,
+            }) if factor > 1.0 => 0.1 * factor.ln() as f64 + 1.0,
+            _ => 1.0,
+        };
+        let cos = (idx_theta.cos()? * mscale)?;
+        let sin = (idx_theta.sin()? * mscale)?;
+        Ok((cos, sin))
+    }
 }
 
 impl Transformer {
-    #[allow(dead_code)]
     pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
+        if gqa == 0 || !(ct.hparams.n_head as usize).is_multiple_of(gqa) {
+            candle::bail!(
+                "gqa {gqa} doesn't divide the {} attention heads",
+                ct.hparams.n_head
+            );
+        }
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let (cos, sin) = precomput_freqs_cis(head_dim, 10000., &ct.device)?;
+        let rope = Rope {
+            dim: head_dim,
+            freq_base: 10000.,
+            context_length: MAX_SEQ_LEN,
+            scaling: None,
+            original_context_length: MAX_SEQ_LEN,
+        };
+        let (cos, sin) = rope.tables(None, &ct.device)?;
         let neg_inf = Tensor::new(f32::NEG_INFINITY, &ct.device)?;
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
+        let quantized_embeddings_size = tok_embeddings.storage_size_in_bytes();
         let tok_embeddings = tok_embeddings.dequantize(&ct.device)?;
         let norm = RmsNorm::new(ct.remove("norm.weight")?, 1e-5)?;
         let output = ct.remove("output.weight")?;
@@ -349,7 +500,7 @@ impl Transformer {
                 cos: cos.clone(),
                 sin: sin.clone(),
                 neg_inf: neg_inf.clone(),
-                kv_cache: None,
+                kv_cache: KvCache::new(MAX_SEQ_LEN, None, KvCacheType::default()),
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -362,7 +513,13 @@ impl Transformer {
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
+            quantized_embeddings_size,
             masks: HashMap::new(),
+            rope,
+            max_seq_len: MAX_SEQ_LEN,
+            sliding_window: None,
+            kv_cache_type: KvCacheType::default(),
+            cancel: CancelToken::default(),
             span,
             span_output,
         })
@@ -372,6 +529,7 @@ impl Transformer {
         ct: gguf_file::Content,
         reader: &mut R,
         device: &Device,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
     ) -> Result<Self> {
         let md_get = |s: &str| match ct.metadata.get(s) {
             None => candle::bail!("cannot find {s} in metadata"),
@@ -396,47 +554,88 @@ impl Transformer {
         let rope_freq_base = md_get("llama.rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
-        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
+        let context_length = md_get("llama.context_length")
+            .and_then(|m| m.to_u32())
+            .map_or(MAX_SEQ_LEN, |n| n as usize);
+        let rope_scaling_type = match md_get("llama.rope.scaling.type") {
+            Ok(m) => RopeScalingType::from_gguf(m.to_string()?),
+            // Older files only have the linear scale.
+            Err(_) => md_get("llama.rope.scale_linear")
+                .ok()
+                .map(|_| RopeScalingType::Linear),
+        };
+        let rope_scaling = match rope_scaling_type {
+            Some(scaling_type) => md_get("llama.rope.scaling.factor")
+                .or_else(|_| md_get("llama.rope.scale_linear"))
+                .and_then(|m| m.to_f32())
+                .ok()
+                .filter(|&factor| factor > 1.0)
+                .map(|factor| RopeScaling {
+                    scaling_type,
+                    factor,
+                }),
+            None => None,
+        };
+        let original_context_length = md_get("llama.rope.scaling.original_context_length")
+            .and_then(|m| m.to_u32())
+            .map_or_else(
+                |_| match rope_scaling {
+                    Some(scaling) => (context_length as f32 / scaling.factor) as usize,
+                    None => context_length,
+                },
+                |n| n as usize,
+            );
+        let rope = Rope {
+            dim: rope_dim,
+            freq_base: rope_freq_base,
+            context_length,
+            scaling: rope_scaling,
+            original_context_length,
+        };
+        let (cos, sin) = rope.tables(None, device)?;
         let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
 
-        let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
+        // Reads a tensor reporting the number of tensors loaded, the progress callback
+        // returns false to cancel loading.
+        let n_tensors = ct.tensor_infos.len();
+        let mut n_loaded = 0;
+        let mut tensor = |name: &str| {
+            let tensor = ct.tensor(reader, name, device)?;
+            n_loaded += 1;
+            if !progress(n_loaded, n_tensors) {
+                candle::bail!("Model loading cancelled");
+            }
+            Ok(tensor)
+        };
+
+        let tok_embeddings = tensor("token_embd.weight")?;
+        let quantized_embeddings_size = tok_embeddings.storage_size_in_bytes();
         let tok_embeddings = tok_embeddings.dequantize(device)?;
-        let norm = RmsNorm::new(
-            ct.tensor(reader, "output_norm.weight", device)?,
-            rms_norm_eps,
-        )?;
-        let output = ct.tensor(reader, "output.weight", device)?;
+        let norm = RmsNorm::new(tensor("output_norm.weight")?, rms_norm_eps)?;
+        let output = tensor("output.weight")?;
         let mut layers = Vec::with_capacity(block_count);
         for layer_idx in 0..block_count {
             let prefix = format!("blk.{layer_idx}");
-            let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
-            let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
-            let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
-            let attention_wo =
-                ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
+            let attention_wq = tensor(&format!("{prefix}.attn_q.weight"))?;
+            let attention_wk = tensor(&format!("{prefix}.attn_k.weight"))?;
+            let attention_wv = tensor(&format!("{prefix}.attn_v.weight"))?;
+            let attention_wo = tensor(&format!("{prefix}.attn_output.weight"))?;
             let mlp_or_moe = if n_expert <= 1 {
-                let feed_forward_w1 =
-                    ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
-                let feed_forward_w2 =
-                    ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
-                let feed_forward_w3 =
-                    ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
+                let feed_forward_w1 = tensor(&format!("{prefix}.ffn_gate.weight"))?;
+                let feed_forward_w2 = tensor(&format!("{prefix}.ffn_down.weight"))?;
+                let feed_forward_w3 = tensor(&format!("{prefix}.ffn_up.weight"))?;
                 MlpOrMoe::Mlp(Mlp {
                     feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
                     feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
                     feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
                 })
             } else {
-                let feed_forward_gate_inp =
-                    ct.tensor(reader, &format!("{prefix}.ffn_gate_inp.weight"), device)?;
+                let feed_forward_gate_inp = tensor(&format!("{prefix}.ffn_gate_inp.weight"))?;
                 let mut experts = Vec::with_capacity(n_expert);
                 for i in 0..n_expert {
-                    let feed_forward_w1 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_gate.{i}.weight"), device)?;
-                    let feed_forward_w2 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_down.{i}.weight"), device)?;
-                    let feed_forward_w3 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_up.{i}.weight"), device)?;
+                    let feed_forward_w1 = tensor(&format!("{prefix}.ffn_gate.{i}.weight"))?;
+                    let feed_forward_w2 = tensor(&format!("{prefix}.ffn_down.{i}.weight"))?;
+                    let feed_forward_w3 = tensor(&format!("{prefix}.ffn_up.{i}.weight"))?;
                     experts.push(Mlp {
                         feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
                         feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
@@ -446,12 +645,12 @@ impl Transformer {
                 MlpOrMoe::MoE {
                     n_expert_used,
                     feed_forward_gate_inp: QMatMul::from_qtensor(feed_forward_gate_inp)?,
+                    selections: vec![0; experts.len()],
                     experts,
                 }
             };
-            let attention_norm =
-                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
-            let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
+            let attention_norm = tensor(&format!("{prefix}.attn_norm.weight"))?;
+            let ffn_norm = tensor(&format!("{prefix}.ffn_norm.weight"))?;
             let span_attn = tracing::span!(tracing::Level::TRACE, "attn");
             let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
             let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
@@ -469,7 +668,7 @@ impl Transformer {
                 cos: cos.clone(),
                 sin: sin.clone(),
                 neg_inf: neg_inf.clone(),
-                kv_cache: None,
+                kv_cache: KvCache::new(context_length, None, KvCacheType::default()),
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -482,31 +681,84 @@ impl Transformer {
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
+            quantized_embeddings_size,
             masks: HashMap::new(),
+            rope,
+            max_seq_len: context_length,
+            sliding_window: None,
+            kv_cache_type: KvCacheType::default(),
+            cancel: CancelToken::default(),
             span,
             span_output,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
+    // Returns a mask for t new tokens at the end of the cached positions, the cached
+    // tokens are visible to all the new tokens unless they are out of their window.
+    fn mask(&mut self, t: usize, cached: Range<usize>, device: &Device) -> Result<Tensor> {
+        let (start, offset) = (cached.start, cached.end - t);
+        if let Some(window) = self.sliding_window {
+            if offset + t > start + window {
+                let mask: Vec<_> = (offset..offset + t)
+                    .flat_map(|i| {
+                        (start..offset + t).map(move |j| u8::from(j > i || i - j >= window))
+                    })
+                    .collect();
+                return Tensor::from_slice(&mask, (t, cached.len()), device);
+            }
+        }
+
+        let mask = if let Some(mask) = self.masks.get(&t) {
+            mask.clone()
         } else {
             let mask: Vec<_> = (0..t)
                 .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
                 .collect();
             let mask = Tensor::from_slice(&mask, (t, t), device)?;
             self.masks.insert(t, mask.clone());
+            mask
+        };
+
+        // A single token mask broadcasts to the cache length.
+        if offset == start || t == 1 {
             Ok(mask)
+        } else {
+            let cached = Tensor::zeros((t, offset - start), DType::U8, device)?;
+            Tensor::cat(&[&cached, &mask], 1)
         }
     }
 
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let seq_len = x.dim(1)?;
+        let x = self.forward_layers(x, index_pos)?;
+        let x = self.norm.forward(&x)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)
+    }
+
+    /// Processes the input tokens to fill the kv cache, without computing the logits.
+    pub fn fill_kv_cache(&mut self, x: &Tensor, index_pos: usize) -> Result<()> {
+        self.forward_layers(x, index_pos)?;
+        Ok(())
+    }
+
+    /// Runs the layers returning their output for all the input tokens.
+    fn forward_layers(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
-        let mask = self.mask(seq_len, x.device())?;
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        // All the layers cache the same positions.
+        for layer in self.layers.iter_mut() {
+            layer.kv_cache.reserve(seq_len)?;
+        }
+        let cached = self.layers[0].kv_cache.positions();
+        let mask = self.mask(seq_len, cached.start..cached.end + seq_len, x.device())?;
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
         for layer in self.layers.iter_mut() {
+            self.cancel.check()?;
             let x = layer_in;
             let residual = &x;
             let x = layer.attention_norm.forward(&x)?;
@@ -521,10 +773,63 @@ impl Transformer {
             let x = (x + residual)?;
             layer_in = x
         }
-        let x = self.norm.forward(&layer_in)?;
-        let x = x.i((.., seq_len - 1, ..))?;
-        let _enter = self.span_output.enter();
-        self.output.forward(&x)
+        Ok(layer_in)
+    }
+
+    /// Sets the token checked between layers to stop processing.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    /// Applies the given LoRA adapters with their scales, replacing the current ones.
+    ///
+    /// An empty list restores the base model weights. Adapters with weights that don't
+    /// fit the projections, or that match none of them, are an error and the base
+    /// model weights are restored.
+    pub fn set_lora(&mut self, adapters: &[(&LoraAdapter, f64)]) -> Result<()> {
+        let result = self.try_set_lora(adapters);
+        if result.is_err() {
+            self.try_set_lora(&[])?;
+        }
+        result
+    }
+
+    fn try_set_lora(&mut self, adapters: &[(&LoraAdapter, f64)]) -> Result<()> {
+        let mut matched = vec![0; adapters.len()];
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = format!("layers.{layer_idx}.self_attn");
+            let (n_head, n_kv_head) = (Some(layer.n_head), Some(layer.n_kv_head));
+            let attn = &mut [
+                (&mut layer.attention_wq, "q_proj", n_head),
+                (&mut layer.attention_wk, "k_proj", n_kv_head),
+                (&mut layer.attention_wv, "v_proj", None),
+                (&mut layer.attention_wo, "o_proj", None),
+            ];
+            for (proj, name, permute_heads) in attn {
+                let module = format!("{prefix}.{name}");
+                proj.set_lora(&module, adapters, *permute_heads, &mut matched)?;
+            }
+
+            if let MlpOrMoe::Mlp(mlp) = &mut layer.mlp_or_moe {
+                let prefix = format!("layers.{layer_idx}.mlp");
+                let mlp = &mut [
+                    (&mut mlp.feed_forward_w1, "gate_proj"),
+                    (&mut mlp.feed_forward_w2, "down_proj"),
+                    (&mut mlp.feed_forward_w3, "up_proj"),
+                ];
+                for (proj, name) in mlp {
+                    proj.set_lora(&format!("{prefix}.{name}"), adapters, None, &mut matched)?;
+                }
+            }
+        }
+
+        if let Some(((adapter, _), _)) = adapters.iter().zip(&matched).find(|(_, &n)| n == 0) {
+            candle::bail!(
+                "LoRA adapter {} matches no projection of the model",
+                adapter.name
+            );
+        }
+        Ok(())
     }
 
     /// Resets the mode for a new prompt.
@@ -533,4 +838,198 @@ impl Transformer {
             layer.clear_kv_cache();
         }
     }
+
+    /// Rolls back the kv cache to the first `len` tokens.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        for layer in &mut self.layers {
+            layer.kv_cache.truncate(len)?;
+        }
+        Ok(())
+    }
+
+    /// Sets how the kv cache is stored, this clears the cache.
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        if let Some(layer) = self.layers.first() {
+            KvCache::check_type(cache_type, layer.head_dim)?;
+        }
+        self.kv_cache_type = cache_type;
+        self.reset_kv_cache();
+        Ok(())
+    }
+
+    /// Sets the sliding window for models trained with one, this clears the cache.
+    ///
+    /// The kv cache then keeps rolling so generation can continue past the window up to
+    /// the training context length.
+    pub fn set_sliding_window(&mut self, sliding_window: Option<usize>) {
+        self.sliding_window = sliding_window;
+        self.reset_kv_cache();
+    }
+
+    /// Sets the scaling of the rotary embeddings, None uses the model scaling, this
+    /// clears the cache.
+    ///
+    /// The context length becomes the original context length times the factor.
+    pub fn set_rope_scaling(&mut self, scaling: Option<RopeScaling>) -> Result<()> {
+        let device = self.tok_embeddings.embeddings().device().clone();
+        let (cos, sin) = self.rope.tables(scaling, &device)?;
+        self.max_seq_len = cos.dim(0)?;
+        for layer in &mut self.layers {
+            layer.cos = cos.clone();
+            layer.sin = sin.clone();
+        }
+        self.reset_kv_cache();
+        Ok(())
+    }
+
+    /// The maximum number of tokens of a sequence.
+    pub fn context_length(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // Creates new kv caches with the current settings.
+    fn reset_kv_cache(&mut self) {
+        let capacity = self.context_length();
+        for layer in &mut self.layers {
+            layer.kv_cache = KvCache::new(capacity, self.sliding_window, self.kv_cache_type);
+        }
+    }
+
+    /// The memory used by the kv cache in bytes.
+    pub fn kv_cache_memory(&self) -> usize {
+        self.layers.iter().map(|l| l.kv_cache.memory()).sum()
+    }
+
+    /// The memory used by the tensors computed from the weights in bytes, the
+    /// dequantized embeddings in place of the quantized ones and the rotary embeddings
+    /// tables shared by the layers.
+    pub fn derived_memory(&self) -> usize {
+        let embeddings = tensor_memory(self.tok_embeddings.embeddings())
+            .saturating_sub(self.quantized_embeddings_size);
+        let rope = self
+            .layers
+            .first()
+            .map(|l| tensor_memory(&l.cos) + tensor_memory(&l.sin))
+            .unwrap_or_default();
+        embeddings + rope
+    }
+
+    /// The number of times each expert of each layer has been selected since the last
+    /// prompt, None for models without a mixture of experts.
+    pub fn expert_stats(&self) -> Option<ExpertStats> {
+        let mut n_used = 0;
+        let selections = self
+            .layers
+            .iter()
+            .map(|layer| match &layer.mlp_or_moe {
+                MlpOrMoe::MoE {
+                    n_expert_used,
+                    selections,
+                    ..
+                } => {
+                    n_used = *n_expert_used;
+                    Some(selections.clone())
+                }
+                MlpOrMoe::Mlp(_) => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(ExpertStats {
+            n_expert_used: n_used,
+            selections,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::quantized::GgmlDType;
+
+    const VOCAB: usize = 32;
+    const EMBD: usize = 64;
+
+    /// A two layers transformer with random weights and grouped query attention.
+    fn tiny_transformer() -> Result<Transformer> {
+        let device = Device::Cpu;
+        let ff = 96;
+        let mut shapes = vec![
+            ("tok_embeddings.weight".to_string(), vec![VOCAB, EMBD]),
+            ("norm.weight".to_string(), vec![EMBD]),
+            ("output.weight".to_string(), vec![VOCAB, EMBD]),
+        ];
+        for layer_idx in 0..2 {
+            let prefix = format!("layers.{layer_idx}");
+            for (name, shape) in [
+                ("attention.wq", vec![EMBD, EMBD]),
+                ("attention.wk", vec![EMBD / 2, EMBD]),
+                ("attention.wv", vec![EMBD / 2, EMBD]),
+                ("attention.wo", vec![EMBD, EMBD]),
+                ("feed_forward.w1", vec![ff, EMBD]),
+                ("feed_forward.w2", vec![EMBD, ff]),
+                ("feed_forward.w3", vec![ff, EMBD]),
+                ("attention_norm", vec![EMBD]),
+                ("ffn_norm", vec![EMBD]),
+            ] {
+                shapes.push((format!("{prefix}.{name}.weight"), shape));
+            }
+        }
+
+        let mut tensors = HashMap::new();
+        for (name, shape) in shapes {
+            let tensor = Tensor::randn(0f32, 0.2, shape, &device)?;
+            tensors.insert(name, QTensor::quantize(&tensor, GgmlDType::F32)?);
+        }
+        let content = ggml_file::Content {
+            magic: ggml_file::VersionedMagic::GgjtV3,
+            hparams: ggml_file::HParams {
+                n_vocab: VOCAB as u32,
+                n_embd: EMBD as u32,
+                n_mult: 0,
+                n_head: 4,
+                n_layer: 2,
+                n_rot: (EMBD / 4) as u32,
+                ftype: 0,
+            },
+            vocab: ggml_file::Vocab {
+                token_score_pairs: vec![],
+            },
+            tensors,
+            device,
+        };
+        Transformer::from_ggml(content, 2)
+    }
+
+    #[test]
+    fn chunked_prefill_matches_single_pass() -> Result<()> {
+        let mut model = tiny_transformer()?;
+        let tokens = (0..13).map(|i| (i * 7 % VOCAB) as u32).collect::<Vec<_>>();
+        let input = |range: Range<usize>| Tensor::new(&tokens[range], &Device::Cpu)?.unsqueeze(0);
+
+        let single = model.forward(&input(0..13)?, 0)?;
+
+        model.fill_kv_cache(&input(0..5)?, 0)?;
+        model.fill_kv_cache(&input(5..10)?, 5)?;
+        let chunked = model.forward(&input(10..13)?, 10)?;
+
+        assert_eq!(single.dims(), [1, VOCAB]);
+        let diff = (single - chunked)?.abs()?.max_keepdim(1)?.flatten_all()?;
+        let diff = diff.to_vec1::<f32>()?[0];
+        assert!(diff < 1e-4, "logits differ by {diff}");
+        Ok(())
+    }
+    #[test]
+    fn derived_memory_follows_rope_scaling() -> Result<()> {
+        let mut model = tiny_transformer()?;
+        // The F32 embeddings take the place of their weights, the cos and sin tables
+        // have half a head of frequencies per position.
+        let tables = |len: usize| 2 * len * (EMBD / 4 / 2) * 4;
+        assert_eq!(model.derived_memory(), tables(MAX_SEQ_LEN));
+
+        model.set_rope_scaling(Some(RopeScaling {
+            scaling_type: RopeScalingType::Linear,
+            factor: 2.0,
+        }))?;
+        assert_eq!(model.derived_memory(), tables(2 * MAX_SEQ_LEN));
+        Ok(())
+    }
 }
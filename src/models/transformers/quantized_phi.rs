@@ -0,0 +1,431 @@
+// A quantized Phi-2 and Phi-3 implementation for the GGUF files converted by llama.cpp,
+// built like quantized_stable_lm.rs from the shared layers.
+//
+// Phi-2 runs the attention and the MLP in parallel on the output of a single layer norm
+// and rotates only part of the heads dims, Phi-3 is a Llama like model with RMS norms
+// and a gated MLP. Both have a fused qkv projection.
+use candle::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Activation, LayerNorm};
+use std::sync::Arc;
+
+use super::kv_cache::KvCache;
+use super::layers::{
+    kv_caches_memory, prepare_decoder_attention_mask, set_kv_caches_type, truncate_kv_caches,
+    RotaryEmbedding,
+};
+use super::weights::{embedding, layer_norm, linear, linear_no_bias, rms_norm, Linear, VarBuilder};
+use crate::models::{CancelToken, KvCacheType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Phi2,
+    Phi3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub architecture: Architecture,
+    pub vocab_size: usize,
+    pub intermediate_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub hidden_act: Activation,
+    pub rotary_dim: usize,
+    pub rope_theta: f64,
+    pub max_position_embeddings: usize,
+    pub norm_eps: f64,
+}
+
+impl Config {
+    /// The Phi-2 config.
+    pub fn phi_2() -> Self {
+        Self {
+            architecture: Architecture::Phi2,
+            vocab_size: 51200,
+            intermediate_size: 10240,
+            hidden_size: 2560,
+            num_hidden_layers: 32,
+            num_attention_heads: 32,
+            num_key_value_heads: 32,
+            hidden_act: Activation::NewGelu,
+            rotary_dim: 32,
+            rope_theta: 10_000.,
+            max_position_embeddings: 2048,
+            norm_eps: 1e-5,
+        }
+    }
+
+    /// The Phi-3 mini config with a 4k context.
+    pub fn phi_3_mini_4k() -> Self {
+        Self {
+            architecture: Architecture::Phi3,
+            vocab_size: 32064,
+            intermediate_size: 8192,
+            hidden_size: 3072,
+            num_hidden_layers: 32,
+            num_attention_heads: 32,
+            num_key_value_heads: 32,
+            hidden_act: Activation::Silu,
+            rotary_dim: 96,
+            rope_theta: 10_000.,
+            max_position_embeddings: 4096,
+            norm_eps: 1e-5,
+        }
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    // Phi-2 uses layer norms, Phi-3 RMS norms.
+    fn norm(&self, vb: VarBuilder) -> Result<LayerNorm> {
+        match self.architecture {
+            Architecture::Phi2 => layer_norm(self.hidden_size, self.norm_eps, vb),
+            Architecture::Phi3 => rms_norm(self.hidden_size, self.norm_eps, vb),
+        }
+    }
+
+    // Phi-2 linear layers have biases, Phi-3 ones don't.
+    fn linear(&self, in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Linear> {
+        match self.architecture {
+            Architecture::Phi2 => linear(in_dim, out_dim, vb),
+            Architecture::Phi3 => linear_no_bias(in_dim, out_dim, vb),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+    /// Phi-3 fuses the gate and up projections, the gate comes first.
+    gated: bool,
+    intermediate_size: usize,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gated = cfg.architecture == Architecture::Phi3;
+        let up_sz = if gated {
+            2 * intermediate_sz
+        } else {
+            intermediate_sz
+        };
+        let up_proj = cfg.linear(hidden_sz, up_sz, vb.pp("ffn_up"))?;
+        let down_proj = cfg.linear(intermediate_sz, hidden_sz, vb.pp("ffn_down"))?;
+        Ok(Self {
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+            gated,
+            intermediate_size: intermediate_sz,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let up_states = xs.apply(&self.up_proj)?;
+        let xs = if self.gated {
+            let gate = up_states.narrow(D::Minus1, 0, self.intermediate_size)?;
+            let up_states =
+                up_states.narrow(D::Minus1, self.intermediate_size, self.intermediate_size)?;
+            (gate.apply(&self.act_fn)? * up_states)?
+        } else {
+            up_states.apply(&self.act_fn)?
+        };
+        xs.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    qkv_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: KvCache,
+    rotary_ndims: usize,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let head_dim = cfg.head_dim();
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let op_size = (num_heads + 2 * num_kv_heads) * head_dim;
+        let qkv_proj = cfg.linear(hidden_sz, op_size, vb.pp("attn_qkv"))?;
+        let o_proj = cfg.linear(num_heads * head_dim, hidden_sz, vb.pp("attn_output"))?;
+        Ok(Self {
+            qkv_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            hidden_size: hidden_sz,
+            rotary_emb,
+            kv_cache: KvCache::new(cfg.max_position_embeddings, None, KvCacheType::default()),
+            rotary_ndims: cfg.rotary_dim,
+        })
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache.reset();
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let qkv = self.qkv_proj.forward(xs)?;
+        let query_pos = self.num_heads * self.head_dim;
+        let kv_size = self.num_kv_heads * self.head_dim;
+        let query_states = qkv.narrow(D::Minus1, 0, query_pos)?;
+        let key_states = qkv.narrow(D::Minus1, query_pos, kv_size)?;
+        let value_states = qkv.narrow(D::Minus1, query_pos + kv_size, kv_size)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let (rot_ndims, pass_ndims) = (self.rotary_ndims, self.head_dim - self.rotary_ndims);
+        let query_rot = query_states.narrow(D::Minus1, 0, rot_ndims)?;
+        let query_pass = query_states.narrow(D::Minus1, rot_ndims, pass_ndims)?;
+        let key_rot = key_states.narrow(D::Minus1, 0, rot_ndims)?;
+        let key_pass = key_states.narrow(D::Minus1, rot_ndims, pass_ndims)?;
+        let query_rot = self
+            .rotary_emb
+            .apply_rotary_emb(&query_rot.contiguous()?, seqlen_offset)?;
+        let key_rot = self
+            .rotary_emb
+            .apply_rotary_emb(&key_rot.contiguous()?, seqlen_offset)?;
+        let query_states = Tensor::cat(&[query_rot, query_pass], D::Minus1)?.contiguous()?;
+        let key_states = Tensor::cat(&[key_rot, key_pass], D::Minus1)?.contiguous()?;
+
+        self.kv_cache.append(&key_states, &value_states)?;
+
+        let attn_output = {
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            let attn_weights = (self.kv_cache.scores(&query_states)? * scale)?;
+
+            let attn_weights = match attention_mask {
+                None => attn_weights,
+                Some(mask) => attn_weights.broadcast_add(mask)?,
+            };
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            self.kv_cache.weighted_values(&attn_weights)?
+        };
+        attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: LayerNorm,
+    /// Phi-2 has no post attention norm as the MLP runs in parallel to the attention.
+    post_attention_layernorm: Option<LayerNorm>,
+}
+
+impl DecoderLayer {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, &vb)?;
+        let mlp = MLP::new(cfg, &vb)?;
+        let input_layernorm = cfg.norm(vb.pp("attn_norm"))?;
+        let post_attention_layernorm = match cfg.architecture {
+            Architecture::Phi2 => None,
+            Architecture::Phi3 => Some(cfg.norm(vb.pp("ffn_norm"))?),
+        };
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache();
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let attn_outputs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        match &self.post_attention_layernorm {
+            None => {
+                let feed_forward_hidden_states = self.mlp.forward(&xs)?;
+                attn_outputs + feed_forward_hidden_states + residual
+            }
+            Some(post_attention_layernorm) => {
+                let xs = (attn_outputs + residual)?;
+                let residual = &xs;
+                let xs = xs.apply(post_attention_layernorm)?.apply(&self.mlp)?;
+                residual + xs
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transformer {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: LayerNorm,
+    lm_head: Linear,
+    device: Device,
+    dtype: DType,
+    head_dim: usize,
+    max_seq_len: usize,
+    derived_memory: usize,
+    cancel: CancelToken,
+}
+
+impl Transformer {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("token_embd"))?;
+        let embeddings_memory =
+            vb.dequantized_memory(embed_tokens.embeddings(), "token_embd.weight")?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(
+            cfg.rotary_dim,
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            vb.dtype(),
+            vb.device(),
+        )?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb.pp("blk");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx))?;
+            layers.push(layer);
+            if !progress(layer_idx + 1, cfg.num_hidden_layers) {
+                candle::bail!("Model loading cancelled");
+            }
+        }
+        let derived_memory = embeddings_memory + rotary_emb.memory();
+        let norm = cfg.norm(vb.pp("output_norm"))?;
+        let lm_head = cfg.linear(cfg.hidden_size, cfg.vocab_size, vb.pp("output"))?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+            head_dim: cfg.head_dim(),
+            max_seq_len: cfg.max_position_embeddings,
+            derived_memory,
+            cancel: CancelToken::default(),
+        })
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        self.forward_layers(input_ids, seqlen_offset)?
+            .narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Processes the input tokens to fill the kv cache, without computing the logits.
+    pub fn fill_kv_cache(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<()> {
+        self.forward_layers(input_ids, seqlen_offset)?;
+        Ok(())
+    }
+
+    /// Runs the layers returning their output for all the input tokens.
+    fn forward_layers(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = prepare_decoder_attention_mask(
+                b_size,
+                seq_len,
+                seqlen_offset,
+                self.dtype,
+                &self.device,
+            )?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            self.cancel.check()?;
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        Ok(xs)
+    }
+
+    /// Sets the token checked between layers to stop processing.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    /// The maximum sequence length supported by the model.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Resets the model for a new prompt.
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.clear_kv_cache();
+        }
+    }
+
+    /// Rolls back the kv cache to the first `len` tokens.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        let caches = self.layers.iter_mut().map(|l| &mut l.self_attn.kv_cache);
+        truncate_kv_caches(caches, len)
+    }
+
+    /// Sets how the kv cache is stored, this clears the cache.
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        let caches = self.layers.iter_mut().map(|l| &mut l.self_attn.kv_cache);
+        set_kv_caches_type(caches, cache_type, self.head_dim, self.max_seq_len)
+    }
+
+    /// The memory used by the kv cache in bytes.
+    pub fn kv_cache_memory(&self) -> usize {
+        kv_caches_memory(self.layers.iter().map(|l| &l.self_attn.kv_cache))
+    }
+
+    /// The memory used by the tensors computed from the weights in bytes, the
+    /// dequantized embeddings and the rotary embeddings tables.
+    pub fn derived_memory(&self) -> usize {
+        self.derived_memory
+    }
+}
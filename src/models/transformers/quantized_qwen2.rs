@@ -0,0 +1,380 @@
+// A quantized Qwen2 implementation for the GGUF files converted by llama.cpp, built like
+// quantized_stable_lm.rs from the shared layers.
+//
+// Qwen2 is a Llama like model with biases in the query, key and value projections,
+// grouped query attention and a 152k tokens vocabulary. The smaller models share the
+// embeddings weights with the output layer.
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, LayerNorm};
+use std::sync::Arc;
+
+use super::kv_cache::KvCache;
+use super::layers::{
+    kv_caches_memory, prepare_decoder_attention_mask, set_kv_caches_type, truncate_kv_caches,
+    RotaryEmbedding,
+};
+use super::weights::{linear, linear_no_bias, rms_norm, tensor_memory, Linear, VarBuilder};
+use crate::models::{CancelToken, KvCacheType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub intermediate_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub hidden_act: Activation,
+    pub rope_theta: f64,
+    pub max_position_embeddings: usize,
+    pub rms_norm_eps: f64,
+}
+
+impl Config {
+    /// The Qwen2 1.5B config.
+    pub fn qwen2_1_5b() -> Self {
+        Self {
+            vocab_size: 151936,
+            intermediate_size: 8960,
+            hidden_size: 1536,
+            num_hidden_layers: 28,
+            num_attention_heads: 12,
+            num_key_value_heads: 2,
+            hidden_act: Activation::Silu,
+            rope_theta: 1_000_000.,
+            max_position_embeddings: 32768,
+            rms_norm_eps: 1e-6,
+        }
+    }
+
+    /// The Qwen2 7B config.
+    pub fn qwen2_7b() -> Self {
+        Self {
+            vocab_size: 152064,
+            intermediate_size: 18944,
+            hidden_size: 3584,
+            num_hidden_layers: 28,
+            num_attention_heads: 28,
+            num_key_value_heads: 4,
+            hidden_act: Activation::Silu,
+            rope_theta: 1_000_000.,
+            max_position_embeddings: 32768,
+            rms_norm_eps: 1e-6,
+        }
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("ffn_gate"))?;
+        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("ffn_up"))?;
+        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("ffn_down"))?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: &VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let head_dim = cfg.head_dim();
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let q_proj = linear(hidden_sz, num_heads * head_dim, vb.pp("attn_q"))?;
+        let k_proj = linear(hidden_sz, num_kv_heads * head_dim, vb.pp("attn_k"))?;
+        let v_proj = linear(hidden_sz, num_kv_heads * head_dim, vb.pp("attn_v"))?;
+        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("attn_output"))?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            rotary_emb,
+            kv_cache: KvCache::new(cfg.max_position_embeddings, None, KvCacheType::default()),
+        })
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache.reset();
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let query_states = self
+            .rotary_emb
+            .apply_rotary_emb(&query_states, seqlen_offset)?;
+        let key_states = self
+            .rotary_emb
+            .apply_rotary_emb(&key_states, seqlen_offset)?;
+
+        self.kv_cache.append(&key_states, &value_states)?;
+
+        let attn_output = {
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            let attn_weights = (self.kv_cache.scores(&query_states)? * scale)?;
+
+            let attn_weights = match attention_mask {
+                None => attn_weights,
+                Some(mask) => attn_weights.broadcast_add(mask)?,
+            };
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            self.kv_cache.weighted_values(&attn_weights)?
+        };
+        attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.num_heads * self.head_dim))?
+            .apply(&self.o_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: LayerNorm,
+    post_attention_layernorm: LayerNorm,
+}
+
+impl DecoderLayer {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, &vb)?;
+        let mlp = MLP::new(cfg, &vb)?;
+        let input_layernorm = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("attn_norm"))?;
+        let post_attention_layernorm =
+            rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("ffn_norm"))?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache();
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transformer {
+    /// The embeddings are stored as F16, as the large vocabulary takes gigabytes in F32.
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: LayerNorm,
+    lm_head: Linear,
+    device: Device,
+    head_dim: usize,
+    max_seq_len: usize,
+    derived_memory: usize,
+    cancel: CancelToken,
+}
+
+impl Transformer {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
+        let embeddings = vb.get((cfg.vocab_size, cfg.hidden_size), "token_embd.weight")?;
+        let embed_tokens =
+            candle_nn::Embedding::new(embeddings.to_dtype(DType::F16)?, cfg.hidden_size);
+        // Without an output layer the quantized embeddings weights are shared.
+        let (lm_head_vb, embeddings_memory) = if vb.contains_tensor("output.weight") {
+            let memory = vb.dequantized_memory(embed_tokens.embeddings(), "token_embd.weight")?;
+            (vb.pp("output"), memory)
+        } else {
+            (
+                vb.pp("token_embd"),
+                tensor_memory(embed_tokens.embeddings()),
+            )
+        };
+        let lm_head = linear_no_bias(cfg.hidden_size, cfg.vocab_size, lm_head_vb)?;
+
+        let rotary_emb = Arc::new(RotaryEmbedding::new(
+            cfg.head_dim(),
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            DType::F32,
+            vb.device(),
+        )?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb.pp("blk");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx))?;
+            layers.push(layer);
+            if !progress(layer_idx + 1, cfg.num_hidden_layers) {
+                candle::bail!("Model loading cancelled");
+            }
+        }
+        let derived_memory = embeddings_memory + rotary_emb.memory();
+        let norm = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("output_norm"))?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: vb.device().clone(),
+            head_dim: cfg.head_dim(),
+            max_seq_len: cfg.max_position_embeddings,
+            derived_memory,
+            cancel: CancelToken::default(),
+        })
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        self.forward_layers(input_ids, seqlen_offset)?
+            .narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Processes the input tokens to fill the kv cache, without computing the logits.
+    pub fn fill_kv_cache(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<()> {
+        self.forward_layers(input_ids, seqlen_offset)?;
+        Ok(())
+    }
+
+    /// Runs the layers returning their output for all the input tokens.
+    fn forward_layers(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = prepare_decoder_attention_mask(
+                b_size,
+                seq_len,
+                seqlen_offset,
+                DType::F32,
+                &self.device,
+            )?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?.to_dtype(DType::F32)?;
+        for layer in self.layers.iter_mut() {
+            self.cancel.check()?;
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        Ok(xs)
+    }
+
+    /// Sets the token checked between layers to stop processing.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    /// The maximum sequence length supported by the model.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Resets the model for a new prompt.
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.clear_kv_cache();
+        }
+    }
+
+    /// Rolls back the kv cache to the first `len` tokens.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        let caches = self.layers.iter_mut().map(|l| &mut l.self_attn.kv_cache);
+        truncate_kv_caches(caches, len)
+    }
+
+    /// Sets how the kv cache is stored, this clears the cache.
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        let caches = self.layers.iter_mut().map(|l| &mut l.self_attn.kv_cache);
+        set_kv_caches_type(caches, cache_type, self.head_dim, self.max_seq_len)
+    }
+
+    /// The memory used by the kv cache in bytes.
+    pub fn kv_cache_memory(&self) -> usize {
+        kv_caches_memory(self.layers.iter().map(|l| &l.self_attn.kv_cache))
+    }
+
+    /// The memory used by the tensors computed from the weights in bytes, the
+    /// dequantized embeddings and the rotary embeddings tables.
+    pub fn derived_memory(&self) -> usize {
+        self.derived_memory
+    }
+}
@@ -2,15 +2,24 @@
 //
 // https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/quantized_stable_lm.rs
 //
-// with some changes to rerun the same model instance on a new prompt (clear_kv_cache).
+// with some changes to rerun the same model instance on a new prompt (clear_kv_cache),
+// to stop processing between layers when cancelled (set_cancel_token), and to use a
+// preallocated kv cache (KvCache).
+//
+// The layers are created from GGUF or safetensors weights (weights::VarBuilder), the
+// config of a safetensors model is read from its config.json.
 use candle::{DType, Device, Module, Result, Tensor, D};
 use candle_nn::{Activation, LayerNorm};
-use candle_transformers::{
-    quantized_nn::{layer_norm, linear, linear_no_bias, Embedding, Linear},
-    quantized_var_builder::VarBuilder,
-};
 use std::sync::Arc;
 
+use super::kv_cache::KvCache;
+use super::layers::{
+    kv_caches_memory, prepare_decoder_attention_mask, set_kv_caches_type, truncate_kv_caches,
+    RotaryEmbedding,
+};
+use super::weights::{embedding, layer_norm, linear, linear_no_bias, Linear, VarBuilder};
+use crate::models::{CancelToken, KvCacheType};
+
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct Config {
     pub vocab_size: usize,
@@ -20,9 +29,11 @@ pub struct Config {
     pub num_attention_heads: usize,
     pub num_key_value_heads: usize,
     pub hidden_act: Activation,
+    #[serde(alias = "partial_rotary_factor")]
     pub rope_pct: f64,
     pub rope_theta: f64,
     pub max_position_embeddings: usize,
+    #[serde(alias = "layer_norm_eps")]
     pub norm_eps: f64,
     pub use_cache: bool,
     #[serde(default)]
@@ -30,7 +41,8 @@ pub struct Config {
 }
 
 impl Config {
-    fn new() -> Self {
+    /// The StableLM 2 1.6B config, used for the GGUF weights that have no config.
+    pub fn stablelm_2_1_6b() -> Self {
         Self {
             hidden_act: Activation::Silu,
             hidden_size: 2048,
@@ -54,59 +66,6 @@ impl Config {
     pub fn rotary_ndims(&self) -> usize {
         (self.head_dim() as f64 * self.rope_pct) as usize
     }
-
-    pub fn num_kv_groups(&self) -> usize {
-        self.num_attention_heads / self.num_key_value_heads
-    }
-}
-
-#[derive(Debug)]
-pub(crate) struct RotaryEmbedding {
-    sin: Tensor,
-    cos: Tensor,
-}
-
-fn rotate_half(xs: &Tensor) -> Result<Tensor> {
-    let xs = xs.chunk(2, D::Minus1)?;
-    Tensor::cat(&[&xs[1].neg()?, &xs[0]], D::Minus1)
-}
-
-impl RotaryEmbedding {
-    pub(crate) fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
-        let dim = cfg.rotary_ndims();
-        let max_seq_len = cfg.max_position_embeddings;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(dtype)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
-    }
-
-    pub(crate) fn apply_rotary_emb_qkv(
-        &self,
-        q: &Tensor,
-        k: &Tensor,
-        seqlen_offset: usize,
-    ) -> Result<(Tensor, Tensor)> {
-        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
-        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
-        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-        let q_embed = (q.broadcast_mul(&cos)? + rotate_half(q)?.broadcast_mul(&sin))?;
-        let k_embed = (k.broadcast_mul(&cos)? + rotate_half(k)?.broadcast_mul(&sin))?;
-        Ok((q_embed, k_embed))
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -150,11 +109,10 @@ struct Attention {
     o_proj: Linear,
     num_heads: usize,
     num_kv_heads: usize,
-    num_kv_groups: usize,
     head_dim: usize,
     hidden_size: usize,
     rotary_emb: Arc<RotaryEmbedding>,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
     use_cache: bool,
     rotary_ndims: usize,
 }
@@ -181,30 +139,17 @@ impl Attention {
             o_proj,
             num_heads,
             num_kv_heads,
-            num_kv_groups: cfg.num_kv_groups(),
             head_dim,
             hidden_size: hidden_sz,
             rotary_emb,
-            kv_cache: None,
+            kv_cache: KvCache::new(cfg.max_position_embeddings, None, KvCacheType::default()),
             use_cache: cfg.use_cache,
             rotary_ndims: cfg.rotary_ndims(),
         })
     }
 
     fn clear_kv_cache(&mut self) {
-        self.kv_cache = None;
-    }
-
-    fn repeat_kv(&self, xs: Tensor) -> Result<Tensor> {
-        let n_rep = self.num_kv_groups;
-        if n_rep == 1 {
-            Ok(xs)
-        } else {
-            let (b_sz, num_kv_heads, seq_len, head_dim) = xs.dims4()?;
-            xs.unsqueeze(2)?
-                .expand((b_sz, num_kv_heads, n_rep, seq_len, head_dim))?
-                .reshape((b_sz, num_kv_heads * n_rep, seq_len, head_dim))
-        }
+        self.kv_cache.reset();
     }
 
     fn forward(
@@ -234,37 +179,29 @@ impl Attention {
         let query_pass = query_states.narrow(D::Minus1, rot_ndims, pass_ndims)?;
         let key_rot = key_states.narrow(D::Minus1, 0, rot_ndims)?;
         let key_pass = key_states.narrow(D::Minus1, rot_ndims, pass_ndims)?;
-        let (query_rot, key_rot) =
-            self.rotary_emb
-                .apply_rotary_emb_qkv(&query_rot, &key_rot, seqlen_offset)?;
+        let query_rot = self
+            .rotary_emb
+            .apply_rotary_emb(&query_rot, seqlen_offset)?;
+        let key_rot = self.rotary_emb.apply_rotary_emb(&key_rot, seqlen_offset)?;
         let query_states = Tensor::cat(&[query_rot, query_pass], D::Minus1)?.contiguous()?;
         let key_states = Tensor::cat(&[key_rot, key_pass], D::Minus1)?.contiguous()?;
 
-        let (key_states, value_states) = match &self.kv_cache {
-            None => (key_states, value_states),
-            Some((prev_k, prev_v)) => {
-                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
-                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
-                (key_states, value_states)
-            }
-        };
-        if self.use_cache {
-            self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        // Without caching only the current tokens are attended.
+        if !self.use_cache {
+            self.kv_cache.reset();
         }
-
-        let key_states = self.repeat_kv(key_states)?.contiguous()?;
-        let value_states = self.repeat_kv(value_states)?.contiguous()?;
+        self.kv_cache.append(&key_states, &value_states)?;
 
         let attn_output = {
             let scale = 1f64 / f64::sqrt(self.head_dim as f64);
-            let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+            let attn_weights = (self.kv_cache.scores(&query_states)? * scale)?;
 
             let attn_weights = match attention_mask {
                 None => attn_weights,
                 Some(mask) => attn_weights.broadcast_add(mask)?,
             };
             let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
-            attn_weights.matmul(&value_states)?
+            self.kv_cache.weighted_values(&attn_weights)?
         };
         attn_output
             .transpose(1, 2)?
@@ -321,27 +258,45 @@ impl DecoderLayer {
 
 #[derive(Debug, Clone)]
 pub struct Transformer {
-    embed_tokens: Embedding,
+    embed_tokens: candle_nn::Embedding,
     layers: Vec<DecoderLayer>,
     norm: LayerNorm,
     lm_head: Linear,
     device: Device,
+    dtype: DType,
+    head_dim: usize,
+    max_seq_len: usize,
+    derived_memory: usize,
+    cancel: CancelToken,
 }
 
 impl Transformer {
-    pub fn new(vb: VarBuilder) -> Result<Self> {
-        let cfg = Config::new();
-
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        progress: &mut dyn FnMut(usize, usize) -> bool,
+    ) -> Result<Self> {
         let vb_m = vb.pp("model");
-        let embed_tokens =
-            Embedding::new(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
-        let rotary_emb = Arc::new(RotaryEmbedding::new(DType::F32, &cfg, vb_m.device())?);
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let embeddings_memory =
+            vb_m.dequantized_memory(embed_tokens.embeddings(), "embed_tokens.weight")?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(
+            cfg.rotary_ndims(),
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            vb.dtype(),
+            vb_m.device(),
+        )?);
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
         let vb_l = vb_m.pp("layers");
         for layer_idx in 0..cfg.num_hidden_layers {
-            let layer = DecoderLayer::new(rotary_emb.clone(), &cfg, vb_l.pp(layer_idx))?;
-            layers.push(layer)
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx))?;
+            layers.push(layer);
+            if !progress(layer_idx + 1, cfg.num_hidden_layers) {
+                candle::bail!("Model loading cancelled");
+            }
         }
+        let derived_memory = embeddings_memory + rotary_emb.memory();
         let norm = layer_norm(cfg.hidden_size, cfg.norm_eps, vb_m.pp("norm"))?;
         let lm_head = linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
         Ok(Self {
@@ -350,51 +305,88 @@ impl Transformer {
             norm,
             lm_head,
             device: vb.device().clone(),
+            dtype: vb.dtype(),
+            head_dim: cfg.head_dim(),
+            max_seq_len: cfg.max_position_embeddings,
+            derived_memory,
+            cancel: CancelToken::default(),
         })
     }
 
-    fn prepare_decoder_attention_mask(
-        &self,
-        b_size: usize,
-        tgt_len: usize,
-        seqlen_offset: usize,
-    ) -> Result<Tensor> {
-        // Sliding window mask?
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(DType::F32)
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let seq_len = input_ids.dim(1)?;
+        self.forward_layers(input_ids, seqlen_offset)?
+            .narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
     }
 
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+    /// Processes the input tokens to fill the kv cache, without computing the logits.
+    pub fn fill_kv_cache(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<()> {
+        self.forward_layers(input_ids, seqlen_offset)?;
+        Ok(())
+    }
+
+    /// Runs the layers returning their output for all the input tokens.
+    fn forward_layers(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
         let (b_size, seq_len) = input_ids.dims2()?;
         let attention_mask = if seq_len <= 1 {
             None
         } else {
-            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
+            let mask = prepare_decoder_attention_mask(
+                b_size,
+                seq_len,
+                seqlen_offset,
+                self.dtype,
+                &self.device,
+            )?;
             Some(mask)
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
         for layer in self.layers.iter_mut() {
+            self.cancel.check()?;
             xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
         }
-        xs.narrow(1, seq_len - 1, 1)?
-            .apply(&self.norm)?
-            .apply(&self.lm_head)
+        Ok(xs)
+    }
+
+    /// Sets the token checked between layers to stop processing.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
     }
 
-    /// Resets the mode for a new prompt.
+    /// The maximum sequence length supported by the model.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Resets the model for a new prompt.
     pub fn clear_kv_cache(&mut self) {
         for layer in &mut self.layers {
             layer.clear_kv_cache();
         }
     }
+
+    /// Rolls back the kv cache to the first `len` tokens.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        let caches = self.layers.iter_mut().map(|l| &mut l.self_attn.kv_cache);
+        truncate_kv_caches(caches, len)
+    }
+
+    /// Sets how the kv cache is stored, this clears the cache.
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        let caches = self.layers.iter_mut().map(|l| &mut l.self_attn.kv_cache);
+        set_kv_caches_type(caches, cache_type, self.head_dim, self.max_seq_len)
+    }
+
+    /// The memory used by the kv cache in bytes.
+    pub fn kv_cache_memory(&self) -> usize {
+        kv_caches_memory(self.layers.iter().map(|l| &l.self_attn.kv_cache))
+    }
+
+    /// The memory used by the tensors computed from the weights in bytes, the
+    /// dequantized embeddings and the rotary embeddings tables.
+    pub fn derived_memory(&self) -> usize {
+        self.derived_memory
+    }
 }
@@ -0,0 +1,204 @@
+// Layers that are created from the quantized weights of a GGUF file or from the full
+// precision weights of safetensors files, so that the same architecture code loads
+// both kinds of models.
+use candle::{
+    quantized::{QMatMul, QTensor},
+    DType, Device, Module, Result, Shape, Tensor,
+};
+use std::{collections::HashMap, sync::Arc};
+
+/// A var builder for quantized weights, like candle's quantized var builder but
+/// created from tensors that are already loaded.
+#[derive(Clone)]
+pub struct QVarBuilder {
+    data: Arc<HashMap<String, Arc<QTensor>>>,
+    path: Vec<String>,
+    device: Device,
+}
+
+impl QVarBuilder {
+    /// Creates a var builder for the tensors loaded on the device.
+    pub fn from_tensors(tensors: HashMap<String, QTensor>, device: &Device) -> Self {
+        let data = tensors
+            .into_iter()
+            .map(|(name, tensor)| (name, Arc::new(tensor)))
+            .collect();
+        Self {
+            data: Arc::new(data),
+            path: vec![],
+            device: device.clone(),
+        }
+    }
+
+    /// Returns a builder for the weights under the given prefix.
+    pub fn pp<S: ToString>(&self, s: S) -> Self {
+        let mut path = self.path.clone();
+        path.push(s.to_string());
+        Self {
+            data: self.data.clone(),
+            path,
+            device: self.device.clone(),
+        }
+    }
+
+    /// The device where the weights are loaded.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn path(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{name}", self.path.join("."))
+        }
+    }
+
+    /// Whether there is a tensor with the given name under the prefix.
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.data.contains_key(&self.path(name))
+    }
+
+    /// Gets the tensor with the given name under the prefix, checking its shape.
+    pub fn get<S: Into<Shape>>(&self, s: S, name: &str) -> Result<Arc<QTensor>> {
+        let path = self.path(name);
+        let Some(qtensor) = self.data.get(&path) else {
+            candle::bail!("cannot find tensor {path}")
+        };
+        let shape = s.into();
+        if qtensor.shape() != &shape {
+            candle::bail!(
+                "shape mismatch for {path}, got {:?}, expected {shape:?}",
+                qtensor.shape()
+            )
+        }
+        Ok(qtensor.clone())
+    }
+}
+
+/// A var builder for quantized or full precision weights.
+#[derive(Clone)]
+pub enum VarBuilder {
+    Quantized(QVarBuilder),
+    Full(candle_nn::VarBuilder<'static>),
+}
+
+impl VarBuilder {
+    /// Returns a builder for the weights under the given prefix.
+    pub fn pp<S: ToString>(&self, s: S) -> Self {
+        match self {
+            Self::Quantized(vb) => Self::Quantized(vb.pp(s)),
+            Self::Full(vb) => Self::Full(vb.pp(s)),
+        }
+    }
+
+    /// The device where the weights are loaded.
+    pub fn device(&self) -> &Device {
+        match self {
+            Self::Quantized(vb) => vb.device(),
+            Self::Full(vb) => vb.device(),
+        }
+    }
+
+    /// The type of the activations, quantized weights compute in F32.
+    pub fn dtype(&self) -> DType {
+        match self {
+            Self::Quantized(_) => DType::F32,
+            Self::Full(vb) => vb.dtype(),
+        }
+    }
+
+    /// Whether there is a tensor with the given name under the prefix.
+    pub fn contains_tensor(&self, name: &str) -> bool {
+        match self {
+            Self::Quantized(vb) => vb.contains_key(name),
+            Self::Full(vb) => vb.contains_tensor(name),
+        }
+    }
+
+    /// Gets a tensor in the activations type, quantized weights are dequantized.
+    pub fn get<S: Into<Shape>>(&self, s: S, name: &str) -> Result<Tensor> {
+        match self {
+            Self::Quantized(vb) => vb.get(s, name)?.dequantize(vb.device()),
+            Self::Full(vb) => vb.get(s, name),
+        }
+    }
+
+    /// The memory added by `tensor` computed from the quantized tensor `name`, that is
+    /// not kept once the model is loaded, 0 for full precision weights used as loaded.
+    pub fn dequantized_memory(&self, tensor: &Tensor, name: &str) -> Result<usize> {
+        match self {
+            Self::Quantized(vb) => {
+                let qtensor = vb.get(tensor.dims(), name)?;
+                Ok(tensor_memory(tensor).saturating_sub(qtensor.storage_size_in_bytes()))
+            }
+            Self::Full(_) => Ok(0),
+        }
+    }
+}
+
+/// The memory used by a tensor in bytes.
+pub fn tensor_memory(tensor: &Tensor) -> usize {
+    tensor.elem_count() * tensor.dtype().size_in_bytes()
+}
+
+#[derive(Debug, Clone)]
+pub enum Linear {
+    Quantized {
+        weight: QMatMul,
+        bias: Option<Tensor>,
+    },
+    Full(candle_nn::Linear),
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Quantized { weight, bias } => {
+                let xs = xs.apply(weight)?;
+                match bias {
+                    Some(bias) => xs.broadcast_add(bias),
+                    None => Ok(xs),
+                }
+            }
+            Self::Full(linear) => linear.forward(xs),
+        }
+    }
+}
+
+pub fn linear(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Linear> {
+    match vb {
+        VarBuilder::Quantized(vb) => Ok(Linear::Quantized {
+            weight: QMatMul::from_arc(vb.get((out_dim, in_dim), "weight")?)?,
+            bias: Some(vb.get(out_dim, "bias")?.dequantize(vb.device())?),
+        }),
+        VarBuilder::Full(vb) => Ok(Linear::Full(candle_nn::linear(in_dim, out_dim, vb)?)),
+    }
+}
+
+pub fn linear_no_bias(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Linear> {
+    match vb {
+        VarBuilder::Quantized(vb) => Ok(Linear::Quantized {
+            weight: QMatMul::from_arc(vb.get((out_dim, in_dim), "weight")?)?,
+            bias: None,
+        }),
+        VarBuilder::Full(vb) => Ok(Linear::Full(candle_nn::linear_no_bias(
+            in_dim, out_dim, vb,
+        )?)),
+    }
+}
+
+/// Embeddings in the activations type, quantized weights are dequantized.
+pub fn embedding(d1: usize, d2: usize, vb: VarBuilder) -> Result<candle_nn::Embedding> {
+    Ok(candle_nn::Embedding::new(vb.get((d1, d2), "weight")?, d2))
+}
+
+pub fn layer_norm(size: usize, eps: f64, vb: VarBuilder) -> Result<candle_nn::LayerNorm> {
+    let weight = vb.get(size, "weight")?;
+    let bias = vb.get(size, "bias")?;
+    Ok(candle_nn::LayerNorm::new(weight, bias, eps))
+}
+
+pub fn rms_norm(size: usize, eps: f64, vb: VarBuilder) -> Result<candle_nn::LayerNorm> {
+    Ok(candle_nn::LayerNorm::rms_norm(vb.get(size, "weight")?, eps))
+}